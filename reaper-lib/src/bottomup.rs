@@ -1,90 +1,206 @@
-use rusqlite::Connection;
+use itertools::Itertools;
+use rayon::prelude::*;
 
-use crate::sql::*;
+use crate::eval::{EvalError, Evaluator};
+use crate::stop::{Stopped, Stopper};
 use crate::types::*;
-use std::collections::HashMap;
-use std::rc::Rc;
+use std::collections::{hash_map::Entry, HashMap, HashSet};
+use std::sync::Arc;
 
-// Get fields from any ASTNode
-pub fn get_fields(node: &AST<()>) -> Vec<Field> {
-    match node {
-        AST::Select { fields, table, .. } => {
-            let mut fields = match fields {
-                Some(fields) => fields.to_vec(),
-                None => get_fields(table),
-            };
-            // Append fields from table
-            let table_fields = get_fields(table);
-            for field in table_fields.iter() {
-                if !fields.contains(field) {
-                    fields.push(field.clone());
-                }
-            }
-            fields.sort_by(|a, b| a.name.cmp(&b.name));
-            fields
-        }
-        AST::Join {
-            fields,
-            table1,
-            table2,
+/// [output_columns(q)] returns the names of the columns of [q], in order, along with whether
+/// queries over [q] can reference each one. Columns named after the expressions that compute
+/// them, like aggregates, can't be.
+fn output_columns<T>(q: &AST<T>) -> Vec<(String, bool)> {
+    match q {
+        AST::Select {
+            fields: Some(items),
             ..
-        } => {
-            let mut fields = match fields {
-                Some(fields) => fields.to_vec(),
-                None => {
-                    let mut fields1 = get_fields(table1);
-                    let mut fields2 = get_fields(table2);
-                    fields1.append(&mut fields2);
-                    fields1
-                }
-            };
-            fields.sort_by(|a, b| a.name.cmp(&b.name));
-            fields
         }
-        AST::Table { name, columns } => columns
+        | AST::Join {
+            fields: Some(items),
+            ..
+        } => items
             .iter()
-            .map(|col| Field {
-                name: col.clone(),
-                table: name.clone(),
+            .map(|item| {
+                let named = item.alias.is_some() || matches!(item.expr, ExprNode::Field(_));
+                (item.column_name(), named)
             })
-            .collect::<Vec<_>>(),
-        AST::Concat { table1, table2 } => {
-            let mut fields1 = get_fields(table1);
-            let mut fields2 = get_fields(table2);
-            fields1.append(&mut fields2);
-            fields1.sort_by(|a, b| a.name.cmp(&b.name));
-            fields1
-        }
+            .collect(),
+        AST::Table { columns, .. } => columns.iter().map(|c| (c.clone(), true)).collect(),
+        AST::Aggregate {
+            group_by,
+            aggregates,
+            ..
+        } => group_by
+            .iter()
+            .map(|f| (f.name.clone(), true))
+            .chain(aggregates.iter().map(|a| (a.column_name(), false)))
+            .collect(),
+        // The columns of a set operation are named after those of its first table.
+        AST::SetOp { table1, .. } => output_columns(table1),
+        _ => q.children().into_iter().flat_map(output_columns).collect(),
     }
 }
 
-fn is_valid(result: &ConcTable, expected: &ConcTable) -> bool {
-    // Check that the columns have the same names
+/// [output_fields(q)] returns the fields that queries over [q] refer to its columns by, in
+/// order, which are qualified with the alias of [q].
+pub fn output_fields<T>(q: &AST<T>) -> Vec<Field> {
+    output_columns(q)
+        .into_iter()
+        .map(|(name, _)| Field {
+            name,
+            table: q.alias().to_string(),
+        })
+        .collect()
+}
+
+// Get fields from any ASTNode
+pub fn get_fields(node: &AST<()>) -> Vec<Field> {
+    output_columns(node)
+        .into_iter()
+        .filter(|(_, named)| *named)
+        .map(|(name, _)| Field {
+            name,
+            table: node.alias().to_string(),
+        })
+        .collect()
+}
+
+pub(crate) fn is_valid(result: &ConcTable, example: &Example) -> bool {
+    column_mapping(result, example).is_some()
+}
+
+/// [is_valid_with(result, example, mode)] is like [is_valid], but matches rows under [mode]
+/// instead of the example's own mode.
+fn is_valid_with(result: &ConcTable, example: &Example, mode: MatchMode) -> bool {
+    column_mappings(result, example)
+        .iter()
+        .any(|mapping| rows_match(&project_columns(result, mapping), example, mode))
+}
+
+/// [column_mapping(result, example)] returns the columns of [result] that line up with each
+/// column of the example's output, in order, if [result] matches the example.
+pub fn column_mapping(result: &ConcTable, example: &Example) -> Option<Vec<usize>> {
+    column_mappings(result, example)
+        .into_iter()
+        .find(|mapping| {
+            rows_match(
+                &project_columns(result, mapping),
+                example,
+                example.match_mode,
+            )
+        })
+}
+
+/// [column_mappings(result, example)] returns every way of lining up the columns of [result]
+/// with those of the example's output that its columns allow, without looking at rows.
+///
+/// By name, the columns line up in order, and each must have the expected name unless it's
+/// blank. By content, they can line up in any order, as long as each column of [result]
+/// contains every value expected in the column it lines up with.
+pub(crate) fn column_mappings(result: &ConcTable, example: &Example) -> Vec<Vec<usize>> {
+    let expected = &example.output;
     if result.columns.len() != expected.columns.len() {
-        return false;
+        return Vec::new();
     }
 
-    for (col1, col2) in result.columns.iter().zip(expected.columns.iter()) {
-        if col1 != col2 {
-            return false;
+    match example.column_match {
+        ColumnMatch::ByName => {
+            let names_match = result
+                .columns
+                .iter()
+                .zip(expected.columns.iter())
+                .all(|(col1, col2)| col2.is_empty() || col1 == col2);
+            if names_match {
+                vec![(0..expected.columns.len()).collect()]
+            } else {
+                Vec::new()
+            }
+        }
+        ColumnMatch::ByContent => {
+            let column_values = |table: &ConcTable, i: usize| -> HashSet<Value> {
+                table.values.iter().map(|row| row[i].clone()).collect()
+            };
+            // For each expected column, the columns of the result it could line up with
+            let candidates: Vec<Vec<usize>> = (0..expected.columns.len())
+                .map(|i| {
+                    let mut expected_values = column_values(expected, i);
+                    expected_values.remove(&Value::Null);
+                    (0..result.columns.len())
+                        .filter(|j| expected_values.is_subset(&column_values(result, *j)))
+                        .collect()
+                })
+                .collect();
+            candidates
+                .iter()
+                .map(|js| js.iter().copied())
+                .multi_cartesian_product()
+                .filter(|mapping| mapping.iter().all_unique())
+                .collect()
         }
     }
+}
 
-    // Check that the result contains all the columns of the expected
-    for col in expected.columns.iter() {
-        if !result.columns.contains(col) {
-            return false;
-        }
+/// [project_columns(table, mapping)] returns the columns [mapping] of [table], in order.
+pub fn project_columns(table: &ConcTable, mapping: &[usize]) -> ConcTable {
+    ConcTable {
+        name: table.name.clone(),
+        columns: mapping.iter().map(|j| table.columns[*j].clone()).collect(),
+        values: table
+            .values
+            .iter()
+            .map(|row| mapping.iter().map(|j| row[*j].clone()).collect())
+            .collect(),
     }
+}
 
-    // Check that the result contains all the rows of the expected
-    for row in expected.values.iter() {
-        if !result.values.contains(row) {
-            return false;
+fn rows_match(result: &ConcTable, example: &Example, mode: MatchMode) -> bool {
+    let expected = &example.output;
+    match mode {
+        MatchMode::Bag if example.ordered => result.values == expected.values,
+        MatchMode::Bag => sorted_rows(result) == sorted_rows(expected),
+        MatchMode::Set if example.ordered => distinct_rows(result) == distinct_rows(expected),
+        MatchMode::Set => {
+            result.values.iter().collect::<HashSet<_>>()
+                == expected.values.iter().collect::<HashSet<_>>()
+        }
+        MatchMode::Superset => {
+            // Check that the result contains all the rows of the expected
+            for row in expected.values.iter() {
+                if !result.values.contains(row) {
+                    return false;
+                }
+            }
+
+            // Check that they also appear in the same order
+            if example.ordered {
+                let mut rows = result.values.iter();
+                for row in expected.values.iter() {
+                    if !rows.any(|r| r == row) {
+                        return false;
+                    }
+                }
+            }
+
+            true
         }
     }
+}
 
-    true
+fn sorted_rows(table: &ConcTable) -> Vec<&Vec<Value>> {
+    let mut rows: Vec<_> = table.values.iter().collect();
+    rows.sort();
+    rows
+}
+
+// Return the rows of a table without duplicates, in the order they first appear
+fn distinct_rows(table: &ConcTable) -> Vec<&Vec<Value>> {
+    let mut seen = HashSet::new();
+    table
+        .values
+        .iter()
+        .filter(|row| seen.insert(*row))
+        .collect()
 }
 
 fn powerset<T>(s: &[T]) -> Vec<Vec<T>>
@@ -102,65 +218,455 @@ where
         .collect()
 }
 
-// Return every combination of fields possible
+// Return every combination of fields possible, along with the projections shaped like the
+// example's output
 // TODO: I don't actually want to be copying the fields everywhere...
-fn field_combinations(query: &AST<()>) -> Vec<Vec<Field>> {
+fn field_combinations(
+    query: &AST<()>,
+    example: &Example,
+    evaluator: &dyn Evaluator,
+) -> Vec<Vec<SelectItem>> {
     let fields = get_fields(query);
-    powerset(&fields)
+    let mut items = items_powerset(&fields);
+    add_target_projections(&mut items, &fields, example, || {
+        Ok((evaluator.eval_abstract(query)?, output_fields(query)))
+    });
+    items
 }
 
-fn field_combinations_join(query1: &AST<()>, query2: &AST<()>) -> Vec<Vec<Field>> {
-    let fields1 = get_fields(query1);
-    let fields2 = get_fields(query2);
+fn field_combinations_join(
+    query1: &AST<()>,
+    query2: &AST<()>,
+    example: &Example,
+    evaluator: &dyn Evaluator,
+) -> Vec<Vec<SelectItem>> {
+    let mut fields = get_fields(query1);
+    fields.extend(get_fields(query2));
 
-    // Union the fields
-    let mut fields = fields1;
-    for field in fields2.iter() {
-        if !fields.contains(field) {
-            fields.push(field.clone());
+    let mut items = items_powerset(&fields);
+    add_target_projections(&mut items, &fields, example, || {
+        // Outer joins only add rows padded with NULLs, which don't rule anything out, so
+        // the rows of the inner join are enough.
+        let join = AST::Join {
+            fields: None,
+            distinct: false,
+            kind: JoinKind::Inner,
+            table1: Box::new(query1.clone()),
+            table2: Box::new(query2.clone()),
+            pred: (),
+            alias: AST::fresh_alias(&[query1, query2]),
+        };
+        let mut columns = output_fields(query1);
+        columns.extend(output_fields(query2));
+        Ok((evaluator.eval_abstract(&join)?, columns))
+    });
+    // Queries over the join couldn't tell apart columns with the same name, like the keys
+    // of a table joined with itself.
+    items.retain(|items| items.iter().map(SelectItem::column_name).all_unique());
+    // Joining a query with itself is the same with its sides swapped, along with the fields
+    // they project, so only the first of each projection and its mirror image is kept.
+    if *query2 == query1.clone().with_alias(query2.alias().to_string()) {
+        let mut kept: Vec<Vec<SelectItem>> = Vec::new();
+        items.retain(|items| {
+            if kept.contains(&mirror_items(items, query1.alias(), query2.alias())) {
+                return false;
+            }
+            kept.push(items.clone());
+            true
+        });
+    }
+    items
+}
+
+/// [mirror_items(items, alias1, alias2)] returns [items] with their fields of [alias1] and
+/// [alias2] swapped.
+fn mirror_items(items: &[SelectItem], alias1: &str, alias2: &str) -> Vec<SelectItem> {
+    items
+        .iter()
+        .map(|item| SelectItem {
+            expr: mirror_expr(&item.expr, alias1, alias2),
+            alias: item.alias.clone(),
+        })
+        .collect()
+}
+
+/// [mirror_expr(e, alias1, alias2)] returns [e] with its fields of [alias1] and [alias2]
+/// swapped.
+fn mirror_expr(e: &ExprNode, alias1: &str, alias2: &str) -> ExprNode {
+    let mirror_field = |field: &Field| Field {
+        name: field.name.clone(),
+        table: match field.table.as_str() {
+            t if t == alias1 => alias2.to_string(),
+            t if t == alias2 => alias1.to_string(),
+            t => t.to_string(),
+        },
+    };
+    let mirror = |e: &ExprNode| Box::new(mirror_expr(e, alias1, alias2));
+    match e {
+        ExprNode::Field(field) => ExprNode::Field(mirror_field(field)),
+        ExprNode::Aggregate(AggExpr { func, field }) => ExprNode::Aggregate(AggExpr {
+            func: *func,
+            field: mirror_field(field),
+        }),
+        ExprNode::Int { .. } => e.clone(),
+        ExprNode::Add { left, right } => ExprNode::Add {
+            left: mirror(left),
+            right: mirror(right),
+        },
+        ExprNode::Sub { left, right } => ExprNode::Sub {
+            left: mirror(left),
+            right: mirror(right),
+        },
+        ExprNode::Mul { left, right } => ExprNode::Mul {
+            left: mirror(left),
+            right: mirror(right),
+        },
+        ExprNode::Div { left, right } => ExprNode::Div {
+            left: mirror(left),
+            right: mirror(right),
+        },
+        ExprNode::Mod { left, right } => ExprNode::Mod {
+            left: mirror(left),
+            right: mirror(right),
+        },
+    }
+}
+
+fn items_powerset(fields: &[Field]) -> Vec<Vec<SelectItem>> {
+    powerset(fields)
+        .into_iter()
+        .map(|fields| fields.into_iter().map(SelectItem::from).collect())
+        .collect()
+}
+
+// Add the projections of [fields] shaped like the example's output to [items]. The powerset
+// only has fields in one order, so if every column is named after a field, that's just those
+// fields in the order of the header. Otherwise, they're found among the [rows] being
+// projected, along with the fields their columns are, which are only evaluated then.
+fn add_target_projections(
+    items: &mut Vec<Vec<SelectItem>>,
+    fields: &[Field],
+    example: &Example,
+    rows: impl FnOnce() -> Result<(ConcTable, Vec<Field>), EvalError>,
+) {
+    let named: Option<Vec<SelectItem>> = expected_column_names(example)
+        .iter()
+        .map(|c| {
+            fields
+                .iter()
+                .find(|f| &f.name == c)
+                .map(|f| SelectItem::from(f.clone()))
+        })
+        .collect();
+    match named {
+        Some(projection) => {
+            if !items.contains(&projection) {
+                items.push(projection);
+            }
         }
+        None => {
+            if let Ok((rows, columns)) = rows() {
+                items.extend(header_projections(fields, &rows, &columns, example));
+            }
+        }
+    }
+}
+
+// Return the names of the example's output columns, which are all blank if they're matched
+// by content
+fn expected_column_names(example: &Example) -> Vec<&str> {
+    match example.column_match {
+        ColumnMatch::ByName => example.output.columns.iter().map(String::as_str).collect(),
+        ColumnMatch::ByContent => vec![""; example.output.columns.len()],
     }
+}
 
-    powerset(&fields)
+// Return every projection of [rows], whose columns are [columns], onto the example's output
+// columns, in order. A column named after a field is that field, and a column with any other
+// name is a renamed field or an expression over the fields. A column without a name can be
+// any of those, unnamed. Items that can't produce every value the example expects in their
+// column are ruled out.
+fn header_projections(
+    fields: &[Field],
+    rows: &ConcTable,
+    columns: &[Field],
+    example: &Example,
+) -> Vec<Vec<SelectItem>> {
+    let exprs = crate::enum_predicates::enum_exprs(&[], fields, &[], 2);
+    let expected = &example.output;
+    let mut candidates = Vec::new();
+    for (i, column) in expected_column_names(example).into_iter().enumerate() {
+        let named = fields.iter().find(|f| f.name == column);
+        let expected_values: HashSet<_> = expected
+            .values
+            .iter()
+            .map(|row| &row[i])
+            .filter(|v| **v != Value::Null)
+            .collect();
+        let produces_expected = |e: &ExprNode| match crate::bvdfs::expr_values(rows, columns, e) {
+            Some(values) => {
+                let values: HashSet<_> = values.iter().collect();
+                expected_values.iter().all(|v| values.contains(v))
+            }
+            None => false,
+        };
+        let exprs: Vec<_> = exprs
+            .iter()
+            .filter(|e| match named {
+                Some(field) => **e == ExprNode::Field(field.clone()),
+                None => true,
+            })
+            .filter(|e| produces_expected(e))
+            .collect();
+        // Over enough rows, some expression will produce the expected values by chance, so
+        // the column is only computed if no field has them.
+        let exprs: Vec<_> = if exprs.iter().any(|e| matches!(e, ExprNode::Field(_))) {
+            exprs
+                .into_iter()
+                .filter(|e| matches!(e, ExprNode::Field(_)))
+                .collect()
+        } else {
+            exprs
+        };
+        let items: Vec<_> = exprs
+            .into_iter()
+            .map(|e| SelectItem {
+                expr: e.clone(),
+                alias: if column.is_empty() || named.is_some() {
+                    None
+                } else {
+                    Some(column.to_string())
+                },
+            })
+            .collect();
+        candidates.push(items);
+    }
+    candidates
+        .iter()
+        .map(|items| items.iter().cloned())
+        .multi_cartesian_product()
+        .collect()
 }
 
-fn grow(queries: Vec<AST<()>>) -> Vec<AST<()>> {
+// Return every aggregation of a query by a (possibly empty) set of grouping fields with a
+// single aggregate function over one of the remaining fields.
+fn aggregations(query: &AST<()>) -> Vec<AST<()>> {
+    let fields = get_fields(query);
     let mut new_queries = Vec::new();
+    for group_by in std::iter::once(Vec::new()).chain(powerset(&fields)) {
+        for field in fields.iter().filter(|f| !group_by.contains(f)) {
+            for func in AggFunc::ALL {
+                new_queries.push(AST::Aggregate {
+                    group_by: Arc::from(&group_by[..]),
+                    aggregates: Arc::from(vec![AggExpr {
+                        func,
+                        field: field.clone(),
+                    }]),
+                    table: Box::new(query.clone()),
+                    having: (),
+                    alias: AST::fresh_alias(&[query]),
+                });
+            }
+        }
+    }
+    new_queries
+}
 
-    for (_i, query) in queries.iter().enumerate() {
-        // Identity
-        new_queries.push(query.clone());
+// Return every ordering of a query by a single field, each followed by a limit to the
+// number of expected rows.
+fn orderings(query: &AST<()>, example: &Example) -> Vec<AST<()>> {
+    let mut new_queries = Vec::new();
+    for field in get_fields(query) {
+        for dir in [SortDir::Asc, SortDir::Desc] {
+            let order_by = AST::OrderBy {
+                keys: Arc::from(vec![SortKey {
+                    field: field.clone(),
+                    dir,
+                }]),
+                table: Box::new(query.clone()),
+                alias: AST::fresh_alias(&[query]),
+            };
+            new_queries.push(AST::Limit {
+                count: example.output.values.len(),
+                table: Box::new(order_by.clone()),
+                alias: AST::fresh_alias(&[&order_by]),
+            });
+            new_queries.push(order_by);
+        }
+    }
+    new_queries
+}
 
-        // Select
-        let field_powerset = field_combinations(query);
-        for fields in field_powerset.iter() {
+/// [is_distinct(query)] returns whether [query] removes duplicate rows from its output.
+fn is_distinct(query: &AST<()>) -> bool {
+    matches!(
+        query,
+        AST::Select { distinct: true, .. } | AST::Join { distinct: true, .. }
+    )
+}
+
+/// [is_filtered_aggregate(query)] returns whether [query] groups rows that the predicates
+/// below it can still filter, under any ORDER BY and LIMIT.
+fn is_filtered_aggregate(query: &AST<()>) -> bool {
+    match query {
+        AST::OrderBy { table, .. } | AST::Limit { table, .. } => is_filtered_aggregate(table),
+        AST::Aggregate { table, .. } => table.num_holes() > 0,
+        _ => false,
+    }
+}
+
+// ORDER BY and LIMIT only make sense at the root of a query, since SQL doesn't guarantee that
+// the order of a derived table is preserved.
+fn is_ordered(query: &AST<()>) -> bool {
+    matches!(query, AST::OrderBy { .. } | AST::Limit { .. })
+}
+
+/// [disambiguate(query1, query2)] returns [query2], renamed if it has the same alias as
+/// [query1] so that a query can read from both, as when a table is joined with itself.
+fn disambiguate(query1: &AST<()>, query2: &AST<()>) -> AST<()> {
+    if query1.alias() != query2.alias() {
+        return query2.clone();
+    }
+    let alias = match query2 {
+        AST::Table { name, .. } => {
+            let taken: HashSet<_> = query1.aliases().union(&query2.aliases()).copied().collect();
+            (2..)
+                .map(|i| format!("{}{}", name, i))
+                .find(|alias| !taken.contains(alias.as_str()))
+                .expect("there are infinitely many aliases")
+        }
+        _ => AST::fresh_alias(&[query1, query2]),
+    };
+    query2.clone().with_alias(alias)
+}
+
+fn grow(
+    queries: Vec<AST<()>>,
+    example: &Example,
+    evaluator: &dyn Evaluator,
+    stopper: &Stopper,
+) -> Result<Vec<AST<()>>, Stopped> {
+    // Each query is grown on its own thread, but the new queries keep the order they'd have
+    // if they were grown one after another.
+    let grown = queries
+        .par_iter()
+        .enumerate()
+        .map(|(i, _)| {
+            if stopper.is_stopped() {
+                return Vec::new();
+            }
+            grow_query(i, &queries, example, evaluator)
+        })
+        .collect::<Vec<_>>();
+    stopper.check()?;
+    Ok(grown.into_iter().flatten().collect())
+}
+
+/// [grow_query(i, queries, example, evaluator)] returns the [i]th of [queries] along with the
+/// queries built on it in one step, including those combining it with each of [queries].
+fn grow_query(
+    i: usize,
+    queries: &[AST<()>],
+    example: &Example,
+    evaluator: &dyn Evaluator,
+) -> Vec<AST<()>> {
+    let query = &queries[i];
+    let mut new_queries = Vec::new();
+
+    // Identity
+    new_queries.push(query.clone());
+
+    if is_ordered(query) {
+        return new_queries;
+    }
+
+    // Select
+    let field_powerset = field_combinations(query, example, evaluator);
+    for fields in field_powerset.iter() {
+        for distinct in [false, true] {
             let select = AST::Select {
-                fields: Some(Rc::from(&fields[..])),
+                fields: Some(Arc::from(&fields[..])),
+                distinct,
                 table: Box::new(query.clone()),
                 pred: (),
+                alias: AST::fresh_alias(&[query]),
             };
             new_queries.push(select);
         }
+    }
+
+    // Aggregate, unless we'd be aggregating an aggregate
+    if !matches!(query, AST::Aggregate { .. }) {
+        new_queries.extend(aggregations(query));
+    }
+
+    // Order and limit, but only if the example says the order matters
+    if example.ordered {
+        new_queries.extend(orderings(query, example));
+    }
 
-        for (_j, query2) in queries.iter().enumerate() {
-            // Join
-            let field_powerset = field_combinations_join(query, query2);
-            for fields in field_powerset.iter() {
-                let join = AST::Join {
-                    fields: Some(Rc::from(&fields[..])),
-                    table1: Box::new(query.clone()),
-                    table2: Box::new(query2.clone()),
-                    pred: (),
-                };
-                new_queries.push(join);
+    for (j, query2) in queries.iter().enumerate() {
+        if is_ordered(query2) {
+            continue;
+        }
+        let renamed = disambiguate(query, query2);
+        let alias = AST::fresh_alias(&[query, &renamed]);
+
+        // Join
+        let mut field_powerset = field_combinations_join(query, &renamed, example, evaluator);
+        if j < i {
+            // Joining the queries the other way around is the same, but for LEFT and RIGHT
+            // trading places, so the projections that were already made then are left out.
+            // Those name the fields of whichever query was renamed by the other's alias.
+            let earlier =
+                field_combinations_join(query2, &disambiguate(query2, query), example, evaluator);
+            field_powerset.retain(|items| {
+                if renamed.alias() == query2.alias() {
+                    !earlier.contains(items)
+                } else {
+                    !earlier.contains(&mirror_items(items, query.alias(), renamed.alias()))
+                }
+            });
+        }
+        let query2 = &renamed;
+        for fields in field_powerset.iter() {
+            for kind in JoinKind::ALL {
+                for distinct in [false, true] {
+                    let join = AST::Join {
+                        fields: Some(Arc::from(&fields[..])),
+                        distinct,
+                        kind,
+                        table1: Box::new(query.clone()),
+                        table2: Box::new(query2.clone()),
+                        pred: (),
+                        alias: alias.clone(),
+                    };
+                    new_queries.push(join);
+                }
             }
+        }
 
-            // Concat
-            let concat = AST::Concat {
-                table1: Box::new(query.clone()),
-                table2: Box::new(query2.clone()),
-            };
-            new_queries.push(concat);
+        // Concat
+        let concat = AST::Concat {
+            table1: Box::new(query.clone()),
+            table2: Box::new(query2.clone()),
+            alias: alias.clone(),
+        };
+        new_queries.push(concat);
+
+        // Set operations, which only make sense between queries of the same arity
+        if query.arity() == query2.arity() {
+            for op in SetOp::ALL {
+                for all in [false, true] {
+                    new_queries.push(AST::SetOp {
+                        op,
+                        all,
+                        table1: Box::new(query.clone()),
+                        table2: Box::new(query2.clone()),
+                        alias: alias.clone(),
+                    });
+                }
+            }
         }
     }
 
@@ -169,80 +675,327 @@ fn grow(queries: Vec<AST<()>>) -> Vec<AST<()>> {
 
 fn elim(
     queries: Vec<AST<()>>,
-    _example: &Example,
-    conn: &Connection,
+    example: &Example,
+    evaluator: &dyn Evaluator,
     is_final: bool,
-) -> Vec<AST<()>> {
+    stopper: &Stopper,
+) -> Result<Vec<AST<()>>, Stopped> {
+    // The queries are evaluated in parallel, then looked through in order.
+    let outputs: Vec<_> = queries
+        .par_iter()
+        .map(|query| {
+            if stopper.is_stopped() {
+                return None;
+            }
+            let mut output = evaluator.eval_abstract(query).ok()?;
+            // If the example doesn't care about order, neither does equivalence. We can't
+            // ignore duplicates for set matching, though, since later aggregates can still
+            // tell them apart.
+            if !example.ordered {
+                output.values.sort();
+            }
+            Some(output)
+        })
+        .collect();
+    // Once stopped, a stopper stays stopped, so if it hasn't been, every query was evaluated.
+    stopper.check()?;
+
     // Map output to representative query
     let mut output_map = HashMap::new();
 
-    for query in queries.iter() {
-        let output = eval_abstract(query, conn);
-
+    for (query, output) in queries.iter().zip(outputs) {
         match output {
-            Err(_) => continue,
-            Ok(output) => {
-                // TODO: equivalence occurs if the values are the same, regardless of ordering
-                if !output_map.contains_key(&output) {
-                    if is_final {
-                        // Check that this is both a superset and the right structure
-                        if !is_valid(&output, &_example.1) {
-                            continue;
-                        }
+            None => continue,
+            Some(output) => {
+                // Removing duplicates changes the output without changing the abstract rows,
+                // so queries that do are only equivalent to others that do too.
+                if let Entry::Vacant(e) = output_map.entry((output, is_distinct(query))) {
+                    // Check that this has the right structure and that predicates could
+                    // still narrow it down to the example. Predicates below an aggregate
+                    // change its values rather than narrowing them down, though.
+                    if is_final
+                        && !is_filtered_aggregate(query)
+                        && !is_valid_with(&e.key().0, example, MatchMode::Superset)
+                    {
+                        continue;
                     }
-                    output_map.insert(output.clone(), query.clone());
+                    e.insert(query.clone());
                 }
                 // TODO: heuristic for which query to keep
             }
         }
     }
 
-    output_map.values().cloned().collect()
+    Ok(output_map.values().cloned().collect())
 }
 
 fn initial_set(example: &Example) -> Vec<AST<()>> {
     // Just return the set of all tables
     let mut queries = Vec::new();
-    for table in example.0.iter() {
+    for table in example.input.iter() {
         queries.push(AST::Table {
             name: table.name.clone(),
             columns: table.columns.clone(),
+            alias: table.name.clone(),
         });
     }
     queries
 }
 
-pub fn generate_abstract_queries(example: Example, depth: i32, conn: &Connection) -> Vec<AST<()>> {
+/// [generate_abstract_queries(example, depth, evaluator, stopper)] returns the queries over
+/// the example's tables that can be built in [depth] steps, up to equivalence, unless
+/// [stopper] stops first.
+pub fn generate_abstract_queries(
+    example: Example,
+    depth: i32,
+    evaluator: &dyn Evaluator,
+    stopper: &Stopper,
+) -> Result<Vec<AST<()>>, Stopped> {
     let mut queries = initial_set(&example);
 
     for d in 0..depth {
-        queries = grow(queries);
-        queries = elim(queries, &example, conn, d == depth - 1);
+        queries = grow(queries, &example, evaluator, stopper)?;
+        queries = elim(queries, &example, evaluator, d == depth - 1, stopper)?;
     }
 
-    queries
+    Ok(queries)
+}
+
+/// [generate_subqueries(example, evaluator, stopper)] returns the queries that predicates can
+/// nest, which are those [generate_abstract_queries] grows from the example's tables in one
+/// step, before they're checked against the example's output, with their predicates left
+/// True, followed by a correlated subquery over each of the example's tables, whose
+/// predicates are enumerated along with those of the queries they're nested in, unless
+/// [stopper] stops first.
+pub fn generate_subqueries(
+    example: &Example,
+    evaluator: &dyn Evaluator,
+    stopper: &Stopper,
+) -> Result<Vec<Arc<Subquery>>, Stopped> {
+    let queries = grow(initial_set(example), example, evaluator, stopper)?;
+    let queries = elim(queries, example, evaluator, false, stopper)?;
+    let tables = initial_set(example);
+    // Once stopped, the rest aren't evaluated.
+    let subqueries = queries
+        .par_iter()
+        .filter(|q| !matches!(q, AST::Table { .. }) && !is_ordered(q))
+        .filter_map(|q| {
+            if stopper.is_stopped() {
+                return None;
+            }
+            let q = q
+                .with_predicates(&vec![PredNode::True; q.num_holes()])
+                .expect("expected hole count to match");
+            Subquery::new(q, evaluator).ok().map(Arc::new)
+        })
+        .chain(tables.par_iter().filter_map(|table| {
+            if stopper.is_stopped() {
+                return None;
+            }
+            Subquery::correlated(table, evaluator).ok().map(Arc::new)
+        }))
+        .collect();
+    stopper.check()?;
+    Ok(subqueries)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::sql::SqliteEvaluator;
 
     #[test]
     fn test_generate_queries_simple() {
         let input = vec![ConcTable {
             name: "t1".to_string(),
             columns: vec!["a".to_string(), "b".to_string()],
-            values: vec![vec![1, 2], vec![3, 4]],
+            values: vec![vec![1.into(), 2.into()], vec![3.into(), 4.into()]],
         }];
         let output = ConcTable {
             name: "".to_string(),
             columns: vec!["".to_string(), "".to_string()],
-            values: vec![vec![1, 2], vec![3, 4]],
+            values: vec![vec![1.into(), 2.into()], vec![3.into(), 4.into()]],
         };
 
-        let conn = create_table(&input).unwrap();
-        let queries = generate_abstract_queries((input, output), 2, &conn);
+        let evaluator = SqliteEvaluator::new(&input).unwrap();
+        let queries = generate_abstract_queries(
+            Example {
+                input,
+                output,
+                ordered: false,
+                match_mode: MatchMode::Bag,
+                column_match: ColumnMatch::ByName,
+            },
+            2,
+            &evaluator,
+            &Stopper::new(),
+        )
+        .unwrap();
 
         assert!(queries.len() > 0);
     }
+
+    #[test]
+    fn test_grow_self_join_candidates() {
+        let input = vec![ConcTable {
+            name: "t1".to_string(),
+            columns: vec!["a".to_string(), "b".to_string()],
+            values: vec![vec![1.into(), 2.into()], vec![3.into(), 4.into()]],
+        }];
+        let output = ConcTable {
+            name: "".to_string(),
+            columns: vec!["".to_string(), "".to_string()],
+            values: vec![vec![1.into(), 2.into()], vec![3.into(), 4.into()]],
+        };
+        let evaluator = SqliteEvaluator::new(&input).unwrap();
+        let example = Example {
+            input,
+            output,
+            ordered: false,
+            match_mode: MatchMode::Bag,
+            column_match: ColumnMatch::ByName,
+        };
+        let stopper = Stopper::new();
+
+        // Joining a query with itself, or two queries both ways around, doesn't project the
+        // same fields twice. Projecting them twice made 132 and about 215,000 candidates.
+        let queries = grow(initial_set(&example), &example, &evaluator, &stopper).unwrap();
+        assert!(queries.len() <= 92, "{} candidates", queries.len());
+        let queries = elim(queries, &example, &evaluator, false, &stopper).unwrap();
+        let queries = grow(queries, &example, &evaluator, &stopper).unwrap();
+        assert!(queries.len() <= 135_000, "{} candidates", queries.len());
+    }
+
+    #[test]
+    fn test_generate_queries_aggregate() {
+        let input = vec![ConcTable {
+            name: "t1".to_string(),
+            columns: vec!["a".to_string(), "b".to_string()],
+            values: vec![
+                vec![1.into(), 10.into()],
+                vec![2.into(), 10.into()],
+                vec![3.into(), 20.into()],
+            ],
+        }];
+        let output = ConcTable {
+            name: "".to_string(),
+            columns: vec!["b".to_string(), "COUNT(a)".to_string()],
+            values: vec![vec![10.into(), 2.into()], vec![20.into(), 1.into()]],
+        };
+
+        let evaluator = SqliteEvaluator::new(&input).unwrap();
+        let queries = generate_abstract_queries(
+            Example {
+                input,
+                output,
+                ordered: false,
+                match_mode: MatchMode::Bag,
+                column_match: ColumnMatch::ByName,
+            },
+            1,
+            &evaluator,
+            &Stopper::new(),
+        )
+        .unwrap();
+
+        let b = Field {
+            name: "b".to_string(),
+            table: "t1".to_string(),
+        };
+        assert!(queries.iter().any(|q| matches!(
+            q,
+            AST::Aggregate { group_by, aggregates, .. }
+                if group_by[..] == [b.clone()] && aggregates[0].func == AggFunc::Count
+        )));
+    }
+
+    #[test]
+    fn test_is_valid_match_modes() {
+        let table = |values: Vec<Vec<Value>>| ConcTable {
+            name: "".to_string(),
+            columns: vec!["a".to_string()],
+            values,
+        };
+        let example = |match_mode, ordered| Example {
+            input: vec![],
+            output: table(vec![vec![1.into()], vec![2.into()]]),
+            ordered,
+            match_mode,
+            column_match: ColumnMatch::ByName,
+        };
+        let reordered = table(vec![vec![2.into()], vec![1.into()]]);
+        let duplicated = table(vec![vec![1.into()], vec![2.into()], vec![1.into()]]);
+        let extra = table(vec![vec![1.into()], vec![3.into()], vec![2.into()]]);
+
+        assert!(is_valid(&reordered, &example(MatchMode::Bag, false)));
+        assert!(!is_valid(&reordered, &example(MatchMode::Bag, true)));
+        assert!(!is_valid(&duplicated, &example(MatchMode::Bag, false)));
+        assert!(is_valid(&duplicated, &example(MatchMode::Set, false)));
+        assert!(is_valid(&duplicated, &example(MatchMode::Set, true)));
+        assert!(!is_valid(&extra, &example(MatchMode::Set, false)));
+        assert!(is_valid(&extra, &example(MatchMode::Superset, true)));
+        assert!(!is_valid(&reordered, &example(MatchMode::Superset, true)));
+    }
+
+    #[test]
+    fn test_column_mapping_by_content() {
+        let result = ConcTable {
+            name: String::new(),
+            columns: vec!["b".to_string(), "a".to_string()],
+            values: vec![vec![2.into(), 1.into()], vec![4.into(), 3.into()]],
+        };
+        let mut example = Example {
+            input: Vec::new(),
+            output: ConcTable {
+                name: String::new(),
+                columns: vec!["first".to_string(), "second".to_string()],
+                values: vec![vec![1.into(), 2.into()], vec![3.into(), 4.into()]],
+            },
+            ordered: false,
+            match_mode: MatchMode::Bag,
+            column_match: ColumnMatch::ByName,
+        };
+
+        assert_eq!(column_mapping(&result, &example), None);
+        example.column_match = ColumnMatch::ByContent;
+        assert_eq!(column_mapping(&result, &example), Some(vec![1, 0]));
+    }
+
+    #[test]
+    fn test_generate_queries_column_order() {
+        let input = vec![ConcTable {
+            name: "t1".to_string(),
+            columns: vec!["id".to_string(), "grade".to_string()],
+            values: vec![vec![1.into(), 90.into()], vec![2.into(), 80.into()]],
+        }];
+        let output = ConcTable {
+            name: "".to_string(),
+            columns: vec!["grade".to_string(), "id".to_string()],
+            values: vec![vec![90.into(), 1.into()], vec![80.into(), 2.into()]],
+        };
+
+        let evaluator = SqliteEvaluator::new(&input).unwrap();
+        let queries = generate_abstract_queries(
+            Example {
+                input,
+                output,
+                ordered: false,
+                match_mode: MatchMode::Bag,
+                column_match: ColumnMatch::ByName,
+            },
+            1,
+            &evaluator,
+            &Stopper::new(),
+        )
+        .unwrap();
+
+        let columns = |q: &AST<()>| match q {
+            AST::Select {
+                fields: Some(items),
+                ..
+            } => items.iter().map(SelectItem::column_name).collect(),
+            _ => Vec::new(),
+        };
+        assert!(queries.iter().any(|q| columns(q) == ["grade", "id"]));
+    }
 }
@@ -1,10 +1,16 @@
-use std::collections::HashMap;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
 
 use crate::{
     enum_predicates,
-    types::{ConcTable, ExprNode, Field, PredNode, AST},
+    eval::{compile_expr, compile_pred, EvalError, Evaluator},
+    stop::{Stopped, Stopper},
+    types::{AggExpr, ConcTable, ExprNode, Field, JoinKind, PredNode, SetOp, Subquery, Value, AST},
 };
 use bitvec::{prelude as bv, vec::BitVec};
+use rayon::prelude::*;
 use thiserror::Error;
 
 impl ConcTable {
@@ -15,58 +21,117 @@ impl ConcTable {
 
 #[derive(Error, Debug)]
 pub enum BVDFSError {
-    #[error("error evaluating query in SQLite")]
-    SQLiteError(#[from] rusqlite::Error),
+    #[error("error evaluating query")]
+    Evaluation(#[from] EvalError),
     #[error("error generating predicates")]
     PredicateEnumeration(#[from] enum_predicates::PredicateEnumerationError),
+    #[error("stopped while searching for predicates")]
+    Stopped(#[from] Stopped),
 }
 
-#[derive(Debug)]
-struct Environment(HashMap<String, isize>);
-impl Environment {
-    fn from_row(table: &ConcTable, i: usize) -> Self {
-        let names = &table.columns;
-        let v = &table.values[i];
-        let map: HashMap<_, _> = names.iter().cloned().zip(v.iter().copied()).collect();
-        Self(map)
-    }
-}
-impl ExprNode {
-    fn eval2(&self, env: &Environment) -> Option<isize> {
+impl BVDFSError {
+    /// [e.stopped()] returns why the search stopped early, if that's what [e] is.
+    pub fn stopped(&self) -> Option<Stopped> {
         match self {
-            ExprNode::Field(Field { table: _, name }) => env.0.get(name).copied(),
-            ExprNode::Int { value } => Some(*value),
+            BVDFSError::Stopped(stopped)
+            | BVDFSError::PredicateEnumeration(
+                enum_predicates::PredicateEnumerationError::Stopped(stopped),
+            ) => Some(*stopped),
+            _ => None,
         }
     }
 }
 
-impl PredNode {
-    fn eval2(&self, env: &Environment) -> Option<bool> {
-        match self {
-            PredNode::True => Some(true),
-            PredNode::Lt { left, right } => left
-                .eval2(env)
-                .and_then(|left| right.eval2(env).map(|right| left < right)),
-            PredNode::Eq { left, right } => left
-                .eval2(env)
-                .and_then(|left| right.eval2(env).map(|right| left == right)),
-            PredNode::And { left, right } => left
-                .eval2(env)
-                .and_then(|left| right.eval2(env).map(|right| left && right)),
+/// [expr_values(rows, fields, e)] evaluates [e] on each of [rows], whose columns are
+/// [fields], returning [None] if [e] references a field that isn't in [rows].
+pub(crate) fn expr_values(rows: &ConcTable, fields: &[Field], e: &ExprNode) -> Option<Vec<Value>> {
+    let e = compile_expr(e, fields, &[])?;
+    Some(rows.values.iter().map(|row| e.eval(row)).collect())
+}
+
+/// A [TruthVector] records which rows a predicate is true for and which it's false for.
+/// Rows it's unknown for, because of a comparison with NULL, are in neither, so compound
+/// predicates can be evaluated with bitwise operations on their children's vectors.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct TruthVector {
+    holds: BitVec,
+    fails: BitVec,
+}
+
+impl TruthVector {
+    /// [TruthVector::all(len)] returns the vector of [PredNode::True] over [len] rows.
+    pub(crate) fn all(len: usize) -> Self {
+        TruthVector {
+            holds: bv::bitvec![1; len],
+            fails: bv::bitvec![0; len],
         }
     }
-}
 
-pub(crate) fn predicate_vector(rows: &ConcTable, p: &PredNode) -> BitVec {
-    let mut v = bv::bitvec![0; rows.values.len()];
-    for (i, mut x) in v.iter_mut().enumerate() {
-        let env = Environment::from_row(rows, i);
-        match p.eval2(&env) {
-            Some(b) => *x = b,
-            None => return bv::bitvec![0; rows.values.len()],
+    /// [v.holds()] returns the rows [v] is true for.
+    pub(crate) fn holds(&self) -> &BitVec {
+        &self.holds
+    }
+
+    /// [v1.and(v2)] returns the vector of the conjunction of the predicates of [v1] and [v2].
+    pub(crate) fn and(&self, other: &Self) -> Self {
+        TruthVector {
+            holds: self.holds.clone() & &other.holds,
+            fails: self.fails.clone() | &other.fails,
+        }
+    }
+
+    /// [v1.or(v2)] returns the vector of the disjunction of the predicates of [v1] and [v2].
+    pub(crate) fn or(&self, other: &Self) -> Self {
+        TruthVector {
+            holds: self.holds.clone() | &other.holds,
+            fails: self.fails.clone() & &other.fails,
+        }
+    }
+
+    /// [v.not()] returns the vector of the negation of the predicate of [v].
+    pub(crate) fn not(&self) -> Self {
+        TruthVector {
+            holds: self.fails.clone(),
+            fails: self.holds.clone(),
         }
     }
-    v
+}
+
+impl FromIterator<Option<bool>> for TruthVector {
+    fn from_iter<I: IntoIterator<Item = Option<bool>>>(iter: I) -> Self {
+        let (holds, fails) = iter
+            .into_iter()
+            .map(|b| (b == Some(true), b == Some(false)))
+            .unzip();
+        TruthVector { holds, fails }
+    }
+}
+
+/// [truth_vector(rows, fields, aggregates, p)] evaluates [p] on each of [rows], whose
+/// columns are [fields] followed by [aggregates], returning [None] if [p] references a
+/// column that isn't in [rows].
+pub(crate) fn truth_vector(
+    rows: &ConcTable,
+    fields: &[Field],
+    aggregates: &[AggExpr],
+    p: &PredNode,
+) -> Option<TruthVector> {
+    let p = compile_pred(p, fields, aggregates)?;
+    Some(rows.values.iter().map(|row| p.eval(row)).collect())
+}
+
+/// [predicate_vector(rows, fields, aggregates, p)] returns which of [rows], whose columns
+/// are [fields] followed by [aggregates], satisfy [p].
+pub(crate) fn predicate_vector(
+    rows: &ConcTable,
+    fields: &[Field],
+    aggregates: &[AggExpr],
+    p: &PredNode,
+) -> BitVec {
+    match truth_vector(rows, fields, aggregates, p) {
+        Some(v) => v.holds,
+        None => bv::bitvec![0; rows.values.len()],
+    }
 }
 
 fn cross(v1: &bv::BitSlice, v2: &bv::BitSlice) -> bv::BitVec {
@@ -79,21 +144,138 @@ fn cross(v1: &bv::BitSlice, v2: &bv::BitSlice) -> bv::BitVec {
     v
 }
 
-/// [bvdfs(q, predicates, row_counts, conn)] returns all bitvectors and the corresponding predicate sequence that generated the bitvector,
+/// [pad_outer_join(kind, v1, v2, inner)] extends [inner], the rows kept from the cross product
+/// of rows [v1] and [v2], with the NULL-padded rows an outer join of [kind] adds for rows that
+/// matched nothing. Padded rows come after the cross product, first those for [v1] and then
+/// those for [v2], which is how [crate::sql::eval_abstract] lays them out.
+fn pad_outer_join(
+    kind: JoinKind,
+    v1: &bv::BitSlice,
+    v2: &bv::BitSlice,
+    mut inner: bv::BitVec,
+) -> bv::BitVec {
+    let m = v2.len();
+    let left_padded: Vec<_> = v1
+        .iter()
+        .enumerate()
+        .map(|(i, b)| *b && inner[i * m..(i + 1) * m].not_any())
+        .collect();
+    let right_padded: Vec<_> = v2
+        .iter()
+        .enumerate()
+        .map(|(j, b)| *b && (0..v1.len()).all(|i| !inner[i * m + j]))
+        .collect();
+    if matches!(kind, JoinKind::Left | JoinKind::Full) {
+        inner.extend(left_padded);
+    }
+    if matches!(kind, JoinKind::Right | JoinKind::Full) {
+        inner.extend(right_padded);
+    }
+    inner
+}
+
+/// [keep_distinct(distinct, rows, v)] returns [v], keeping only the first of the rows of
+/// [rows] it keeps with each value if [distinct] is set.
+fn keep_distinct(distinct: bool, rows: &ConcTable, mut v: bv::BitVec) -> bv::BitVec {
+    if distinct {
+        let mut seen = HashSet::new();
+        for (row, mut b) in rows.values.iter().zip(v.iter_mut()) {
+            *b = *b && seen.insert(row);
+        }
+    }
+    v
+}
+
+/// [combine_set_op(op, all, rows1, v1, rows2, v2)] returns which rows of [rows1] followed by
+/// [rows2] make up the result of [op] over the rows of each kept by [v1] and [v2], which is
+/// how [crate::sql::eval_abstract] lays them out. When duplicates are removed, the first copy
+/// of a row is the one kept.
+fn combine_set_op(
+    op: SetOp,
+    all: bool,
+    rows1: &[Vec<Value>],
+    v1: &bv::BitSlice,
+    rows2: &[Vec<Value>],
+    v2: &bv::BitSlice,
+) -> bv::BitVec {
+    if op == SetOp::Union {
+        let mut v = v1.to_bitvec();
+        v.extend_from_bitslice(v2);
+        if !all {
+            let mut seen = HashSet::new();
+            for (row, mut b) in rows1.iter().chain(rows2.iter()).zip(v.iter_mut()) {
+                *b = *b && seen.insert(row);
+            }
+        }
+        return v;
+    }
+
+    let count = |rows: &[Vec<Value>], v: &bv::BitSlice| {
+        let mut counts: HashMap<_, usize> = HashMap::new();
+        for (row, b) in rows.iter().zip(v.iter()) {
+            if *b {
+                *counts.entry(row.clone()).or_default() += 1;
+            }
+        }
+        counts
+    };
+    let (counts1, counts2) = (count(rows1, v1), count(rows2, v2));
+    // Only rows of the first table can make it into the result. Under ALL, as many copies of
+    // a row are kept as there are in both tables (or more in the first, for EXCEPT), and like
+    // [crate::types::ConcTable::target_bitvec], those are the first copies.
+    let mut seen: HashMap<_, usize> = HashMap::new();
+    let mut v: bv::BitVec = rows1
+        .iter()
+        .zip(v1.iter())
+        .map(|(row, b)| {
+            if !*b {
+                return false;
+            }
+            let copy = seen.entry(row).or_default();
+            let count1 = counts1[row];
+            let count2 = counts2.get(row).copied().unwrap_or(0);
+            let keep = match (op, all) {
+                (SetOp::Intersect, true) => *copy < count2,
+                (SetOp::Intersect, false) => *copy == 0 && count2 > 0,
+                (SetOp::Except, true) => *copy + count2 < count1,
+                (SetOp::Except, false) => *copy == 0 && count2 == 0,
+                (SetOp::Union, _) => unreachable!("UNION is handled above"),
+            };
+            *copy += 1;
+            keep
+        })
+        .collect();
+    v.resize(rows1.len() + rows2.len(), false);
+    v
+}
+
+/// [bvdfs(q, constants, subqueries, max_predicate_depth, row_counts, evaluator, stopper)] returns all bitvectors and the corresponding predicate sequence that generated the bitvector,
 /// where the predicate pool is drawn from predicates, for the given abstract query.
 ///
 /// All bitvectors should be of the same arity. The ordering of the predicates is the parent node first, then all left children, then all right children,
 /// applied recursively, so the same construction should be used when substituting predicate nodes back into the tree.
+///
+/// If [stopper] stops before the search is done, nothing is returned but why it stopped.
 pub fn bvdfs(
     q: &AST<()>,
     constants: &[isize],
+    subqueries: &[Arc<Subquery>],
     max_predicate_depth: usize,
     row_counts: &mut HashMap<String, usize>,
-    conn: &rusqlite::Connection,
+    evaluator: &dyn Evaluator,
+    stopper: &Stopper,
 ) -> Result<Vec<(bv::BitVec, im::Vector<PredNode>)>, BVDFSError> {
+    stopper.check()?;
     // TODO: we only look over the representatives
-    let predicates =
-        crate::enum_predicates::enum_and_group_predicates(q, constants, max_predicate_depth, conn)?;
+    let predicates = crate::enum_predicates::enum_and_group_predicates(
+        q,
+        constants,
+        subqueries,
+        max_predicate_depth,
+        evaluator,
+        stopper,
+    )?;
+    let scope = crate::enum_predicates::predicate_scope(q);
     let representatives: Vec<_> = predicates
         .values()
         .map(|v| {
@@ -104,45 +286,76 @@ pub fn bvdfs(
         .collect();
     match q {
         AST::Select {
-            fields: _,
-            table,
-            pred: _,
+            distinct, table, ..
         } => {
-            let rows = crate::sql::eval_abstract(q, conn)?;
-            let other_vectors = bvdfs(table, constants, max_predicate_depth - 1, row_counts, conn)?;
+            let rows = &evaluator.eval_abstract(q)?;
+            let table_rows = &evaluator.eval_predicate_rows(q)?;
+            let other_vectors = bvdfs(
+                table,
+                constants,
+                subqueries,
+                max_predicate_depth - 1,
+                row_counts,
+                evaluator,
+                stopper,
+            )?;
             let all = representatives
-                .iter()
-                .flat_map(|p| {
-                    let v1 = predicate_vector(&rows, p);
+                .par_iter()
+                .flat_map_iter(|p| {
+                    let v1 = predicate_vector(table_rows, &scope, &[], p);
                     other_vectors.iter().map(move |(v2, preds)| {
                         let mut preds = preds.clone();
                         preds.push_front(p.clone());
-                        (v1.clone() & v2.clone(), preds)
+                        (
+                            keep_distinct(*distinct, rows, v1.clone() & v2.clone()),
+                            preds,
+                        )
                     })
                 })
                 .collect::<Vec<_>>();
             Ok(all)
         }
         AST::Join {
-            fields: _,
+            distinct,
+            kind,
             table1,
             table2,
-            pred: _,
+            ..
         } => {
+            let (kind, distinct) = (*kind, *distinct);
             // TODO: use the cached lengths instead of doing an eval_abstract here
-            let rows = crate::sql::eval_abstract(q, conn)?;
-            let left = bvdfs(table1, constants, max_predicate_depth, row_counts, conn)?;
-            let right = bvdfs(table2, constants, max_predicate_depth, row_counts, conn)?;
+            let rows = &evaluator.eval_abstract(q)?;
+            let join_rows = &evaluator.eval_predicate_rows(q)?;
+            let left = bvdfs(
+                table1,
+                constants,
+                subqueries,
+                max_predicate_depth,
+                row_counts,
+                evaluator,
+                stopper,
+            )?;
+            let right = bvdfs(
+                table2,
+                constants,
+                subqueries,
+                max_predicate_depth,
+                row_counts,
+                evaluator,
+                stopper,
+            )?;
             let all = representatives
-                .iter()
-                .flat_map(|p| {
-                    let v = predicate_vector(&rows, p);
+                .par_iter()
+                .flat_map_iter(|p| {
+                    let v = predicate_vector(join_rows, &scope, &[], p);
                     let right = right.clone();
                     left.clone().into_iter().flat_map(move |(l, vl)| {
                         let v = v.clone();
                         let right = right.clone();
                         right.into_iter().map(move |(r, vr)| {
-                            let v = cross(&l, &r) & v.clone();
+                            let inner = cross(&l, &r) & &v[..l.len() * r.len()];
+                            let v = pad_outer_join(kind, &l, &r, inner);
+                            let v = keep_distinct(distinct, rows, v);
                             let mut vector = vl.clone();
                             vector.append(vr);
                             vector.push_front(p.clone());
@@ -153,26 +366,44 @@ pub fn bvdfs(
                 .collect();
             Ok(all)
         }
-        AST::Table { name, columns: _ } => {
+        AST::Table { name, .. } => {
             use std::collections::hash_map::Entry;
             let row_count = match row_counts.entry(name.clone()) {
                 Entry::Occupied(e) => *e.get(),
                 Entry::Vacant(e) => {
                     let query = AST::Select {
                         fields: None,
+                        distinct: false,
                         table: Box::new(q.clone()),
                         pred: (),
+                        alias: AST::fresh_alias(&[q]),
                     };
-                    let rows = crate::sql::eval_abstract(&query, conn)?;
+                    let rows = evaluator.eval_abstract(&query)?;
                     e.insert(rows.values.len());
                     rows.values.len()
                 }
             };
             Ok(vec![(bv::bitvec![1; row_count], im::Vector::new())])
         }
-        AST::Concat { table1, table2 } => {
-            let left = bvdfs(table1, constants, max_predicate_depth, row_counts, conn)?;
-            let right = bvdfs(table2, constants, max_predicate_depth, row_counts, conn)?;
+        AST::Concat { table1, table2, .. } => {
+            let left = bvdfs(
+                table1,
+                constants,
+                subqueries,
+                max_predicate_depth,
+                row_counts,
+                evaluator,
+                stopper,
+            )?;
+            let right = bvdfs(
+                table2,
+                constants,
+                subqueries,
+                max_predicate_depth,
+                row_counts,
+                evaluator,
+                stopper,
+            )?;
             let all = left
                 .iter()
                 .flat_map(|(l, vl)| {
@@ -187,5 +418,78 @@ pub fn bvdfs(
                 .collect();
             Ok(all)
         }
+        AST::SetOp {
+            op,
+            all,
+            table1,
+            table2,
+            ..
+        } => {
+            // TODO: use the cached rows instead of doing an eval_abstract here
+            let rows1 = evaluator.eval_abstract(table1)?;
+            let rows2 = evaluator.eval_abstract(table2)?;
+            let left = bvdfs(
+                table1,
+                constants,
+                subqueries,
+                max_predicate_depth,
+                row_counts,
+                evaluator,
+                stopper,
+            )?;
+            let right = bvdfs(
+                table2,
+                constants,
+                subqueries,
+                max_predicate_depth,
+                row_counts,
+                evaluator,
+                stopper,
+            )?;
+            let all = left
+                .iter()
+                .flat_map(|(l, vl)| {
+                    right.iter().map(|(r, vr)| {
+                        let v = combine_set_op(*op, *all, &rows1.values, l, &rows2.values, r);
+                        let mut preds = vl.clone();
+                        preds.append(vr.clone());
+                        (v, preds)
+                    })
+                })
+                .collect();
+            Ok(all)
+        }
+        AST::OrderBy { table, .. } | AST::Limit { table, .. } => {
+            // NOTE: ORDER BY permutes the rows and LIMIT keeps a prefix of whichever rows reach
+            // it, so neither maps bitvectors below it onto its output. Like aggregates, we only
+            // consider leaving the predicates below True; [crate::synthesize] searches beneath
+            // them instead when they're at the root.
+            let rows = evaluator.eval_abstract(q)?;
+            Ok(vec![(
+                bv::bitvec![1; rows.values.len()],
+                std::iter::repeat_n(PredNode::True, table.num_holes()).collect(),
+            )])
+        }
+        AST::Aggregate {
+            table, aggregates, ..
+        } => {
+            // NOTE: predicates below an aggregate change the values within each group rather
+            // than which groups survive, which a bitvector over the output can't describe, so
+            // here we only consider leaving all of them True; [crate::synthesize] regroups the
+            // rows they keep when the aggregate is at the root. The HAVING clause filters
+            // groups just like a WHERE clause filters rows, though.
+            let rows = evaluator.eval_abstract(q)?;
+            let below: im::Vector<_> =
+                std::iter::repeat_n(PredNode::True, table.num_holes()).collect();
+            let all = representatives
+                .par_iter()
+                .map(|p| {
+                    let mut preds = below.clone();
+                    preds.push_front(p.clone());
+                    (predicate_vector(&rows, &scope, aggregates, p), preds)
+                })
+                .collect();
+            Ok(all)
+        }
     }
 }
@@ -1,120 +1,341 @@
-use crate::types::{ExprNode, Field, PredNode, AST};
+use crate::bottomup::{get_fields, output_fields};
+use crate::bvdfs::{truth_vector, TruthVector};
+use crate::eval::{EvalError, Evaluator};
+use crate::stop::{Stopped, Stopper};
+use crate::types::{AggExpr, ConcTable, ExprNode, Field, PredNode, Subquery, AST};
 use bitvec::prelude as bv;
-use itertools::Itertools;
-use std::collections::{HashMap, HashSet};
+use rayon::prelude::*;
+use std::collections::HashMap;
+use std::sync::Arc;
 use thiserror::Error;
 
 #[derive(Debug, Error)]
 pub enum PredicateEnumerationError {
-    #[error("database operation failed")]
-    DatabaseError(#[from] rusqlite::Error),
+    #[error("failed to evaluate the rows predicates apply to")]
+    Evaluation(#[from] EvalError),
+    #[error("stopped while enumerating predicates")]
+    Stopped(#[from] Stopped),
 }
 
-fn enum_primitive_pred(constants: &[isize], fields: &[Field]) -> Vec<PredNode> {
-    fn exprs<'a>(
-        constants: &'a [isize],
-        fields: &'a [Field],
-    ) -> impl Iterator<Item = ExprNode> + 'a + Clone {
-        constants
-            .iter()
-            .map(|n| ExprNode::Int { value: *n })
-            .chain(fields.iter().map(|f| ExprNode::Field(f.clone())))
+/// The maximum height of expressions compared in primitive predicates, so 2 allows a single
+/// arithmetic operator such as `t.a * t.b`.
+const MAX_EXPR_HEIGHT: usize = 2;
+
+/// [enum_exprs(constants, fields, aggregates, max_height)] returns every expression over
+/// [fields], [aggregates] and [constants] of height at most [max_height], ordered by height.
+///
+/// Compound expressions must reference a field, since arithmetic over constants alone is
+/// just another constant. Commutative operators are only applied in one order, and the
+/// others aren't applied to an expression and itself.
+pub(crate) fn enum_exprs(
+    constants: &[isize],
+    fields: &[Field],
+    aggregates: &[AggExpr],
+    max_height: usize,
+) -> Vec<ExprNode> {
+    let mut exprs: Vec<_> = fields
+        .iter()
+        .map(|f| ExprNode::Field(f.clone()))
+        .chain(aggregates.iter().map(|a| ExprNode::Aggregate(a.clone())))
+        .chain(constants.iter().map(|n| ExprNode::Int { value: *n }))
+        .collect();
+    for height in 2..=max_height {
+        let mut new = Vec::new();
+        for (i, left) in exprs.iter().enumerate() {
+            for (j, right) in exprs.iter().enumerate() {
+                // At least one side must come from the previous height, or we've already
+                // built this expression.
+                if left.height().max(right.height()) != height - 1
+                    || !(left.has_field() || right.has_field())
+                {
+                    continue;
+                }
+                let (l, r) = (Box::new(left.clone()), Box::new(right.clone()));
+                if i <= j {
+                    new.push(ExprNode::Add {
+                        left: l.clone(),
+                        right: r.clone(),
+                    });
+                    new.push(ExprNode::Mul {
+                        left: l.clone(),
+                        right: r.clone(),
+                    });
+                }
+                if i != j {
+                    new.push(ExprNode::Sub {
+                        left: l.clone(),
+                        right: r.clone(),
+                    });
+                    new.push(ExprNode::Div {
+                        left: l.clone(),
+                        right: r.clone(),
+                    });
+                    new.push(ExprNode::Mod { left: l, right: r });
+                }
+            }
+        }
+        exprs.extend(new);
+    }
+    exprs
+}
+
+fn enum_primitive_pred(
+    constants: &[isize],
+    fields: &[Field],
+    aggregates: &[AggExpr],
+    subqueries: &[Arc<Subquery>],
+) -> Vec<PredNode> {
+    let exprs = enum_exprs(constants, fields, aggregates, MAX_EXPR_HEIGHT);
+    // Comparisons with their operands swapped are already covered by the flipped operator,
+    // so only compare each expression against itself and those after it. To keep the number
+    // of predicates manageable, compound expressions are only compared against base ones.
+    let mut preds = vec![PredNode::True];
+    for (i, left) in exprs.iter().enumerate() {
+        for right in exprs[i..].iter() {
+            if left.height() > 1 && right.height() > 1 {
+                break;
+            }
+            preds.extend(PredNode::comparisons(left, right));
+        }
+    }
+    // Similarly, only fields are tested for membership, in subqueries with a single column.
+    for subquery in subqueries.iter() {
+        if let Some(scope) = &subquery.scope {
+            // Correlated subqueries select every field of their rows, so they're tested for
+            // membership once they're projected to each of them. EXISTS comes first, so that
+            // it's the one kept when both keep the same rows.
+            let correlated = correlations(subquery, fields);
+            let columns: Vec<_> = correlated
+                .iter()
+                .flat_map(|c| {
+                    scope
+                        .iter()
+                        .map(|inner| Arc::new(c.projected(inner.clone())))
+                })
+                .collect();
+            preds.extend(correlated.into_iter().map(|c| PredNode::Exists {
+                subquery: Arc::new(c),
+            }));
+            for column in columns.iter() {
+                preds.extend(fields.iter().map(|f| PredNode::In {
+                    expr: ExprNode::Field(f.clone()),
+                    subquery: Arc::clone(column),
+                }));
+            }
+            continue;
+        }
+        if subquery.query.arity() == 1 {
+            preds.extend(fields.iter().map(|f| PredNode::In {
+                expr: ExprNode::Field(f.clone()),
+                subquery: Arc::clone(subquery),
+            }));
+        }
+        preds.push(PredNode::Exists {
+            subquery: Arc::clone(subquery),
+        });
+    }
+    preds
+}
+
+/// [correlations(subquery, fields)] returns [subquery], a correlated subquery, filtered by
+/// each comparison of one of its fields with one of [fields], those of the query it's nested
+/// in. Like primitive predicates, these only compare fields. If [subquery] reads from a table
+/// with the same alias as one of [fields], which would hide it, there are none.
+fn correlations(subquery: &Subquery, fields: &[Field]) -> Vec<Subquery> {
+    let (scope, table) = match (&subquery.scope, &subquery.query) {
+        (Some(scope), AST::Select { table, .. }) => (scope, table),
+        _ => return Vec::new(),
+    };
+    let aliases = table.aliases();
+    if fields.iter().any(|f| aliases.contains(f.table.as_str())) {
+        return Vec::new();
     }
-    exprs(constants, fields)
-        .cartesian_product(exprs(constants, fields))
-        .flat_map(|(p1, p2)| {
-            std::iter::once(PredNode::Eq {
-                left: p1.clone(),
-                right: p2.clone(),
+    scope
+        .iter()
+        .flat_map(|inner| {
+            fields.iter().flat_map(move |outer| {
+                PredNode::comparisons(
+                    &ExprNode::Field(inner.clone()),
+                    &ExprNode::Field(outer.clone()),
+                )
             })
-            .chain(std::iter::once(PredNode::Lt {
-                left: p1,
-                right: p2,
-            }))
-            .chain(std::iter::once(PredNode::True))
         })
+        .map(|pred| subquery.filtered(pred))
         .collect()
 }
 
-fn enum_compound_pred(predicates: &[PredNode]) -> impl Iterator<Item = PredNode> + '_ {
-    predicates
-        .iter()
-        .cartesian_product(predicates.iter())
-        .map(|(p1, p2)| PredNode::And {
-            left: Box::new(p1.clone()),
-            right: Box::new(p2.clone()),
+/// [enum_compound_pred(predicates)] returns the conjunctions, disjunctions and negations of
+/// [predicates], each with its [TruthVector] composed from those of its children, which is
+/// [None] if it references a field that isn't in scope.
+fn enum_compound_pred(
+    predicates: &[(PredNode, Option<TruthVector>)],
+) -> impl ParallelIterator<Item = (PredNode, Option<TruthVector>)> + '_ {
+    let binary = predicates.par_iter().flat_map_iter(move |(p1, v1)| {
+        predicates.iter().flat_map(move |(p2, v2)| {
+            let vs = v1.as_ref().zip(v2.as_ref());
+            [
+                (
+                    PredNode::And {
+                        left: Box::new(p1.clone()),
+                        right: Box::new(p2.clone()),
+                    },
+                    vs.map(|(v1, v2)| v1.and(v2)),
+                ),
+                (
+                    PredNode::Or {
+                        left: Box::new(p1.clone()),
+                        right: Box::new(p2.clone()),
+                    },
+                    vs.map(|(v1, v2)| v1.or(v2)),
+                ),
+            ]
         })
+    });
+    let negations = predicates.par_iter().map(|(p, v)| {
+        (
+            PredNode::Not {
+                pred: Box::new(p.clone()),
+            },
+            v.as_ref().map(TruthVector::not),
+        )
+    });
+    binary.chain(negations)
+}
+
+/// [predicate_scope(q)] returns the fields the predicate at the root of [q] can reference,
+/// one for each column of the rows it's applied to, which are those of
+/// [Evaluator::eval_predicate_rows]. The aggregates a HAVING clause can also reference come
+/// after the grouping fields and aren't included.
+pub(crate) fn predicate_scope(q: &AST<()>) -> Vec<Field> {
+    scope_with(q, output_fields)
 }
 
-fn all_fields(q: &AST<()>) -> Vec<Field> {
+/// [scope_with(q, fields)] returns the [fields] of the queries the predicate at the root of
+/// [q] is applied to the rows of.
+fn scope_with(q: &AST<()>, fields: fn(&AST<()>) -> Vec<Field>) -> Vec<Field> {
     match q {
-        AST::Select {
-            fields,
-            table,
-            pred,
-        } => all_fields(table),
-        AST::Join {
-            fields,
-            table1,
-            table2,
-            pred,
-        } => all_fields(table1)
-            .into_iter()
-            .chain(all_fields(table2).into_iter())
-            .collect::<HashSet<_>>()
-            .into_iter()
-            .collect(),
-        AST::Table { name, columns } => columns
-            .iter()
-            .map(|c| Field {
-                table: name.clone(),
-                name: c.clone(),
-            })
-            .collect(),
-
-        AST::Concat { table1, table2 } => all_fields(table1)
-            .into_iter()
-            .chain(all_fields(table2).into_iter())
-            .collect::<HashSet<_>>()
-            .into_iter()
-            .collect(),
+        AST::Select { table, .. } => fields(table),
+        AST::Join { table1, table2, .. } => {
+            let mut scope = fields(table1);
+            scope.extend(fields(table2));
+            scope
+        }
+        AST::Aggregate { group_by, .. } => group_by.to_vec(),
+        _ => fields(q),
+    }
+}
+
+type Groups = HashMap<bv::BitVec, (Option<TruthVector>, Vec<PredNode>)>;
+
+/// [add_to_group(groups, len, p, v)] adds [p], whose [TruthVector] over [len] rows is [v],
+/// to the group of predicates satisfied by the same rows.
+fn add_to_group(groups: &mut Groups, len: usize, p: PredNode, v: Option<TruthVector>) {
+    let predicate_vector = match &v {
+        Some(v) => v.holds().clone(),
+        None => bv::bitvec![0; len],
+    };
+    groups
+        .entry(predicate_vector)
+        .or_insert_with(|| (v, Vec::new()))
+        .1
+        .push(p);
+}
+
+/// [merge_groups(groups, later)] adds the predicates of [later], which come after those of
+/// [groups], to the groups of [groups].
+fn merge_groups(mut groups: Groups, later: Groups) -> Groups {
+    for (predicate_vector, (v, preds)) in later {
+        groups
+            .entry(predicate_vector)
+            .or_insert_with(|| (v, Vec::new()))
+            .1
+            .extend(preds);
     }
+    groups
+}
+
+/// [group(preds, len, stopper)] groups [preds], whose [TruthVector]s are over [len] rows, on
+/// as many threads as are free, unless [stopper] stops first. The groups are the same as if
+/// [preds] were added one after another.
+fn group(
+    preds: impl ParallelIterator<Item = (PredNode, Option<TruthVector>)>,
+    len: usize,
+    stopper: &Stopper,
+) -> Result<Groups, Stopped> {
+    let groups = preds
+        .map(|pred| (!stopper.is_stopped()).then_some(pred))
+        .while_some()
+        .fold(Groups::new, |mut groups, (p, v)| {
+            add_to_group(&mut groups, len, p, v);
+            groups
+        })
+        .reduce(Groups::new, merge_groups);
+    stopper.check()?;
+    Ok(groups)
 }
 
 pub fn enum_and_group_predicates(
     q: &AST<()>,
     constants: &[isize],
+    subqueries: &[Arc<Subquery>],
     max_depth: usize,
-    conn: &rusqlite::Connection,
+    evaluator: &dyn Evaluator,
+    stopper: &Stopper,
 ) -> Result<HashMap<bv::BitVec, Vec<PredNode>>, PredicateEnumerationError> {
-    let t = crate::sql::eval_abstract(q, conn)?;
-    let fields = all_fields(q);
-    let primitives = enum_primitive_pred(constants, &fields);
-    let mut rep: HashMap<_, Vec<PredNode>> = HashMap::new();
-    primitives.into_iter().for_each(|p| {
-        let predicate_vector = crate::bvdfs::predicate_vector(&t, &p);
-        rep.entry(predicate_vector).or_insert_with(Vec::new).push(p);
+    let t = evaluator.eval_predicate_rows(q)?;
+    let rep = group_predicates(q, &t, constants, subqueries, max_depth, stopper)?;
+    for preds in rep.values() {
+        println!("{}", preds.first().unwrap());
+    }
+
+    // TODO: sort the Vec by simplicity?
+    Ok(rep)
+}
+
+/// [group_predicates(q, t, constants, subqueries, max_depth, stopper)] enumerates the
+/// predicates that can fill the hole at the root of [q] and groups them by which rows of [t]
+/// satisfy them, where [t] is laid out like [Evaluator::eval_predicate_rows(q)] but needn't
+/// be the rows it evaluates to.
+pub(crate) fn group_predicates(
+    q: &AST<()>,
+    t: &ConcTable,
+    constants: &[isize],
+    subqueries: &[Arc<Subquery>],
+    max_depth: usize,
+    stopper: &Stopper,
+) -> Result<HashMap<bv::BitVec, Vec<PredNode>>, Stopped> {
+    let scope = predicate_scope(q);
+    // Columns named after expressions can't be referenced as fields.
+    let fields = scope_with(q, get_fields);
+    // Only the HAVING clause of an aggregate can reference the aggregates it computes.
+    let aggregates = match q {
+        AST::Aggregate { aggregates, .. } => aggregates.to_vec(),
+        _ => Vec::new(),
+    };
+    let primitives = enum_primitive_pred(constants, &fields, &aggregates, subqueries);
+    // Each group keeps the truth vector of its first predicate, which represents it when
+    // building compound predicates, so those never have to be evaluated row by row.
+    let len = t.values.len();
+    let primitives = primitives.into_par_iter().map(|p| {
+        let v = truth_vector(t, &scope, &aggregates, &p);
+        (p, v)
     });
+    let mut groups = group(primitives, len, stopper)?;
 
     for _ in 1..max_depth {
-        let representatives = rep
+        let representatives = groups
             .values()
-            .map(|v| {
-                v.first()
-                    .expect("all vectors in rep must be non-empty")
-                    .clone()
+            .map(|(v, preds)| {
+                let p = preds.first().expect("all groups must be non-empty");
+                (p.clone(), v.clone())
             })
             .collect::<Vec<_>>();
-        enum_compound_pred(&representatives).for_each(|p| {
-            let predicate_vector = crate::bvdfs::predicate_vector(&t, &p);
-            rep.entry(predicate_vector).or_insert_with(Vec::new).push(p);
-        });
+        let compound = group(enum_compound_pred(&representatives), len, stopper)?;
+        groups = merge_groups(groups, compound);
     }
 
-    for preds in rep.values() {
-        println!("{}", preds.first().unwrap());
-    }
-
-    // TODO: sort the Vec by simplicity?
-    Ok(rep)
+    Ok(groups
+        .into_iter()
+        .map(|(k, (_v, preds))| (k, preds))
+        .collect())
 }
@@ -0,0 +1,998 @@
+use crate::types::*;
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum EvalError {
+    #[error("failed to run the query in SQLite")]
+    Database(#[from] rusqlite::Error),
+    #[error("no table named {0:?}")]
+    UnknownTable(String),
+    #[error("no column {0}")]
+    UnknownColumn(String),
+}
+
+/// Evaluates queries over the tables of an example. [crate::sql::SqliteEvaluator] does so by
+/// printing them as SQL for SQLite, and [NativeEvaluator] does so in memory, with the same
+/// results down to the order of the rows and the names of the columns. Evaluators are shared
+/// by the threads that synthesis runs on.
+pub trait Evaluator: Sync {
+    /// [e.eval(query)] returns the rows [query] produces.
+    fn eval(&self, query: &AST<PredNode>) -> Result<ConcTable, EvalError>;
+
+    /// [e.eval_abstract(query)] returns every row [query] could produce for some choice of its
+    /// predicates, laid out the way [crate::bvdfs::bvdfs] expects. Outer joins produce their
+    /// whole cross product followed by every NULL-padded row, set operations produce the rows
+    /// of both tables, and duplicates are kept. If [query] can't be evaluated, it produces no
+    /// rows.
+    fn eval_abstract(&self, query: &AST<()>) -> Result<ConcTable, EvalError>;
+
+    /// [e.eval_predicate_rows(query)] evaluates the rows that the predicate at the root of
+    /// [query] is applied to, laid out like those of [Evaluator::eval_abstract]. Selections
+    /// and joins apply their predicates before projecting, so these include every field of
+    /// their tables.
+    fn eval_predicate_rows(&self, query: &AST<()>) -> Result<ConcTable, EvalError> {
+        match query {
+            AST::Select { table, .. } => self.eval_abstract(table),
+            AST::Join {
+                fields: Some(_),
+                kind,
+                table1,
+                table2,
+                alias,
+                ..
+            } => {
+                let query = AST::Join {
+                    fields: None,
+                    distinct: false,
+                    kind: *kind,
+                    table1: table1.clone(),
+                    table2: table2.clone(),
+                    pred: (),
+                    alias: alias.clone(),
+                };
+                self.eval_abstract(&query)
+            }
+            _ => self.eval_abstract(query),
+        }
+    }
+}
+
+impl Subquery {
+    /// [Subquery::new(query, evaluator)] evaluates [query] so that it can be nested in
+    /// predicates.
+    pub fn new(query: AST<PredNode>, evaluator: &dyn Evaluator) -> Result<Subquery, EvalError> {
+        let rows = evaluator.eval(&query)?.values;
+        Ok(Subquery {
+            query,
+            rows,
+            scope: None,
+        })
+    }
+
+    /// [Subquery::correlated(table, evaluator)] evaluates the rows of [table], an example
+    /// table, for a correlated subquery selecting all of them. Its predicate is True until
+    /// it's replaced with [s.filtered(pred)].
+    pub fn correlated(table: &AST<()>, evaluator: &dyn Evaluator) -> Result<Subquery, EvalError> {
+        let scope = crate::bottomup::get_fields(table);
+        let table = table.with_predicates(&[]).expect("tables don't have holes");
+        let rows = evaluator.eval(&table)?.values;
+        let query = AST::Select {
+            fields: None,
+            distinct: false,
+            pred: PredNode::True,
+            alias: AST::fresh_alias(&[&table]),
+            table: Box::new(table),
+        };
+        Ok(Subquery {
+            query,
+            rows,
+            scope: Some(Arc::from(scope)),
+        })
+    }
+}
+
+/// [empty_table(query)] is what [Evaluator::eval_abstract] returns for a [query] that can't
+/// be evaluated: no rows, with the columns of its leftmost table.
+pub(crate) fn empty_table<T>(query: &AST<T>) -> ConcTable {
+    match query {
+        AST::Table { name, columns, .. } => ConcTable {
+            name: name.clone(),
+            columns: columns.clone(),
+            values: Vec::new(),
+        },
+        AST::Select { table, .. }
+        | AST::Aggregate { table, .. }
+        | AST::OrderBy { table, .. }
+        | AST::Limit { table, .. } => empty_table(table),
+        AST::Join { table1, .. } | AST::Concat { table1, .. } | AST::SetOp { table1, .. } => {
+            empty_table(table1)
+        }
+    }
+}
+
+/// Evaluates queries over tables held in memory, following SQLite's semantics for them.
+pub struct NativeEvaluator {
+    tables: Vec<ConcTable>,
+}
+
+/// An expression whose fields have been resolved to the columns of the rows it's evaluated
+/// on.
+pub(crate) enum Expr {
+    Column(usize),
+    Const(Value),
+    Arith(fn(&Value, &Value) -> Value, Box<Expr>, Box<Expr>),
+}
+
+/// A predicate whose fields have been resolved like those of an [Expr].
+pub(crate) enum Pred {
+    True,
+    Compare(Comparison, Expr, Expr),
+    And(Box<Pred>, Box<Pred>),
+    Or(Box<Pred>, Box<Pred>),
+    Not(Box<Pred>),
+    In(Expr, Nested),
+    Exists(Nested),
+}
+
+/// A subquery nested in a [Pred]. If it's correlated, its predicate and the expression for
+/// its single column are resolved against its rows followed by the row it's evaluated for.
+pub(crate) struct Nested {
+    subquery: Arc<Subquery>,
+    correlation: Option<(Box<Pred>, Expr)>,
+}
+
+impl Nested {
+    /// [n.rows(row)] returns the rows of the subquery that its predicate keeps when it's
+    /// evaluated for [row], each followed by [row], or [None] if it's uncorrelated.
+    fn rows<'a>(&'a self, row: &'a [Value]) -> Option<impl Iterator<Item = Vec<Value>> + 'a> {
+        let (pred, _) = self.correlation.as_ref()?;
+        Some(self.subquery.rows.iter().filter_map(move |inner| {
+            let joined: Vec<_> = inner.iter().chain(row).cloned().collect();
+            (pred.eval(&joined) == Some(true)).then_some(joined)
+        }))
+    }
+
+    /// [n.contains(row, v)] is SQL's [v IN n] when [n] is evaluated for [row].
+    fn contains(&self, row: &[Value], v: &Value) -> Option<bool> {
+        match (self.rows(row), &self.correlation) {
+            (Some(rows), Some((_, column))) => sql_in(v, rows.map(|joined| column.eval(&joined))),
+            _ => self.subquery.contains(v),
+        }
+    }
+
+    /// [n.exists(row)] is SQL's [EXISTS n] when [n] is evaluated for [row].
+    fn exists(&self, row: &[Value]) -> bool {
+        match self.rows(row) {
+            Some(mut rows) => rows.next().is_some(),
+            None => self.subquery.exists(),
+        }
+    }
+}
+
+impl Expr {
+    /// [e.eval(row)] evaluates [e] on [row].
+    pub(crate) fn eval(&self, row: &[Value]) -> Value {
+        match self {
+            Expr::Column(i) => row[*i].clone(),
+            Expr::Const(v) => v.clone(),
+            Expr::Arith(op, left, right) => op(&left.eval(row), &right.eval(row)),
+        }
+    }
+}
+
+impl Pred {
+    /// [p.eval(row)] evaluates [p] under three-valued logic, where [None] is unknown.
+    pub(crate) fn eval(&self, row: &[Value]) -> Option<bool> {
+        match self {
+            Pred::True => Some(true),
+            Pred::Compare(holds, left, right) => {
+                left.eval(row).sql_cmp(&right.eval(row)).map(holds)
+            }
+            Pred::And(left, right) => and3(left.eval(row), right.eval(row)),
+            Pred::Or(left, right) => or3(left.eval(row), right.eval(row)),
+            Pred::Not(pred) => pred.eval(row).map(|b| !b),
+            Pred::In(expr, nested) => nested.contains(row, &expr.eval(row)),
+            Pred::Exists(nested) => Some(nested.exists(row)),
+        }
+    }
+}
+
+/// The columns of the rows a clause is evaluated on, which each come from the query with
+/// some alias in the FROM clause.
+struct Scope<'a> {
+    columns: Vec<(&'a str, &'a str)>,
+}
+
+impl<'a> Scope<'a> {
+    fn new(sources: &'a [(&'a str, ConcTable)]) -> Self {
+        let columns = sources
+            .iter()
+            .flat_map(|(alias, t)| t.columns.iter().map(move |c| (*alias, c.as_str())))
+            .collect();
+        Scope { columns }
+    }
+
+    /// [s.column(f)] returns the index of the first column [f] refers to. Like SQLite, names
+    /// are compared case-insensitively.
+    fn column(&self, f: &Field) -> Result<usize, EvalError> {
+        self.columns
+            .iter()
+            .position(|(alias, name)| {
+                alias.eq_ignore_ascii_case(&f.table) && name.eq_ignore_ascii_case(&f.name)
+            })
+            .ok_or_else(|| EvalError::UnknownColumn(format!("{}.{}", f.table, f.name)))
+    }
+}
+
+/// [resolve_expr(e, lookup)] resolves the fields and aggregates of [e] to columns with
+/// [lookup].
+fn resolve_expr(
+    e: &ExprNode,
+    lookup: &dyn Fn(&ExprNode) -> Result<usize, EvalError>,
+) -> Result<Expr, EvalError> {
+    let arith = |op, left: &ExprNode, right: &ExprNode| {
+        Ok(Expr::Arith(
+            op,
+            Box::new(resolve_expr(left, lookup)?),
+            Box::new(resolve_expr(right, lookup)?),
+        ))
+    };
+    match e {
+        ExprNode::Field(_) | ExprNode::Aggregate(_) => Ok(Expr::Column(lookup(e)?)),
+        ExprNode::Int { value } => Ok(Expr::Const(Value::Integer(*value))),
+        ExprNode::Add { left, right } => arith(Value::sql_add, left, right),
+        ExprNode::Sub { left, right } => arith(Value::sql_sub, left, right),
+        ExprNode::Mul { left, right } => arith(Value::sql_mul, left, right),
+        ExprNode::Div { left, right } => arith(Value::sql_div, left, right),
+        ExprNode::Mod { left, right } => arith(Value::sql_rem, left, right),
+    }
+}
+
+/// [resolve_pred(p, lookup)] resolves the fields and aggregates of [p] to columns with
+/// [lookup].
+fn resolve_pred(
+    p: &PredNode,
+    lookup: &dyn Fn(&ExprNode) -> Result<usize, EvalError>,
+) -> Result<Pred, EvalError> {
+    match p {
+        PredNode::True => Ok(Pred::True),
+        PredNode::Lt { .. }
+        | PredNode::Eq { .. }
+        | PredNode::Le { .. }
+        | PredNode::Gt { .. }
+        | PredNode::Ge { .. }
+        | PredNode::Ne { .. } => {
+            let (holds, left, right) = p.comparison().expect("p is a comparison");
+            Ok(Pred::Compare(
+                holds,
+                resolve_expr(left, lookup)?,
+                resolve_expr(right, lookup)?,
+            ))
+        }
+        PredNode::And { left, right } => Ok(Pred::And(
+            Box::new(resolve_pred(left, lookup)?),
+            Box::new(resolve_pred(right, lookup)?),
+        )),
+        PredNode::Or { left, right } => Ok(Pred::Or(
+            Box::new(resolve_pred(left, lookup)?),
+            Box::new(resolve_pred(right, lookup)?),
+        )),
+        PredNode::Not { pred } => Ok(Pred::Not(Box::new(resolve_pred(pred, lookup)?))),
+        PredNode::In { expr, subquery } => Ok(Pred::In(
+            resolve_expr(expr, lookup)?,
+            resolve_nested(subquery, lookup)?,
+        )),
+        PredNode::Exists { subquery } => Ok(Pred::Exists(resolve_nested(subquery, lookup)?)),
+    }
+}
+
+/// [resolve_nested(subquery, lookup)] resolves the fields of [subquery] that belong to the
+/// query it's nested in with [lookup], if it's correlated.
+fn resolve_nested(
+    subquery: &Arc<Subquery>,
+    lookup: &dyn Fn(&ExprNode) -> Result<usize, EvalError>,
+) -> Result<Nested, EvalError> {
+    let correlation = match (&subquery.scope, &subquery.query) {
+        (Some(scope), AST::Select { fields, pred, .. }) => {
+            let lookup = |e: &ExprNode| {
+                let inner = match e {
+                    ExprNode::Field(f) => scope.iter().position(|g| g == f),
+                    _ => None,
+                };
+                match inner {
+                    Some(i) => Ok(i),
+                    None => lookup(e).map(|i| scope.len() + i),
+                }
+            };
+            let column = match fields {
+                Some(items) => resolve_expr(&items[0].expr, &lookup)?,
+                None => Expr::Column(0),
+            };
+            Some((Box::new(resolve_pred(pred, &lookup)?), column))
+        }
+        _ => None,
+    };
+    Ok(Nested {
+        subquery: Arc::clone(subquery),
+        correlation,
+    })
+}
+
+/// [positional_lookup(fields, aggregates)] resolves fields and aggregates to their positions
+/// in rows whose columns are [fields] followed by [aggregates], which is how the rows
+/// predicates are applied to during synthesis are laid out. Aggregates are compared by
+/// their fields rather than their column names, which leave out the table. Anything that
+/// appears more than once resolves to its first column.
+fn positional_lookup<'a>(
+    fields: &'a [Field],
+    aggregates: &'a [AggExpr],
+) -> impl Fn(&ExprNode) -> Result<usize, EvalError> + 'a {
+    move |e| {
+        let column = match e {
+            ExprNode::Field(f) => fields.iter().position(|g| g == f),
+            ExprNode::Aggregate(a) => aggregates
+                .iter()
+                .position(|b| b == a)
+                .map(|i| i + fields.len()),
+            _ => None,
+        };
+        column.ok_or_else(|| EvalError::UnknownColumn(e.to_string()))
+    }
+}
+
+/// [compile_expr(e, fields, aggregates)] resolves [e] against rows whose columns are
+/// [fields] followed by [aggregates], returning [None] if [e] references a column that isn't
+/// in them.
+pub(crate) fn compile_expr(e: &ExprNode, fields: &[Field], aggregates: &[AggExpr]) -> Option<Expr> {
+    resolve_expr(e, &positional_lookup(fields, aggregates)).ok()
+}
+
+/// [compile_pred(p, fields, aggregates)] resolves [p] like [compile_expr].
+pub(crate) fn compile_pred(p: &PredNode, fields: &[Field], aggregates: &[AggExpr]) -> Option<Pred> {
+    resolve_pred(p, &positional_lookup(fields, aggregates)).ok()
+}
+
+/// [sort_cmp(a, b)] orders values the way SQLite sorts them: NULL first, then numbers, then
+/// text.
+fn sort_cmp(a: &Value, b: &Value) -> Ordering {
+    match (a, b) {
+        (Value::Null, Value::Null) => Ordering::Equal,
+        (Value::Null, _) => Ordering::Less,
+        (_, Value::Null) => Ordering::Greater,
+        _ => a.sql_cmp(b).unwrap_or(Ordering::Equal),
+    }
+}
+
+fn sort_cmp_rows(a: &[Value], b: &[Value]) -> Ordering {
+    a.iter()
+        .zip(b.iter())
+        .map(|(a, b)| sort_cmp(a, b))
+        .find(|o| o.is_ne())
+        .unwrap_or(Ordering::Equal)
+}
+
+/// [row_key(row)] identifies [row] up to SQL equality, under which integers equal the reals
+/// with the same value, and NULL is the same as NULL.
+fn row_key(row: &[Value]) -> Vec<Value> {
+    row.iter()
+        .map(|v| match v {
+            Value::Real(x) if x.fract() == 0.0 && x.abs() < isize::MAX as f64 => {
+                Value::Integer(*x as isize)
+            }
+            v => v.clone(),
+        })
+        .collect()
+}
+
+/// [distinct_rows(rows)] keeps the first of [rows] with each value.
+fn distinct_rows(rows: Vec<Vec<Value>>) -> Vec<Vec<Value>> {
+    let mut seen = HashSet::new();
+    rows.into_iter()
+        .filter(|row| seen.insert(row_key(row)))
+        .collect()
+}
+
+/// [derived_columns(columns)] renames the columns of a query nested in a FROM clause the way
+/// SQLite does, so that they're unique: a repeated name gets a suffix counting up from
+/// [:1] until it's new.
+fn derived_columns(columns: &[String]) -> Vec<String> {
+    let mut taken = HashSet::new();
+    columns
+        .iter()
+        .map(|name| {
+            let base = match name.rsplit_once(':') {
+                Some((base, n)) if !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()) => base,
+                _ => name.as_str(),
+            };
+            let mut name = name.clone();
+            let mut count = 0;
+            while !taken.insert(name.to_lowercase()) {
+                count += 1;
+                name = format!("{}:{}", base, count);
+            }
+            name
+        })
+        .collect()
+}
+
+fn aggregate(func: AggFunc, values: &[&Value]) -> Value {
+    let values: Vec<_> = values.iter().filter(|v| ***v != Value::Null).collect();
+    match func {
+        AggFunc::Count => Value::Integer(values.len() as isize),
+        _ if values.is_empty() => Value::Null,
+        AggFunc::Min => (**values.iter().min_by(|a, b| sort_cmp(a, b)).unwrap()).clone(),
+        AggFunc::Max => (**values.iter().rev().max_by(|a, b| sort_cmp(a, b)).unwrap()).clone(),
+        AggFunc::Sum | AggFunc::Avg => {
+            // Integers are summed exactly unless they overflow, but anything else makes the
+            // sum real, as does averaging.
+            let integers: Option<Vec<isize>> = values
+                .iter()
+                .map(|v| match v {
+                    Value::Integer(n) => Some(*n),
+                    _ => None,
+                })
+                .collect();
+            let sum = integers
+                .and_then(|ns| ns.into_iter().try_fold(0isize, isize::checked_add))
+                .filter(|_| func == AggFunc::Sum);
+            match sum {
+                Some(n) => Value::Integer(n),
+                None => {
+                    let total = values
+                        .iter()
+                        .fold(Value::Real(0.0), |acc, v| acc.sql_add(v));
+                    match func {
+                        AggFunc::Avg => total.sql_div(&Value::Real(values.len() as f64)),
+                        _ => total,
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl NativeEvaluator {
+    /// [NativeEvaluator::new(tables)] evaluates queries over [tables], like
+    /// [crate::sql::SqliteEvaluator::new(tables)].
+    pub fn new(tables: &[ConcTable]) -> Self {
+        NativeEvaluator {
+            tables: tables.to_vec(),
+        }
+    }
+
+    /// [e.source(query, abstract_rows)] evaluates [query] as an item of a FROM clause,
+    /// returning its alias with its rows.
+    fn source<'q>(
+        &self,
+        query: &'q AST<PredNode>,
+        abstract_rows: bool,
+    ) -> Result<(&'q str, ConcTable), EvalError> {
+        let mut t = self.eval_query(query, abstract_rows)?;
+        if !matches!(query, AST::Table { .. }) {
+            t.columns = derived_columns(&t.columns);
+        }
+        Ok((query.alias(), t))
+    }
+
+    /// [e.project(fields, distinct, sources, rows, abstract_rows)] selects [fields] (or every
+    /// column, if [None]) from [rows], whose columns are those of [sources].
+    fn project(
+        &self,
+        fields: &Option<Arc<[SelectItem]>>,
+        distinct: bool,
+        sources: &[(&str, ConcTable)],
+        rows: Vec<Vec<Value>>,
+        abstract_rows: bool,
+    ) -> Result<ConcTable, EvalError> {
+        let scope = Scope::new(sources);
+        let (columns, mut values) = match fields {
+            None => (
+                scope.columns.iter().map(|(_, c)| c.to_string()).collect(),
+                rows,
+            ),
+            Some(items) => {
+                let lookup = |e: &ExprNode| match e {
+                    ExprNode::Field(f) => scope.column(f),
+                    _ => Err(EvalError::UnknownColumn(e.to_string())),
+                };
+                let exprs = items
+                    .iter()
+                    .map(|item| resolve_expr(&item.expr, &lookup))
+                    .collect::<Result<Vec<_>, _>>()?;
+                // Fields are named after the columns they are, as spelled in their tables.
+                let columns = items
+                    .iter()
+                    .zip(exprs.iter())
+                    .map(|(item, expr)| match (expr, &item.alias, &item.expr) {
+                        (Expr::Column(i), None, ExprNode::Field(_)) => {
+                            scope.columns[*i].1.to_string()
+                        }
+                        _ => item.column_name(),
+                    })
+                    .collect();
+                let values = rows
+                    .iter()
+                    .map(|row| exprs.iter().map(|e| e.eval(row)).collect())
+                    .collect();
+                (columns, values)
+            }
+        };
+        if distinct && !abstract_rows {
+            values = distinct_rows(values);
+        }
+        Ok(ConcTable {
+            name: String::new(),
+            columns,
+            values,
+        })
+    }
+
+    fn eval_query(
+        &self,
+        query: &AST<PredNode>,
+        abstract_rows: bool,
+    ) -> Result<ConcTable, EvalError> {
+        match query {
+            AST::Table { name, .. } => {
+                let t = self
+                    .tables
+                    .iter()
+                    .find(|t| t.name.eq_ignore_ascii_case(name))
+                    .ok_or_else(|| EvalError::UnknownTable(name.clone()))?;
+                Ok(ConcTable {
+                    name: String::new(),
+                    columns: t.columns.clone(),
+                    values: t.values.clone(),
+                })
+            }
+            AST::Select {
+                fields,
+                distinct,
+                table,
+                pred,
+                ..
+            } => {
+                let sources = [self.source(table, abstract_rows)?];
+                let scope = Scope::new(&sources);
+                let pred = resolve_pred(pred, &|e| match e {
+                    ExprNode::Field(f) => scope.column(f),
+                    _ => Err(EvalError::UnknownColumn(e.to_string())),
+                })?;
+                let rows = sources[0]
+                    .1
+                    .values
+                    .iter()
+                    .filter(|row| pred.eval(row) == Some(true))
+                    .cloned()
+                    .collect();
+                self.project(fields, *distinct, &sources, rows, abstract_rows)
+            }
+            AST::Join {
+                fields,
+                distinct,
+                kind,
+                table1,
+                table2,
+                pred,
+                ..
+            } => {
+                let sources = [
+                    self.source(table1, abstract_rows)?,
+                    self.source(table2, abstract_rows)?,
+                ];
+                let scope = Scope::new(&sources);
+                let pred = resolve_pred(pred, &|e| match e {
+                    ExprNode::Field(f) => scope.column(f),
+                    _ => Err(EvalError::UnknownColumn(e.to_string())),
+                })?;
+                let rows = join(*kind, &sources[0].1, &sources[1].1, &pred, abstract_rows);
+                self.project(fields, *distinct, &sources, rows, abstract_rows)
+            }
+            AST::Concat { table1, table2, .. } => {
+                let sources = [
+                    self.source(table1, abstract_rows)?,
+                    self.source(table2, abstract_rows)?,
+                ];
+                let rows = join(
+                    JoinKind::Inner,
+                    &sources[0].1,
+                    &sources[1].1,
+                    &Pred::True,
+                    abstract_rows,
+                );
+                self.project(&None, false, &sources, rows, abstract_rows)
+            }
+            AST::Aggregate {
+                group_by,
+                aggregates,
+                table,
+                having,
+                ..
+            } => {
+                let sources = [self.source(table, abstract_rows)?];
+                let scope = Scope::new(&sources);
+                let keys = group_by
+                    .iter()
+                    .map(|f| scope.column(f))
+                    .collect::<Result<Vec<_>, _>>()?;
+                let arguments = aggregates
+                    .iter()
+                    .map(|a| scope.column(&a.field))
+                    .collect::<Result<Vec<_>, _>>()?;
+                let rows = &sources[0].1.values;
+                // SQLite sorts the rows to group them, so the groups come out in order of
+                // their keys. Without any keys, there's always exactly one group.
+                let mut order: Vec<_> = (0..rows.len()).collect();
+                let key = |i: usize| keys.iter().map(|k| rows[i][*k].clone()).collect::<Vec<_>>();
+                order.sort_by(|i, j| sort_cmp_rows(&key(*i), &key(*j)));
+                let mut groups: Vec<Vec<usize>> = Vec::new();
+                for i in order {
+                    match groups.last_mut() {
+                        Some(group) if sort_cmp_rows(&key(group[0]), &key(i)).is_eq() => {
+                            group.push(i)
+                        }
+                        _ => groups.push(vec![i]),
+                    }
+                }
+                if groups.is_empty() && keys.is_empty() {
+                    groups.push(Vec::new());
+                }
+                // The HAVING clause is evaluated on each output row, so it reads the grouping
+                // fields and aggregates from there.
+                let having = resolve_pred(having, &|e| {
+                    let i = match e {
+                        ExprNode::Field(f) => group_by.iter().position(|g| g == f),
+                        ExprNode::Aggregate(a) => aggregates
+                            .iter()
+                            .position(|b| b == a)
+                            .map(|i| group_by.len() + i),
+                        _ => None,
+                    };
+                    i.ok_or_else(|| EvalError::UnknownColumn(e.to_string()))
+                })?;
+                let values = groups
+                    .iter()
+                    .map(|group| {
+                        let mut row = match group.last() {
+                            Some(&i) => key(i),
+                            None => Vec::new(),
+                        };
+                        for (a, i) in aggregates.iter().zip(arguments.iter()) {
+                            let values: Vec<_> = group.iter().map(|j| &rows[*j][*i]).collect();
+                            row.push(aggregate(a.func, &values));
+                        }
+                        row
+                    })
+                    .filter(|row| having.eval(row) == Some(true))
+                    .collect();
+                let columns = keys
+                    .iter()
+                    .map(|k| scope.columns[*k].1.to_string())
+                    .chain(aggregates.iter().map(AggExpr::column_name))
+                    .collect();
+                Ok(ConcTable {
+                    name: String::new(),
+                    columns,
+                    values,
+                })
+            }
+            AST::OrderBy { keys, table, .. } => {
+                let sources = [self.source(table, abstract_rows)?];
+                let scope = Scope::new(&sources);
+                let resolved = keys
+                    .iter()
+                    .map(|k| Ok((scope.column(&k.field)?, k.dir)))
+                    .collect::<Result<Vec<_>, EvalError>>()?;
+                let [(_, mut t)] = sources;
+                t.values.sort_by(|a, b| {
+                    resolved
+                        .iter()
+                        .map(|(i, dir)| match dir {
+                            SortDir::Asc => sort_cmp(&a[*i], &b[*i]),
+                            SortDir::Desc => sort_cmp(&b[*i], &a[*i]),
+                        })
+                        .find(|o| o.is_ne())
+                        .unwrap_or(Ordering::Equal)
+                });
+                Ok(t)
+            }
+            AST::Limit { count, table, .. } => {
+                let [(_, mut t)] = [self.source(table, abstract_rows)?];
+                t.values.truncate(*count);
+                Ok(t)
+            }
+            AST::SetOp {
+                op,
+                all,
+                table1,
+                table2,
+                ..
+            } => {
+                let (_, mut t1) = self.source(table1, abstract_rows)?;
+                let (_, t2) = self.source(table2, abstract_rows)?;
+                if abstract_rows || (*op == SetOp::Union && *all) {
+                    t1.values.extend(t2.values);
+                    return Ok(t1);
+                }
+                // Otherwise, SQLite matches rows up in a sorted index, which they come out of
+                // in order.
+                let mut counts2: HashMap<_, usize> = HashMap::new();
+                for row in t2.values.iter() {
+                    *counts2.entry(row_key(row)).or_default() += 1;
+                }
+                let mut rows = match op {
+                    SetOp::Union => {
+                        t1.values.extend(t2.values);
+                        distinct_rows(std::mem::take(&mut t1.values))
+                    }
+                    SetOp::Intersect | SetOp::Except => {
+                        let mut seen: HashMap<_, usize> = HashMap::new();
+                        let mut rows = Vec::new();
+                        for row in std::mem::take(&mut t1.values) {
+                            let key = row_key(&row);
+                            let count2 = counts2.get(&key).copied().unwrap_or(0);
+                            let copy = seen.entry(key).or_default();
+                            let keep = match (op, all) {
+                                (SetOp::Intersect, true) => *copy < count2,
+                                (SetOp::Intersect, false) => *copy == 0 && count2 > 0,
+                                (SetOp::Except, true) => *copy >= count2,
+                                (SetOp::Except, false) => *copy == 0 && count2 == 0,
+                                (SetOp::Union, _) => unreachable!("UNION is handled above"),
+                            };
+                            *copy += 1;
+                            if keep {
+                                rows.push(row);
+                            }
+                        }
+                        rows
+                    }
+                };
+                rows.sort_by(|a, b| sort_cmp_rows(a, b));
+                t1.values = rows;
+                Ok(t1)
+            }
+        }
+    }
+}
+
+/// [join(kind, t1, t2, pred, abstract_rows)] returns the rows of the join of [kind] of [t1]
+/// and [t2] on [pred], in the order SQLite produces them: the rows of [t2] matching each row
+/// of [t1] in turn, padded if a left join finds none, followed by the padded rows of [t2]
+/// that matched nothing in a right join. Abstract rows are the whole cross product followed
+/// by every padded row.
+fn join(
+    kind: JoinKind,
+    t1: &ConcTable,
+    t2: &ConcTable,
+    pred: &Pred,
+    abstract_rows: bool,
+) -> Vec<Vec<Value>> {
+    let pad1 = vec![Value::Null; t1.columns.len()];
+    let pad2 = vec![Value::Null; t2.columns.len()];
+    let concat = |r1: &[Value], r2: &[Value]| r1.iter().chain(r2.iter()).cloned().collect();
+    let keeps_left = matches!(kind, JoinKind::Left | JoinKind::Full);
+    let keeps_right = matches!(kind, JoinKind::Right | JoinKind::Full);
+    let mut rows = Vec::new();
+    let mut matched2 = vec![false; t2.values.len()];
+    for r1 in t1.values.iter() {
+        let mut matched = false;
+        for (j, r2) in t2.values.iter().enumerate() {
+            let row: Vec<Value> = concat(r1, r2);
+            if pred.eval(&row) == Some(true) {
+                matched = true;
+                matched2[j] = true;
+                rows.push(row);
+            }
+        }
+        if keeps_left && !matched && !abstract_rows {
+            rows.push(concat(r1, &pad2));
+        }
+    }
+    if abstract_rows {
+        matched2.fill(false);
+        if keeps_left {
+            rows.extend(t1.values.iter().map(|r1| concat(r1, &pad2)));
+        }
+    }
+    if keeps_right {
+        for (r2, matched) in t2.values.iter().zip(matched2) {
+            if !matched {
+                rows.push(concat(&pad1, r2));
+            }
+        }
+    }
+    rows
+}
+
+impl Evaluator for NativeEvaluator {
+    fn eval(&self, query: &AST<PredNode>) -> Result<ConcTable, EvalError> {
+        self.eval_query(query, false)
+    }
+
+    fn eval_abstract(&self, query: &AST<()>) -> Result<ConcTable, EvalError> {
+        let query = query
+            .with_predicates(&vec![PredNode::True; query.num_holes()])
+            .expect("expected hole count to match");
+        Ok(self
+            .eval_query(&query, true)
+            .unwrap_or_else(|_| empty_table(&query)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bottomup::{generate_abstract_queries, generate_subqueries};
+    use crate::sql::{create_table, SqliteEvaluator};
+    use crate::stop::Stopper;
+
+    fn example(ordered: bool) -> Example {
+        let input = vec![
+            ConcTable {
+                name: String::from("t1"),
+                columns: vec![String::from("a"), String::from("b")],
+                values: vec![
+                    vec![1.into(), 2.into()],
+                    vec![3.into(), Value::Null],
+                    vec![1.into(), 2.into()],
+                    vec![2.into(), 5.into()],
+                ],
+            },
+            ConcTable {
+                name: String::from("t2"),
+                columns: vec![String::from("a"), String::from("c")],
+                values: vec![
+                    vec![1.into(), "x".into()],
+                    vec![Value::Null, "y".into()],
+                    vec![3.into(), Value::Real(2.5)],
+                ],
+            },
+        ];
+        let output = ConcTable {
+            name: String::new(),
+            columns: vec![String::from("a")],
+            values: vec![vec![1.into()]],
+        };
+        Example {
+            input,
+            output,
+            ordered,
+            match_mode: MatchMode::Superset,
+            column_match: ColumnMatch::ByName,
+        }
+    }
+
+    #[test]
+    fn test_native_eval_abstract() {
+        for ordered in [false, true] {
+            let example = example(ordered);
+            let sqlite = SqliteEvaluator::new(&example.input).unwrap();
+            let native = NativeEvaluator::new(&example.input);
+            for q in
+                generate_abstract_queries(example.clone(), 1, &sqlite, &Stopper::new()).unwrap()
+            {
+                let expected = sqlite.eval_abstract(&q).unwrap();
+                let actual = native.eval_abstract(&q).unwrap();
+                assert_eq!(actual.columns, expected.columns, "{:?}", q);
+                assert_eq!(actual.values, expected.values, "{:?}", q);
+            }
+        }
+    }
+
+    #[test]
+    fn test_native_eval() {
+        let example = example(true);
+        let sqlite = SqliteEvaluator::new(&example.input).unwrap();
+        let native = NativeEvaluator::new(&example.input);
+        let subqueries = generate_subqueries(&example, &sqlite, &Stopper::new()).unwrap();
+        for q in generate_abstract_queries(example.clone(), 1, &sqlite, &Stopper::new()).unwrap() {
+            let assignments = crate::bvdfs::bvdfs(
+                &q,
+                &[2],
+                &subqueries,
+                1,
+                &mut HashMap::new(),
+                &sqlite,
+                &Stopper::new(),
+            )
+            .unwrap();
+            for (_, preds) in assignments.into_iter().take(20) {
+                let preds: Vec<_> = preds.into_iter().collect();
+                let q = q.with_predicates(&preds).unwrap();
+                let mut expected = sqlite.eval(&q).unwrap();
+                let mut actual = native.eval(&q).unwrap();
+                let sql = crate::sql::create_sql_query(&q);
+                assert_eq!(actual.columns, expected.columns, "{}", sql);
+                // SQLite's planner can reorder joins, so only sorted queries have an order.
+                if !matches!(q, AST::OrderBy { .. } | AST::Limit { .. }) {
+                    expected.values.sort();
+                    actual.values.sort();
+                }
+                assert_eq!(actual.values, expected.values, "{}", sql);
+            }
+        }
+    }
+
+    #[test]
+    fn test_native_eval_sql() {
+        let example = example(false);
+        let conn = create_table(&example.input).unwrap();
+        let native = NativeEvaluator::new(&example.input);
+        let queries = [
+            "SELECT * FROM (SELECT * FROM t1 JOIN t2 ON t1.a = t2.a) AS j",
+            "SELECT * FROM (SELECT * FROM (SELECT * FROM t1, t1 AS u) AS v, t1) AS j",
+            "SELECT b, COUNT(a), SUM(a), AVG(a) FROM t1 GROUP BY b",
+            "SELECT a, MIN(c), MAX(c), SUM(c) FROM t2 GROUP BY a HAVING MAX(c) > 0",
+            "SELECT COUNT(b), SUM(b), MIN(b) FROM (SELECT * FROM t1 WHERE a > 5) AS e",
+            "SELECT a FROM t1 UNION SELECT a FROM t2",
+            "SELECT a FROM t1 INTERSECT ALL SELECT a FROM t1",
+            "SELECT * FROM t1 EXCEPT ALL SELECT * FROM (SELECT DISTINCT * FROM t1) AS d",
+            "SELECT * FROM t1 RIGHT JOIN t2 ON t1.a = t2.a",
+            "SELECT * FROM t1 FULL JOIN t2 ON t1.a < t2.a AND t2.c > 1",
+            "SELECT a, c % 2, c / 2 AS half FROM t2 WHERE c <> 1 OR NOT a = 1",
+            "SELECT * FROM t1 WHERE a IN (SELECT a FROM t2) ORDER BY b DESC, a LIMIT 3",
+            "SELECT * FROM t1 WHERE NOT a IN (SELECT a FROM t2)",
+        ];
+        for sql in queries {
+            let q = crate::parse::parse_sql_query(sql, &conn).unwrap();
+            let mut expected = crate::sql::eval(&q, &conn).unwrap();
+            let mut actual = native.eval(&q).unwrap();
+            assert_eq!(actual.columns, expected.columns, "{}", sql);
+            if !matches!(q, AST::OrderBy { .. } | AST::Limit { .. }) {
+                expected.values.sort();
+                actual.values.sort();
+            }
+            assert_eq!(actual.values, expected.values, "{}", sql);
+        }
+    }
+
+    #[test]
+    fn test_compile_pred() {
+        let field = |table: &str| Field {
+            table: String::from(table),
+            name: String::from("a"),
+        };
+        let aggregate = |table: &str| AggExpr {
+            func: AggFunc::Count,
+            field: field(table),
+        };
+        let count = |table: &str| ExprNode::Aggregate(aggregate(table));
+        let fields = [field("t1"), field("t2")];
+        let aggregates = [aggregate("t1"), aggregate("t2")];
+        let row = [1.into(), 2.into(), 10.into(), 20.into()];
+        let eval = |p: &PredNode| compile_pred(p, &fields, &aggregates).map(|p| p.eval(&row));
+
+        // Columns with the same name from different tables, or aggregates whose columns have
+        // the same name, are told apart.
+        let lt = PredNode::Lt {
+            left: ExprNode::Field(field("t1")),
+            right: ExprNode::Field(field("t2")),
+        };
+        assert_eq!(eval(&lt), Some(Some(true)));
+        let lt = PredNode::Lt {
+            left: count("t2"),
+            right: count("t1"),
+        };
+        assert_eq!(eval(&lt), Some(Some(false)));
+        let eq = PredNode::Eq {
+            left: ExprNode::Mul {
+                left: Box::new(ExprNode::Field(field("t1"))),
+                right: Box::new(ExprNode::Int { value: 10 }),
+            },
+            right: count("t1"),
+        };
+        assert_eq!(eval(&eq), Some(Some(true)));
+
+        let missing = PredNode::Not {
+            pred: Box::new(PredNode::Eq {
+                left: ExprNode::Field(field("t3")),
+                right: ExprNode::Int { value: 1 },
+            }),
+        };
+        assert_eq!(eval(&missing), None);
+        assert!(compile_pred(&lt, &fields, &[]).is_none());
+    }
+}
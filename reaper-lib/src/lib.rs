@@ -1,14 +1,18 @@
 use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
 
 use bitvec::prelude as bv;
 use enum_predicates::enum_and_group_predicates;
-use sql::eval_abstract;
+use rayon::prelude::*;
 use thiserror::Error;
 
 pub mod bottomup;
 pub mod bvdfs;
 pub mod enum_predicates;
+pub mod eval;
+pub mod parse;
 pub mod sql;
+pub mod stop;
 pub mod stun;
 pub mod types;
 
@@ -23,55 +27,258 @@ pub enum SynthesisError {
     Enumeration(#[from] enum_predicates::PredicateEnumerationError),
     #[error("failed during BVDFS to find target bitvector")]
     BVDFS(#[from] bvdfs::BVDFSError),
-    #[error("failed during database query")]
-    Database(#[from] rusqlite::Error),
+    #[error("failed to evaluate a query")]
+    Evaluation(#[from] eval::EvalError),
     #[error("failed to find a satisfying query")]
     NoQueriesFound,
+    /// Synthesis ran past its deadline. Queries are only built once every predicate has been
+    /// enumerated, so [partial] holds those validated before it stopped, and is empty if it
+    /// stopped while predicates were still being enumerated.
+    #[error("ran past the deadline after finding {} queries", partial.len())]
+    TimedOut {
+        partial: Vec<types::AST<types::PredNode>>,
+    },
+    /// Synthesis was cancelled, with [partial] like that of [SynthesisError::TimedOut].
+    #[error("cancelled after finding {} queries", partial.len())]
+    Cancelled {
+        partial: Vec<types::AST<types::PredNode>>,
+    },
+}
+
+impl SynthesisError {
+    /// [SynthesisError::stopped(stopped, partial)] is the error for synthesis that stopped
+    /// because of [stopped], after finding the queries [partial].
+    fn stopped(stopped: stop::Stopped, partial: Vec<types::AST<types::PredNode>>) -> Self {
+        match stopped {
+            stop::Stopped::TimedOut => SynthesisError::TimedOut { partial },
+            stop::Stopped::Cancelled => SynthesisError::Cancelled { partial },
+        }
+    }
 }
 
 impl types::ConcTable {
-    fn subset_bitvec(&self, other: &Self) -> bv::BitVec {
-        let other_rows = other.values.iter().collect::<HashSet<_>>();
+    /// [t.target_bitvec(other, mode)] returns the rows of [t] to keep to produce [other]
+    /// under [mode]. Under bag semantics, only as many copies of a row are kept as appear
+    /// in [other]; otherwise every copy of a row in [other] is kept.
+    fn target_bitvec(&self, other: &Self, mode: types::MatchMode) -> bv::BitVec {
         let mut v = bv::bitvec![0; self.values.len()];
-        for (mut b, row) in v.iter_mut().zip(self.values.iter()) {
-            *b = other_rows.contains(row);
+        match mode {
+            types::MatchMode::Bag => {
+                let mut counts: HashMap<_, usize> = HashMap::new();
+                for row in other.values.iter() {
+                    *counts.entry(row).or_default() += 1;
+                }
+                for (mut b, row) in v.iter_mut().zip(self.values.iter()) {
+                    if let Some(n @ 1..) = counts.get_mut(row) {
+                        *n -= 1;
+                        *b = true;
+                    }
+                }
+            }
+            types::MatchMode::Set | types::MatchMode::Superset => {
+                let other_rows = other.values.iter().collect::<HashSet<_>>();
+                for (mut b, row) in v.iter_mut().zip(self.values.iter()) {
+                    *b = other_rows.contains(row);
+                }
+            }
         }
         v
     }
 }
 
+/// [unordered(q)] strips any ORDER BY and LIMIT from the root of [q], returning the query
+/// beneath them and whether a LIMIT was stripped.
+fn unordered(q: &types::AST<()>) -> (&types::AST<()>, bool) {
+    match q {
+        types::AST::OrderBy { table, .. } => unordered(table),
+        types::AST::Limit { table, .. } => (unordered(table).0, true),
+        _ => (q, false),
+    }
+}
+
+/// [target_matcher(t, example, limited)] returns whether a bitvector over the rows of [t]
+/// picks out the example's output from them, where [limited] is whether a LIMIT drops rows
+/// after them.
+fn target_matcher<'a>(
+    t: &types::ConcTable,
+    example: &'a types::Example,
+    limited: bool,
+) -> impl Fn(&bv::BitVec) -> bool + 'a {
+    // Each way of lining up the columns with the expected ones picks out different rows.
+    let target_bvs: Vec<_> = bottomup::column_mappings(t, example)
+        .iter()
+        .map(|mapping| {
+            bottomup::project_columns(t, mapping).target_bitvec(&example.output, example.match_mode)
+        })
+        .collect();
+    // A LIMIT can drop rows, so the rows beneath it only need to include the target, as
+    // do the rows of any query if we're only looking for a superset.
+    move |bv| {
+        target_bvs.iter().any(|target_bv| {
+            if limited || example.match_mode == types::MatchMode::Superset {
+                bv.clone() & target_bv == *target_bv
+            } else {
+                bv == target_bv
+            }
+        })
+    }
+}
+
+/// [bvdfs_error(e)] is the error for synthesis that failed because [bvdfs::bvdfs] did.
+fn bvdfs_error(e: bvdfs::BVDFSError) -> SynthesisError {
+    match e.stopped() {
+        Some(stopped) => SynthesisError::stopped(stopped, Vec::new()),
+        None => e.into(),
+    }
+}
+
+/// [filtered_aggregate_assignments(q, example, constants, subqueries, max_predicate_depth,
+/// evaluator, stopper)] returns predicates for the holes of [q] that filter the rows an
+/// aggregate at its root groups, along with a HAVING predicate for the aggregate, that may
+/// pick out the example's output.
+///
+/// Filtering rows before grouping them changes the values of the groups rather than which
+/// of them survive, which bitvectors over the groups of every row can't describe, so
+/// [bvdfs::bvdfs] leaves predicates below an aggregate True. Instead, the rows are grouped
+/// again for each distinct set of them the predicates below can keep, and HAVING predicates
+/// are looked for over those groups.
+fn filtered_aggregate_assignments(
+    q: &types::AST<()>,
+    example: &types::Example,
+    constants: &[isize],
+    subqueries: &[Arc<types::Subquery>],
+    max_predicate_depth: usize,
+    evaluator: &dyn eval::Evaluator,
+    stopper: &stop::Stopper,
+) -> Result<Vec<im::Vector<types::PredNode>>, SynthesisError> {
+    let (inner, limited) = unordered(q);
+    let table = match inner {
+        types::AST::Aggregate { table, .. } if table.num_holes() > 0 => table,
+        _ => return Ok(Vec::new()),
+    };
+    let below = bvdfs::bvdfs(
+        table,
+        constants,
+        subqueries,
+        max_predicate_depth,
+        &mut HashMap::new(),
+        evaluator,
+        stopper,
+    )
+    .map_err(bvdfs_error)?;
+    // Keeping every row is the same as leaving the predicates below True.
+    let mut seen = HashSet::new();
+    let below: Vec<_> = below
+        .into_iter()
+        .filter(|(bv, _preds)| !bv.all() && seen.insert(bv.clone()))
+        .collect();
+    let assignments = below
+        .into_par_iter()
+        .map(|(_bv, below)| {
+            let mut preds = below.clone();
+            preds.push_front(types::PredNode::True);
+            let grouped = inner
+                .with_predicates(&preds.into_iter().collect::<Vec<_>>())
+                .expect("query substitution failed!");
+            let groups = evaluator.eval(&grouped)?;
+            let matches = target_matcher(&groups, example, limited);
+            let having = enum_predicates::group_predicates(
+                inner,
+                &groups,
+                constants,
+                subqueries,
+                max_predicate_depth,
+                stopper,
+            )
+            .map_err(|stopped| SynthesisError::stopped(stopped, Vec::new()))?;
+            Ok(having
+                .into_iter()
+                .filter(|(bv, _having)| matches(bv))
+                .map(|(_bv, having)| {
+                    let mut preds = below.clone();
+                    preds.push_front(having[0].clone());
+                    preds
+                })
+                .collect::<Vec<_>>())
+        })
+        .collect::<Result<Vec<_>, SynthesisError>>()?;
+    Ok(assignments.into_iter().flatten().collect())
+}
+
 pub fn synthesize(
     q: &types::AST<()>,
-    target: &types::ConcTable,
+    example: &types::Example,
     constants: &[isize],
+    subqueries: &[Arc<types::Subquery>],
     max_predicate_depth: usize,
-    conn: &rusqlite::Connection,
+    evaluator: &dyn eval::Evaluator,
+    stopper: &stop::Stopper,
 ) -> Result<Vec<types::AST<types::PredNode>>, SynthesisError> {
-    let bitvectors = bvdfs::bvdfs(q, constants, max_predicate_depth, &mut HashMap::new(), conn)?;
+    // ORDER BY and LIMIT don't have holes, but they do move rows around, so we look for
+    // predicates on the query beneath them. Since that query has the same holes in the same
+    // order, the predicates can be substituted into the whole query afterwards.
+    let (inner, limited) = unordered(q);
+    let bitvectors = bvdfs::bvdfs(
+        inner,
+        constants,
+        subqueries,
+        max_predicate_depth,
+        &mut HashMap::new(),
+        evaluator,
+        stopper,
+    )
+    .map_err(bvdfs_error)?;
     // TODO: make the return type of bvdfs less stupid. probably should be a hashmap from bitvecs to all predicate vectors that
     // produce that value. Without that change, it's not really better to make a HashMap out of these since we could just iterate.
-    let t = eval_abstract(q, conn)?;
-    let target_bv = t.subset_bitvec(target);
-    let mut queries: Vec<_> = bitvectors
+    let t = evaluator.eval_abstract(inner)?;
+    let matches = target_matcher(&t, example, limited);
+    let mut assignments: Vec<_> = bitvectors
         .into_iter()
-        .filter_map(|(bv, preds)| {
-            if bv == target_bv {
-                let preds = preds.into_iter().collect::<Vec<_>>();
-                // TODO: with_predicates should probably accept an im::Vector instead.
-                let q = q
-                    .with_predicates(&preds)
-                    .expect("query substitution failed!");
-                Some(q)
-            } else {
-                None
+        .filter(|(bv, _preds)| matches(bv))
+        .map(|(_bv, preds)| preds)
+        .collect();
+    assignments.extend(filtered_aggregate_assignments(
+        q,
+        example,
+        constants,
+        subqueries,
+        max_predicate_depth,
+        evaluator,
+        stopper,
+    )?);
+    stopper
+        .check()
+        .map_err(|stopped| SynthesisError::stopped(stopped, Vec::new()))?;
+    let validated = assignments
+        .into_par_iter()
+        .map(|preds| {
+            // Once stopped, the queries found so far are returned instead.
+            if stopper.is_stopped() {
+                return Ok(None);
             }
+            let preds = preds.into_iter().collect::<Vec<_>>();
+            // TODO: with_predicates should probably accept an im::Vector instead.
+            let q = q
+                .with_predicates(&preds)
+                .expect("query substitution failed!");
+            // Bitvectors don't say anything about the order of rows or about expected rows
+            // that the abstract query can't produce at all, so check the result concretely.
+            let valid = bottomup::is_valid(&evaluator.eval(&q)?, example);
+            Ok(Some(valid.then_some(q)))
         })
-        .collect();
-    if queries.is_empty() {
-        Err(SynthesisError::NoQueriesFound)
-    } else {
-        queries.sort_by_key(query_rank);
-        Ok(queries)
+        .collect::<Result<Vec<_>, eval::EvalError>>()?;
+    // Stopping after every query has been validated doesn't lose any of them.
+    let finished = validated.iter().all(Option::is_some);
+    let mut queries = validated
+        .into_iter()
+        .flatten()
+        .flatten()
+        .collect::<Vec<_>>();
+    queries.sort_by_key(query_rank);
+    match stopper.check() {
+        Err(stopped) if !finished => Err(SynthesisError::stopped(stopped, queries)),
+        _ if queries.is_empty() => Err(SynthesisError::NoQueriesFound),
+        _ => Ok(queries),
     }
 }
 
@@ -79,9 +286,957 @@ pub fn synthesize(
 mod tests {
     use super::*;
 
+    use eval::Evaluator;
+    use std::time::Duration;
+    use types::*;
+
+    /// [bag_example(input, output)] is the example of [output] from [input], matched as an
+    /// unordered bag of rows, by column name.
+    fn bag_example(input: Vec<ConcTable>, output: ConcTable) -> Example {
+        Example {
+            input,
+            output,
+            ordered: false,
+            match_mode: MatchMode::Bag,
+            column_match: ColumnMatch::ByName,
+        }
+    }
+
+    /// [synthesize_example(example, constants)] returns every query found for [example] from
+    /// the abstract queries of depth 1.
+    fn synthesize_example(example: &Example, constants: &[isize]) -> Vec<AST<PredNode>> {
+        let sqlite = sql::SqliteEvaluator::new(&example.input).unwrap();
+        bottomup::generate_abstract_queries(example.clone(), 1, &sqlite, &stop::Stopper::new())
+            .unwrap()
+            .iter()
+            .filter_map(|q| {
+                synthesize(
+                    q,
+                    example,
+                    constants,
+                    &[],
+                    1,
+                    &sqlite,
+                    &stop::Stopper::new(),
+                )
+                .ok()
+            })
+            .flatten()
+            .collect()
+    }
+
     #[test]
     fn it_works() {
         let result = 4;
         assert_eq!(result, 4);
     }
+
+    #[test]
+    fn test_synthesize_having() {
+        let input = vec![ConcTable {
+            name: String::from("employees"),
+            columns: vec![String::from("id"), String::from("dept")],
+            values: vec![
+                vec![1.into(), "eng".into()],
+                vec![2.into(), "eng".into()],
+                vec![3.into(), "eng".into()],
+                vec![4.into(), "ops".into()],
+            ],
+        }];
+        let output = ConcTable {
+            name: String::new(),
+            columns: vec![String::from("dept"), String::from("COUNT(id)")],
+            values: vec![vec!["eng".into(), 3.into()]],
+        };
+        let count = AggExpr {
+            func: AggFunc::Count,
+            field: Field {
+                name: String::from("id"),
+                table: String::from("employees"),
+            },
+        };
+        let query = AST::Aggregate {
+            group_by: Arc::from(vec![Field {
+                name: String::from("dept"),
+                table: String::from("employees"),
+            }]),
+            aggregates: Arc::from(vec![count.clone()]),
+            table: Box::new(AST::Table {
+                name: String::from("employees"),
+                columns: input[0].columns.clone(),
+                alias: String::from("employees"),
+            }),
+            having: (),
+            alias: String::from("q1"),
+        };
+
+        let sqlite = sql::SqliteEvaluator::new(&input).unwrap();
+        let example = bag_example(input, output);
+        let queries = synthesize(
+            &query,
+            &example,
+            &[1],
+            &[],
+            1,
+            &sqlite,
+            &stop::Stopper::new(),
+        )
+        .unwrap();
+        let best = queries.first().unwrap();
+        assert_eq!(sqlite.eval(best).unwrap().values, example.output.values);
+        assert!(sql::create_sql_query(best).contains("HAVING"));
+    }
+
+    #[test]
+    fn test_synthesize_where_under_group_by() {
+        let input = vec![ConcTable {
+            name: String::from("employees"),
+            columns: vec![String::from("dept"), String::from("active")],
+            values: vec![
+                vec!["eng".into(), 1.into()],
+                vec!["eng".into(), 0.into()],
+                vec!["ops".into(), 1.into()],
+            ],
+        }];
+        // The number of active employees in each department, which no HAVING clause can
+        // pick out of the number of all of them.
+        let output = ConcTable {
+            name: String::new(),
+            columns: vec![String::from("dept"), String::from("COUNT(active)")],
+            values: vec![vec!["eng".into(), 1.into()], vec!["ops".into(), 1.into()]],
+        };
+        let sqlite = sql::SqliteEvaluator::new(&input).unwrap();
+        let example = bag_example(input, output);
+        let queries =
+            bottomup::generate_abstract_queries(example.clone(), 2, &sqlite, &stop::Stopper::new())
+                .unwrap();
+        // The aggregate over the rows a WHERE clause keeps has to survive being checked
+        // against the example before its predicates are found.
+        let best = queries
+            .iter()
+            .filter(|q| match q {
+                AST::Aggregate { table, .. } => matches!(**table, AST::Select { .. }),
+                _ => false,
+            })
+            .find_map(|q| {
+                synthesize(q, &example, &[1], &[], 1, &sqlite, &stop::Stopper::new())
+                    .ok()
+                    .and_then(|found| found.into_iter().next())
+            })
+            .unwrap();
+        let mut rows = sqlite.eval(&best).unwrap().values;
+        rows.sort();
+        assert_eq!(rows, example.output.values);
+        assert!(sql::create_sql_query(&best).contains("WHERE"));
+    }
+
+    #[test]
+    fn test_synthesize_native() {
+        let input = vec![ConcTable {
+            name: String::from("t"),
+            columns: vec![String::from("a"), String::from("b")],
+            values: vec![
+                vec![1.into(), 2.into()],
+                vec![2.into(), 2.into()],
+                vec![3.into(), 1.into()],
+            ],
+        }];
+        let output = ConcTable {
+            name: String::new(),
+            columns: vec![String::from("a")],
+            values: vec![vec![1.into()], vec![2.into()]],
+        };
+        let example = bag_example(input, output);
+        let sqlite = sql::SqliteEvaluator::new(&example.input).unwrap();
+        let native = eval::NativeEvaluator::new(&example.input);
+        // Both evaluators find the same queries, though not in the same order, since that
+        // depends on the order of hash maps.
+        let found = |evaluator: &dyn eval::Evaluator| {
+            let mut queries: Vec<_> = bottomup::generate_abstract_queries(
+                example.clone(),
+                1,
+                evaluator,
+                &stop::Stopper::new(),
+            )
+            .unwrap()
+            .iter()
+            .filter_map(|q| {
+                synthesize(q, &example, &[2], &[], 1, evaluator, &stop::Stopper::new()).ok()
+            })
+            .flatten()
+            .map(|q| sql::create_sql_query(&q))
+            .collect();
+            queries.sort();
+            queries
+        };
+        let queries = found(&native);
+        assert!(!queries.is_empty());
+        assert_eq!(queries, found(&sqlite));
+    }
+
+    #[test]
+    fn test_synthesize_stopped() {
+        let input = vec![ConcTable {
+            name: String::from("t"),
+            columns: vec![String::from("a")],
+            values: vec![vec![1.into()], vec![2.into()]],
+        }];
+        let output = ConcTable {
+            name: String::new(),
+            columns: vec![String::from("a")],
+            values: vec![vec![1.into()]],
+        };
+        let query = AST::Select {
+            fields: None,
+            distinct: false,
+            table: Box::new(AST::Table {
+                name: String::from("t"),
+                columns: input[0].columns.clone(),
+                alias: String::from("t"),
+            }),
+            pred: (),
+            alias: String::from("q1"),
+        };
+        let example = bag_example(input, output);
+        let sqlite = sql::SqliteEvaluator::new(&example.input).unwrap();
+
+        let cancelled = stop::Stopper::new();
+        cancelled.cancel();
+        assert!(matches!(
+            bottomup::generate_abstract_queries(example.clone(), 1, &sqlite, &cancelled),
+            Err(stop::Stopped::Cancelled)
+        ));
+        assert!(matches!(
+            bottomup::generate_subqueries(&example, &sqlite, &cancelled),
+            Err(stop::Stopped::Cancelled)
+        ));
+        // Stopping before any query has been built leaves none to return.
+        assert!(matches!(
+            synthesize(&query, &example, &[1], &[], 1, &sqlite, &cancelled),
+            Err(SynthesisError::Cancelled { partial }) if partial.is_empty()
+        ));
+        let timed_out = stop::Stopper::with_timeout(Duration::ZERO);
+        assert!(matches!(
+            synthesize(&query, &example, &[1], &[], 1, &sqlite, &timed_out),
+            Err(SynthesisError::TimedOut { partial }) if partial.is_empty()
+        ));
+
+        // Queries are only evaluated concretely to validate them, so this cancels synthesis
+        // partway through validation, once it's found a query.
+        struct CancelOnMatch<'a> {
+            inner: &'a dyn Evaluator,
+            example: &'a Example,
+            stopper: &'a stop::Stopper,
+        }
+        impl Evaluator for CancelOnMatch<'_> {
+            fn eval(&self, query: &AST<PredNode>) -> Result<ConcTable, eval::EvalError> {
+                let result = self.inner.eval(query)?;
+                if bottomup::is_valid(&result, self.example) {
+                    self.stopper.cancel();
+                }
+                Ok(result)
+            }
+
+            fn eval_abstract(&self, query: &AST<()>) -> Result<ConcTable, eval::EvalError> {
+                self.inner.eval_abstract(query)
+            }
+        }
+
+        // Only one query is left to validate, so stopping once it has been loses nothing.
+        let stopper = stop::Stopper::new();
+        let evaluator = CancelOnMatch {
+            inner: &sqlite,
+            example: &example,
+            stopper: &stopper,
+        };
+        assert!(synthesize(&query, &example, &[1], &[], 1, &evaluator, &stopper).is_ok());
+
+        // Any superset of the output matches, so there are many more queries to validate.
+        let example = Example {
+            input: vec![ConcTable {
+                name: String::from("t"),
+                columns: vec![String::from("a")],
+                values: (1..=8).map(|n| vec![n.into()]).collect(),
+            }],
+            match_mode: MatchMode::Superset,
+            ..example
+        };
+        let sqlite = sql::SqliteEvaluator::new(&example.input).unwrap();
+        let stopper = stop::Stopper::new();
+        let evaluator = CancelOnMatch {
+            inner: &sqlite,
+            example: &example,
+            stopper: &stopper,
+        };
+        let constants: Vec<_> = (1..=8).collect();
+        match synthesize(&query, &example, &constants, &[], 1, &evaluator, &stopper) {
+            Err(SynthesisError::Cancelled { partial }) => {
+                assert!(!partial.is_empty());
+                for q in partial.iter() {
+                    assert!(bottomup::is_valid(&sqlite.eval(q).unwrap(), &example));
+                }
+            }
+            result => panic!("expected cancellation, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_synthesize_match_modes() {
+        let input = vec![ConcTable {
+            name: String::from("t"),
+            columns: vec![String::from("a")],
+            values: vec![vec![1.into()], vec![1.into()], vec![2.into()]],
+        }];
+        let output = ConcTable {
+            name: String::new(),
+            columns: vec![String::from("a")],
+            values: vec![vec![1.into()]],
+        };
+        let query = AST::Select {
+            fields: None,
+            distinct: false,
+            table: Box::new(AST::Table {
+                name: String::from("t"),
+                columns: vec![String::from("a")],
+                alias: String::from("t"),
+            }),
+            pred: (),
+            alias: String::from("q1"),
+        };
+        let mut example = bag_example(input, output);
+
+        // No predicate keeps only one of the two identical rows...
+        let sqlite = sql::SqliteEvaluator::new(&example.input).unwrap();
+        assert!(matches!(
+            synthesize(
+                &query,
+                &example,
+                &[1],
+                &[],
+                1,
+                &sqlite,
+                &stop::Stopper::new()
+            ),
+            Err(SynthesisError::NoQueriesFound)
+        ));
+
+        // ...but that's fine if we don't care about duplicates.
+        example.match_mode = MatchMode::Set;
+        let queries = synthesize(
+            &query,
+            &example,
+            &[1],
+            &[],
+            1,
+            &sqlite,
+            &stop::Stopper::new(),
+        )
+        .unwrap();
+        assert!(!queries.is_empty());
+    }
+
+    #[test]
+    fn test_synthesize_where_bag() {
+        let input = vec![ConcTable {
+            name: String::from("t"),
+            columns: vec![String::from("a"), String::from("b")],
+            values: vec![
+                vec![1.into(), 1.into()],
+                vec![2.into(), 2.into()],
+                vec![3.into(), 3.into()],
+            ],
+        }];
+        let output = ConcTable {
+            name: String::new(),
+            columns: vec![String::from("a")],
+            values: vec![vec![2.into()], vec![3.into()]],
+        };
+        let example = bag_example(input, output);
+
+        // No abstract query matches the example exactly, so the one that does once it's
+        // filtered has to survive until its predicates are synthesized.
+        let sqlite = sql::SqliteEvaluator::new(&example.input).unwrap();
+        let found = synthesize_example(&example, &[1]);
+        for q in found.iter() {
+            let mut rows = sqlite.eval(q).unwrap().values;
+            rows.sort();
+            assert_eq!(rows, example.output.values);
+        }
+        assert!(found
+            .iter()
+            .any(|q| sql::create_sql_query(q).contains("WHERE")));
+    }
+
+    #[test]
+    fn test_synthesize_top_n() {
+        let input = vec![ConcTable {
+            name: String::from("students"),
+            columns: vec![String::from("id"), String::from("grade")],
+            values: vec![
+                vec![1.into(), 80.into()],
+                vec![2.into(), 95.into()],
+                vec![3.into(), 70.into()],
+                vec![4.into(), 90.into()],
+            ],
+        }];
+        let output = ConcTable {
+            name: String::new(),
+            columns: vec![String::from("id"), String::from("grade")],
+            values: vec![vec![2.into(), 95.into()], vec![4.into(), 90.into()]],
+        };
+        let example = Example {
+            ordered: true,
+            ..bag_example(input, output)
+        };
+
+        let sqlite = sql::SqliteEvaluator::new(&example.input).unwrap();
+        let found = synthesize_example(&example, &[]);
+        assert!(!found.is_empty());
+        for q in found.iter() {
+            assert_eq!(sqlite.eval(q).unwrap().values, example.output.values);
+            // Derived tables don't keep their order, so the rows have to be limited by the
+            // same statement that sorts them.
+            for dialect in [Dialect::Sqlite, Dialect::Postgres, Dialect::MySql] {
+                let sql = sql::create_dialect_sql_query(q, dialect);
+                if let Some(start) = sql.find(" ORDER BY ") {
+                    let order_by = &sql[start..];
+                    assert!(!order_by.contains(')'), "{}", sql);
+                    assert!(order_by.ends_with(" LIMIT 2"), "{}", sql);
+                }
+            }
+        }
+        assert!(found
+            .iter()
+            .any(|q| sql::create_sql_query(q).contains(" ORDER BY ")));
+    }
+
+    #[test]
+    fn test_synthesize_left_join() {
+        let input = vec![
+            ConcTable {
+                name: String::from("customers"),
+                columns: vec![String::from("id")],
+                values: vec![vec![1.into()], vec![2.into()]],
+            },
+            ConcTable {
+                name: String::from("orders"),
+                columns: vec![String::from("customer")],
+                values: vec![vec![1.into()], vec![1.into()], vec![3.into()]],
+            },
+        ];
+        let output = ConcTable {
+            name: String::new(),
+            columns: vec![String::from("id"), String::from("customer")],
+            values: vec![
+                vec![1.into(), 1.into()],
+                vec![1.into(), 1.into()],
+                vec![2.into(), Value::Null],
+            ],
+        };
+        let table = |name: &str, column: &str| AST::Table {
+            name: String::from(name),
+            columns: vec![String::from(column)],
+            alias: String::from(name),
+        };
+        let query = AST::Join {
+            fields: None,
+            distinct: false,
+            kind: JoinKind::Left,
+            table1: Box::new(table("customers", "id")),
+            table2: Box::new(table("orders", "customer")),
+            pred: (),
+            alias: String::from("q1"),
+        };
+
+        let sqlite = sql::SqliteEvaluator::new(&input).unwrap();
+        let example = bag_example(input, output);
+        let queries = synthesize(
+            &query,
+            &example,
+            &[],
+            &[],
+            1,
+            &sqlite,
+            &stop::Stopper::new(),
+        )
+        .unwrap();
+        let best = queries.first().unwrap();
+        let mut rows = sqlite.eval(best).unwrap().values;
+        rows.sort();
+        assert_eq!(rows, example.output.values);
+    }
+
+    #[test]
+    fn test_synthesize_set_ops() {
+        let input = vec![
+            ConcTable {
+                name: String::from("a"),
+                columns: vec![String::from("id")],
+                values: vec![
+                    vec![1.into()],
+                    vec![1.into()],
+                    vec![2.into()],
+                    vec![3.into()],
+                ],
+            },
+            ConcTable {
+                name: String::from("b"),
+                columns: vec![String::from("id")],
+                values: vec![vec![1.into()], vec![3.into()], vec![3.into()]],
+            },
+        ];
+        let select = |name: &str| AST::Select {
+            fields: None,
+            distinct: false,
+            table: Box::new(AST::Table {
+                name: String::from(name),
+                columns: vec![String::from("id")],
+                alias: String::from(name),
+            }),
+            pred: (),
+            alias: String::from(name),
+        };
+        let sqlite = sql::SqliteEvaluator::new(&input).unwrap();
+
+        for op in SetOp::ALL {
+            for all in [false, true] {
+                let query = AST::SetOp {
+                    op,
+                    all,
+                    table1: Box::new(select("a")),
+                    table2: Box::new(select("b")),
+                    alias: String::from("q1"),
+                };
+                // Whatever SQLite makes of the query without predicates should be found again.
+                let output = sqlite
+                    .eval(
+                        &query
+                            .with_predicates(&[PredNode::True, PredNode::True])
+                            .unwrap(),
+                    )
+                    .unwrap();
+                let example = bag_example(input.clone(), output);
+                let queries = synthesize(
+                    &query,
+                    &example,
+                    &[],
+                    &[],
+                    1,
+                    &sqlite,
+                    &stop::Stopper::new(),
+                );
+                assert!(queries.is_ok(), "nothing found for {} (ALL: {})", op, all);
+            }
+        }
+    }
+
+    #[test]
+    fn test_synthesize_distinct() {
+        let input = vec![ConcTable {
+            name: String::from("employees"),
+            columns: vec![String::from("id"), String::from("dept")],
+            values: vec![
+                vec![1.into(), "eng".into()],
+                vec![2.into(), "eng".into()],
+                vec![3.into(), "ops".into()],
+                vec![4.into(), "hr".into()],
+            ],
+        }];
+        let output = ConcTable {
+            name: String::new(),
+            columns: vec![String::from("dept")],
+            values: vec![vec!["eng".into()], vec!["ops".into()]],
+        };
+        let example = bag_example(input, output);
+
+        let sqlite = sql::SqliteEvaluator::new(&example.input).unwrap();
+        let found = synthesize_example(&example, &[3]);
+        assert!(!found.is_empty());
+        for q in found.iter() {
+            assert!(sql::create_sql_query(q).contains("DISTINCT"));
+            let mut rows = sqlite.eval(q).unwrap().values;
+            rows.sort();
+            assert_eq!(rows, example.output.values);
+        }
+    }
+
+    #[test]
+    fn test_synthesize_in_subquery() {
+        let input = vec![
+            ConcTable {
+                name: String::from("students"),
+                columns: vec![String::from("id"), String::from("name")],
+                values: vec![
+                    vec![1.into(), "ann".into()],
+                    vec![2.into(), "bob".into()],
+                    vec![3.into(), "cat".into()],
+                ],
+            },
+            ConcTable {
+                name: String::from("honors"),
+                columns: vec![String::from("student")],
+                values: vec![vec![1.into()], vec![3.into()]],
+            },
+        ];
+        let output = ConcTable {
+            name: String::new(),
+            columns: vec![String::from("name")],
+            values: vec![vec!["ann".into()], vec!["cat".into()]],
+        };
+        let query = AST::Select {
+            fields: Some(Arc::from(
+                [Field {
+                    name: String::from("name"),
+                    table: String::from("students"),
+                }]
+                .map(SelectItem::from),
+            )),
+            distinct: false,
+            table: Box::new(AST::Table {
+                name: String::from("students"),
+                columns: input[0].columns.clone(),
+                alias: String::from("students"),
+            }),
+            pred: (),
+            alias: String::from("q1"),
+        };
+
+        let sqlite = sql::SqliteEvaluator::new(&input).unwrap();
+        let example = bag_example(input, output);
+        // Without constants, nothing picks out the first and last students...
+        assert!(synthesize(
+            &query,
+            &example,
+            &[],
+            &[],
+            1,
+            &sqlite,
+            &stop::Stopper::new()
+        )
+        .is_err());
+
+        // ...but membership in the honors table does.
+        let subqueries =
+            bottomup::generate_subqueries(&example, &sqlite, &stop::Stopper::new()).unwrap();
+        let queries = synthesize(
+            &query,
+            &example,
+            &[],
+            &subqueries,
+            1,
+            &sqlite,
+            &stop::Stopper::new(),
+        )
+        .unwrap();
+        let best = queries.first().unwrap();
+        let mut rows = sqlite.eval(best).unwrap().values;
+        rows.sort();
+        assert_eq!(rows, example.output.values);
+        assert!(sql::create_sql_query(best).contains(" IN "));
+    }
+
+    #[test]
+    fn test_synthesize_correlated_exists() {
+        let input = vec![
+            ConcTable {
+                name: String::from("customers"),
+                columns: vec![String::from("id"), String::from("name")],
+                values: vec![
+                    vec![1.into(), "ann".into()],
+                    vec![2.into(), "bob".into()],
+                    vec![3.into(), "cat".into()],
+                ],
+            },
+            ConcTable {
+                name: String::from("orders"),
+                columns: vec![String::from("id"), String::from("customer")],
+                values: vec![
+                    vec![10.into(), 1.into()],
+                    vec![11.into(), 3.into()],
+                    vec![12.into(), 1.into()],
+                ],
+            },
+        ];
+        // The customers with at least one order.
+        let output = ConcTable {
+            name: String::new(),
+            columns: vec![String::from("name")],
+            values: vec![vec!["ann".into()], vec!["cat".into()]],
+        };
+        let query = AST::Select {
+            fields: Some(Arc::from(
+                [Field {
+                    name: String::from("name"),
+                    table: String::from("customers"),
+                }]
+                .map(SelectItem::from),
+            )),
+            distinct: false,
+            table: Box::new(AST::Table {
+                name: String::from("customers"),
+                columns: input[0].columns.clone(),
+                alias: String::from("customers"),
+            }),
+            pred: (),
+            alias: String::from("q1"),
+        };
+
+        let sqlite = sql::SqliteEvaluator::new(&input).unwrap();
+        let native = eval::NativeEvaluator::new(&input);
+        let example = bag_example(input, output);
+        // Only correlated subqueries, so that it can't be found with IN instead.
+        let subqueries: Vec<_> =
+            bottomup::generate_subqueries(&example, &sqlite, &stop::Stopper::new())
+                .unwrap()
+                .into_iter()
+                .filter(|subquery| subquery.scope.is_some())
+                .collect();
+        let queries = synthesize(
+            &query,
+            &example,
+            &[],
+            &subqueries,
+            1,
+            &sqlite,
+            &stop::Stopper::new(),
+        )
+        .unwrap();
+        for q in queries.iter() {
+            for evaluator in [&sqlite as &dyn eval::Evaluator, &native] {
+                let mut rows = evaluator.eval(q).unwrap().values;
+                rows.sort();
+                assert_eq!(rows, example.output.values, "{}", sql::create_sql_query(q));
+            }
+        }
+        assert!(queries
+            .iter()
+            .any(|q| sql::create_sql_query(q).contains("EXISTS")));
+    }
+
+    #[test]
+    fn test_synthesize_correlated_in() {
+        let input = vec![
+            ConcTable {
+                name: String::from("customers"),
+                columns: vec![
+                    String::from("id"),
+                    String::from("name"),
+                    String::from("city"),
+                ],
+                values: vec![
+                    vec![1.into(), "ann".into(), "bos".into()],
+                    vec![2.into(), "bob".into(), "nyc".into()],
+                    vec![3.into(), "cat".into(), "nyc".into()],
+                ],
+            },
+            ConcTable {
+                name: String::from("orders"),
+                columns: vec![String::from("customer"), String::from("city")],
+                values: vec![
+                    vec![1.into(), "bos".into()],
+                    vec![2.into(), "bos".into()],
+                    vec![3.into(), "nyc".into()],
+                ],
+            },
+        ];
+        // The customers with an order shipped to their own city.
+        let output = ConcTable {
+            name: String::new(),
+            columns: vec![String::from("name")],
+            values: vec![vec!["ann".into()], vec!["cat".into()]],
+        };
+        let query = AST::Select {
+            fields: Some(Arc::from(
+                [Field {
+                    name: String::from("name"),
+                    table: String::from("customers"),
+                }]
+                .map(SelectItem::from),
+            )),
+            distinct: false,
+            table: Box::new(AST::Table {
+                name: String::from("customers"),
+                columns: input[0].columns.clone(),
+                alias: String::from("customers"),
+            }),
+            pred: (),
+            alias: String::from("q1"),
+        };
+
+        let sqlite = sql::SqliteEvaluator::new(&input).unwrap();
+        let native = eval::NativeEvaluator::new(&input);
+        let example = bag_example(input, output);
+        let subqueries: Vec<_> =
+            bottomup::generate_subqueries(&example, &sqlite, &stop::Stopper::new())
+                .unwrap()
+                .into_iter()
+                .filter(|subquery| subquery.scope.is_some())
+                .collect();
+        let queries = synthesize(
+            &query,
+            &example,
+            &[],
+            &subqueries,
+            1,
+            &sqlite,
+            &stop::Stopper::new(),
+        )
+        .unwrap();
+        for q in queries.iter() {
+            for evaluator in [&sqlite as &dyn eval::Evaluator, &native] {
+                let mut rows = evaluator.eval(q).unwrap().values;
+                rows.sort();
+                assert_eq!(rows, example.output.values, "{}", sql::create_sql_query(q));
+            }
+        }
+        // Only correlated subqueries were given, so any IN is one of them.
+        assert!(queries
+            .iter()
+            .any(|q| sql::create_sql_query(q).contains(" IN ")));
+    }
+
+    #[test]
+    fn test_synthesize_computed_columns() {
+        let input = vec![ConcTable {
+            name: String::from("orders"),
+            columns: vec![
+                String::from("id"),
+                String::from("price"),
+                String::from("qty"),
+            ],
+            values: vec![
+                vec![1.into(), 3.into(), 2.into()],
+                vec![2.into(), 5.into(), 1.into()],
+                vec![3.into(), 2.into(), 4.into()],
+            ],
+        }];
+        let output = ConcTable {
+            name: String::new(),
+            columns: vec![String::from("id"), String::from("total")],
+            values: vec![vec![1.into(), 6.into()], vec![3.into(), 8.into()]],
+        };
+        let mut example = bag_example(input, output);
+        let sqlite = sql::SqliteEvaluator::new(&example.input).unwrap();
+
+        let found = synthesize_example(&example, &[4]);
+        assert!(!found.is_empty());
+        for q in found.iter() {
+            assert!(sql::create_sql_query(q).contains(" AS \"total\""));
+            let result = sqlite.eval(q).unwrap();
+            assert_eq!(result.columns, example.output.columns);
+            let mut rows = result.values;
+            rows.sort();
+            assert_eq!(rows, example.output.values);
+        }
+
+        // Without names, the columns are matched by value alone.
+        example.output.columns = vec![String::new(), String::new()];
+        let found = synthesize_example(&example, &[4]);
+        assert!(!found.is_empty());
+        for q in found.iter() {
+            let mut rows = sqlite.eval(q).unwrap().values;
+            rows.sort();
+            assert_eq!(rows, example.output.values);
+        }
+    }
+
+    #[test]
+    fn test_synthesize_by_content() {
+        let input = vec![ConcTable {
+            name: String::from("people"),
+            columns: vec![String::from("name"), String::from("age")],
+            values: vec![
+                vec!["ann".into(), 30.into()],
+                vec!["bob".into(), 17.into()],
+                vec!["cat".into(), 45.into()],
+            ],
+        }];
+        // Pasted with made-up headers, and the columns the other way around.
+        let output = ConcTable {
+            name: String::new(),
+            columns: vec![String::from("Age"), String::from("Person")],
+            values: vec![vec![30.into(), "ann".into()], vec![45.into(), "cat".into()]],
+        };
+        let example = Example {
+            column_match: ColumnMatch::ByContent,
+            ..bag_example(input, output)
+        };
+
+        let sqlite = sql::SqliteEvaluator::new(&example.input).unwrap();
+        let found = synthesize_example(&example, &[18]);
+        assert!(!found.is_empty());
+        for q in found.iter() {
+            let result = sqlite.eval(q).unwrap();
+            let mapping = bottomup::column_mapping(&result, &example).unwrap();
+            let mut rows = bottomup::project_columns(&result, &mapping).values;
+            rows.sort();
+            assert_eq!(rows, example.output.values);
+        }
+    }
+
+    #[test]
+    fn test_synthesize_self_join() {
+        let input = vec![ConcTable {
+            name: String::from("employees"),
+            columns: vec![
+                String::from("id"),
+                String::from("name"),
+                String::from("manager"),
+            ],
+            values: vec![
+                vec![1.into(), "ann".into(), Value::Null],
+                vec![2.into(), "bob".into(), 1.into()],
+                vec![3.into(), "cat".into(), 2.into()],
+            ],
+        }];
+        let output = ConcTable {
+            name: String::new(),
+            columns: vec![String::from("name"), String::from("boss")],
+            values: vec![
+                vec!["bob".into(), "ann".into()],
+                vec!["cat".into(), "bob".into()],
+            ],
+        };
+        let table = |alias: &str| AST::Table {
+            name: String::from("employees"),
+            columns: input[0].columns.clone(),
+            alias: String::from(alias),
+        };
+        let field = |table: &str, name: &str| Field {
+            table: String::from(table),
+            name: String::from(name),
+        };
+        let query = AST::Join {
+            fields: Some(Arc::from(vec![
+                SelectItem::from(field("employees", "name")),
+                SelectItem {
+                    expr: ExprNode::Field(field("managers", "name")),
+                    alias: Some(String::from("boss")),
+                },
+            ])),
+            distinct: false,
+            kind: JoinKind::Inner,
+            table1: Box::new(table("employees")),
+            table2: Box::new(table("managers")),
+            pred: (),
+            alias: String::from("q1"),
+        };
+
+        let sqlite = sql::SqliteEvaluator::new(&input).unwrap();
+        let example = bag_example(input, output);
+        let queries = synthesize(
+            &query,
+            &example,
+            &[],
+            &[],
+            1,
+            &sqlite,
+            &stop::Stopper::new(),
+        )
+        .unwrap();
+        let best = queries.first().unwrap();
+        let mut rows = sqlite.eval(best).unwrap().values;
+        rows.sort();
+        assert_eq!(rows, example.output.values);
+    }
 }
@@ -0,0 +1,1407 @@
+use crate::bottomup::output_fields;
+use crate::eval::EvalError;
+use crate::types::*;
+use rusqlite::Connection;
+use std::sync::Arc;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum ParseError {
+    /// [offset] is in bytes, and [line] and [column] count from 1.
+    #[error("{message} at line {line}, column {column}")]
+    Syntax {
+        message: String,
+        offset: usize,
+        line: usize,
+        column: usize,
+    },
+    #[error("failed to evaluate a subquery")]
+    Evaluation(#[from] EvalError),
+}
+
+/// [syntax_error(sql, offset, message)] reports [message] about the text of [sql] starting at
+/// byte [offset].
+fn syntax_error(sql: &str, offset: usize, message: String) -> ParseError {
+    let before = &sql[..offset];
+    let line = before.matches('\n').count() + 1;
+    let column = before[before.rfind('\n').map_or(0, |i| i + 1)..]
+        .chars()
+        .count()
+        + 1;
+    ParseError::Syntax {
+        message,
+        offset,
+        line,
+        column,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    /// A keyword or an unquoted identifier.
+    Word(String),
+    Quoted(String),
+    Int(isize),
+    Symbol(&'static str),
+    End,
+}
+
+const SYMBOLS: [&str; 17] = [
+    "<=", ">=", "<>", "!=", "(", ")", ",", ".", "*", "+", "-", "/", "%", "=", "<", ">", ";",
+];
+
+/// Words that are only ever keywords in the supported subset, so they have to be quoted to be
+/// used as names.
+const RESERVED: [&str; 40] = [
+    "ALL",
+    "AND",
+    "AS",
+    "ASC",
+    "BY",
+    "CROSS",
+    "DESC",
+    "DISTINCT",
+    "DIV",
+    "EXCEPT",
+    "EXISTS",
+    "FALSE",
+    "FETCH",
+    "FROM",
+    "FULL",
+    "GROUP",
+    "HAVING",
+    "IN",
+    "INNER",
+    "INTERSECT",
+    "IS",
+    "JOIN",
+    "LEFT",
+    "LIMIT",
+    "NATURAL",
+    "NOT",
+    "NULL",
+    "OFFSET",
+    "ON",
+    "OR",
+    "ORDER",
+    "OUTER",
+    "RIGHT",
+    "SELECT",
+    "TRUE",
+    "UNION",
+    "USING",
+    "WHERE",
+    "WINDOW",
+    "WITH",
+];
+
+/// [tokenize(sql)] splits [sql] into tokens paired with their offsets, ending with
+/// [Token::End]. Comments are skipped.
+fn tokenize(sql: &str) -> Result<Vec<(Token, usize)>, ParseError> {
+    let mut tokens = Vec::new();
+    let mut chars = sql.char_indices().peekable();
+    while let Some(&(start, c)) = chars.peek() {
+        let rest = &sql[start..];
+        if c.is_whitespace() {
+            chars.next();
+        } else if rest.starts_with("--") {
+            while chars.next_if(|&(_, c)| c != '\n').is_some() {}
+        } else if c.is_alphabetic() || c == '_' {
+            let mut end = start;
+            while let Some((i, c)) = chars.next_if(|&(_, c)| c.is_alphanumeric() || c == '_') {
+                end = i + c.len_utf8();
+            }
+            tokens.push((Token::Word(sql[start..end].to_string()), start));
+        } else if c.is_ascii_digit() {
+            let mut end = start;
+            while let Some((i, _)) = chars.next_if(|&(_, c)| c.is_ascii_digit()) {
+                end = i + 1;
+            }
+            if let Some(&(_, '.' | 'e' | 'E')) = chars.peek() {
+                return Err(syntax_error(
+                    sql,
+                    start,
+                    String::from("only integer literals are supported"),
+                ));
+            }
+            let value = sql[start..end].parse().map_err(|_| {
+                syntax_error(sql, start, String::from("integer literal is too large"))
+            })?;
+            tokens.push((Token::Int(value), start));
+        } else if c == '"' || c == '`' {
+            chars.next();
+            let mut name = String::new();
+            loop {
+                match chars.next() {
+                    Some((_, d)) if d == c => {
+                        // A doubled quote stands for itself.
+                        if chars.next_if(|&(_, d)| d == c).is_none() {
+                            break;
+                        }
+                        name.push(c);
+                    }
+                    Some((_, d)) => name.push(d),
+                    None => {
+                        return Err(syntax_error(
+                            sql,
+                            start,
+                            String::from("unterminated quoted identifier"),
+                        ))
+                    }
+                }
+            }
+            tokens.push((Token::Quoted(name), start));
+        } else if c == '\'' {
+            return Err(syntax_error(
+                sql,
+                start,
+                String::from("string literals aren't supported"),
+            ));
+        } else if let Some(symbol) = SYMBOLS.iter().find(|s| rest.starts_with(*s)) {
+            for _ in 0..symbol.len() {
+                chars.next();
+            }
+            tokens.push((Token::Symbol(symbol), start));
+        } else {
+            return Err(syntax_error(
+                sql,
+                start,
+                format!("unexpected character {:?}", c),
+            ));
+        }
+    }
+    tokens.push((Token::End, sql.len()));
+    Ok(tokens)
+}
+
+/// Which aggregates an expression can contain.
+#[derive(Clone, Copy)]
+enum Aggregates<'s> {
+    Forbidden,
+    Any,
+    /// Those computed by the grouped query the expression is in the HAVING clause of.
+    Selected(&'s [AggExpr]),
+}
+
+/// What the names in an expression can refer to: the columns of the queries in the FROM
+/// clause it's in.
+#[derive(Clone, Copy)]
+struct Scope<'s> {
+    sources: &'s [AST<PredNode>],
+    aggregates: Aggregates<'s>,
+}
+
+/// The clauses of a SELECT statement, before they're turned into an [AST].
+struct Core {
+    distinct: bool,
+    /// The selected items with their offsets, or [None] for `*`.
+    items: Option<Vec<(SelectItem, usize)>>,
+    items_offset: usize,
+    sources: Vec<AST<PredNode>>,
+    /// The kind and ON condition of an explicit join, if the FROM clause has one.
+    join: Option<(JoinKind, PredNode)>,
+    filter: Option<(PredNode, usize)>,
+    group_by: Option<(Vec<Field>, usize)>,
+    having: Option<PredNode>,
+}
+
+/// The sources of a FROM clause, with the kind and ON condition of their join if it's explicit.
+type FromClause = (Vec<AST<PredNode>>, Option<(JoinKind, PredNode)>);
+
+struct Parser<'a> {
+    sql: &'a str,
+    tokens: Vec<(Token, usize)>,
+    pos: usize,
+    conn: &'a Connection,
+}
+
+/// [parse_sql_query(sql, conn)] parses [sql] into a query over the tables of [conn], which
+/// also evaluates any subqueries in its predicates.
+///
+/// The supported subset is what [crate::sql::create_sql_query] prints: SELECT statements over
+/// tables and derived tables with at most one join, integer arithmetic, comparisons, IN and
+/// EXISTS subqueries, grouping by fields with COUNT, SUM, MIN, MAX and AVG, set operations,
+/// ORDER BY and LIMIT. Anything else is reported with its position.
+pub fn parse_sql_query(sql: &str, conn: &Connection) -> Result<AST<PredNode>, ParseError> {
+    let mut parser = Parser {
+        sql,
+        tokens: tokenize(sql)?,
+        pos: 0,
+        conn,
+    };
+    let query = parser.query()?;
+    parser.symbol(";");
+    match parser.peek() {
+        Token::End => Ok(query),
+        _ => parser.error(parser.offset(), "expected the end of the query"),
+    }
+}
+
+fn comparison(op: &str, left: ExprNode, right: ExprNode) -> PredNode {
+    match op {
+        "<" => PredNode::Lt { left, right },
+        "<=" => PredNode::Le { left, right },
+        ">" => PredNode::Gt { left, right },
+        ">=" => PredNode::Ge { left, right },
+        "=" => PredNode::Eq { left, right },
+        _ => PredNode::Ne { left, right },
+    }
+}
+
+fn contains_aggregate(expr: &ExprNode) -> bool {
+    match expr {
+        ExprNode::Aggregate(_) => true,
+        ExprNode::Field(_) | ExprNode::Int { .. } => false,
+        ExprNode::Add { left, right }
+        | ExprNode::Sub { left, right }
+        | ExprNode::Mul { left, right }
+        | ExprNode::Div { left, right }
+        | ExprNode::Mod { left, right } => contains_aggregate(left) || contains_aggregate(right),
+    }
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> &Token {
+        &self.tokens[self.pos].0
+    }
+
+    fn offset(&self) -> usize {
+        self.tokens[self.pos].1
+    }
+
+    fn advance(&mut self) {
+        if self.pos + 1 < self.tokens.len() {
+            self.pos += 1;
+        }
+    }
+
+    fn error<T>(&self, offset: usize, message: impl Into<String>) -> Result<T, ParseError> {
+        Err(syntax_error(self.sql, offset, message.into()))
+    }
+
+    fn is_keyword(&self, keyword: &str) -> bool {
+        matches!(self.peek(), Token::Word(w) if w.eq_ignore_ascii_case(keyword))
+    }
+
+    /// [p.keyword(k)] consumes keyword [k] if it's next, returning whether it was.
+    fn keyword(&mut self, keyword: &str) -> bool {
+        let found = self.is_keyword(keyword);
+        if found {
+            self.advance();
+        }
+        found
+    }
+
+    fn expect_keyword(&mut self, keyword: &str) -> Result<(), ParseError> {
+        if self.keyword(keyword) {
+            Ok(())
+        } else {
+            self.error(self.offset(), format!("expected {}", keyword))
+        }
+    }
+
+    fn symbol(&mut self, symbol: &str) -> bool {
+        let found = matches!(self.peek(), Token::Symbol(s) if *s == symbol);
+        if found {
+            self.advance();
+        }
+        found
+    }
+
+    fn expect_symbol(&mut self, symbol: &str) -> Result<(), ParseError> {
+        if self.symbol(symbol) {
+            Ok(())
+        } else {
+            self.error(self.offset(), format!("expected {:?}", symbol))
+        }
+    }
+
+    /// [p.name()] returns the identifier that's next, if there is one.
+    fn name(&mut self) -> Option<String> {
+        let name = match self.peek() {
+            Token::Quoted(name) => name.clone(),
+            Token::Word(w) if !RESERVED.iter().any(|r| w.eq_ignore_ascii_case(r)) => w.clone(),
+            _ => return None,
+        };
+        self.advance();
+        Some(name)
+    }
+
+    fn expect_name(&mut self, what: &str) -> Result<String, ParseError> {
+        match self.name() {
+            Some(name) => Ok(name),
+            None => self.error(self.offset(), format!("expected {}", what)),
+        }
+    }
+
+    /// [p.alias()] parses the optional [AS alias] after a source or select item.
+    fn alias(&mut self) -> Result<Option<String>, ParseError> {
+        if self.keyword("AS") {
+            self.expect_name("an alias").map(Some)
+        } else {
+            Ok(self.name())
+        }
+    }
+
+    fn count(&mut self) -> Result<usize, ParseError> {
+        match *self.peek() {
+            Token::Int(n) if n >= 0 => {
+                self.advance();
+                Ok(n as usize)
+            }
+            _ => self.error(self.offset(), "expected a row count"),
+        }
+    }
+
+    /// query := core {(UNION | INTERSECT | EXCEPT) [ALL] core} [ORDER BY keys]
+    ///          [LIMIT n | FETCH FIRST n ROWS ONLY]
+    fn query(&mut self) -> Result<AST<PredNode>, ParseError> {
+        if self.is_keyword("WITH") {
+            return self.error(self.offset(), "WITH isn't supported; use a derived table");
+        }
+        let (mut query, mut visible) = self.core()?;
+        loop {
+            let op = if self.keyword("UNION") {
+                SetOp::Union
+            } else if self.keyword("INTERSECT") {
+                SetOp::Intersect
+            } else if self.keyword("EXCEPT") {
+                SetOp::Except
+            } else {
+                break;
+            };
+            let all = self.keyword("ALL");
+            let start = self.offset();
+            let (mut table2, _) = self.core()?;
+            if table2.arity() != query.arity() {
+                return self.error(
+                    start,
+                    format!(
+                        "expected {} columns, like the other side of the {}",
+                        query.arity(),
+                        op
+                    ),
+                );
+            }
+            if table2.alias() == query.alias() {
+                let alias = AST::fresh_alias(&[&query, &table2]);
+                table2 = table2.with_alias(alias);
+            }
+            let alias = AST::fresh_alias(&[&query, &table2]);
+            query = AST::SetOp {
+                op,
+                all,
+                table1: Box::new(query),
+                table2: Box::new(table2),
+                alias,
+            };
+            visible = Vec::new();
+        }
+        if self.keyword("ORDER") {
+            self.expect_keyword("BY")?;
+            let mut keys = Vec::new();
+            loop {
+                let field = self.order_key(&query, &visible)?;
+                let dir = if self.keyword("DESC") {
+                    SortDir::Desc
+                } else {
+                    self.keyword("ASC");
+                    SortDir::Asc
+                };
+                keys.push(SortKey { field, dir });
+                if !self.symbol(",") {
+                    break;
+                }
+            }
+            let alias = AST::fresh_alias(&[&query]);
+            query = AST::OrderBy {
+                keys: keys.into(),
+                table: Box::new(query),
+                alias,
+            };
+        }
+        let count = if self.keyword("LIMIT") {
+            Some(self.count()?)
+        } else if self.keyword("FETCH") {
+            if !self.keyword("FIRST") {
+                self.expect_keyword("NEXT")?;
+            }
+            let count = self.count()?;
+            if !self.keyword("ROWS") {
+                self.expect_keyword("ROW")?;
+            }
+            self.expect_keyword("ONLY")?;
+            Some(count)
+        } else {
+            None
+        };
+        if let Some(count) = count {
+            if self.is_keyword("OFFSET") || self.symbol(",") {
+                return self.error(self.offset(), "OFFSET isn't supported");
+            }
+            let alias = AST::fresh_alias(&[&query]);
+            query = AST::Limit {
+                count,
+                table: Box::new(query),
+                alias,
+            };
+        }
+        Ok(query)
+    }
+
+    /// [p.order_key(query, visible)] parses a column of [query] to sort by. It can be
+    /// qualified by the alias of [query] or, if [query] is a single SELECT, by the aliases in
+    /// its FROM clause, [visible].
+    fn order_key(
+        &mut self,
+        query: &AST<PredNode>,
+        visible: &[String],
+    ) -> Result<Field, ParseError> {
+        let start = self.offset();
+        let mut name = self.expect_name("a column to sort by")?;
+        if self.symbol(".") {
+            let known = name.eq_ignore_ascii_case(query.alias())
+                || visible.iter().any(|v| v.eq_ignore_ascii_case(&name));
+            if !known {
+                return self.error(start, format!("unknown table or alias {:?}", name));
+            }
+            name = self.expect_name("a column name")?;
+        }
+        let mut matches = output_fields(query)
+            .into_iter()
+            .filter(|f| f.name.eq_ignore_ascii_case(&name));
+        match (matches.next(), matches.next()) {
+            (Some(field), None) => Ok(field),
+            (None, _) => self.error(start, format!("unknown column {:?}", name)),
+            (Some(_), Some(_)) => self.error(start, format!("ambiguous column {:?}", name)),
+        }
+    }
+
+    /// [p.core()] parses a SELECT statement without set operations, ORDER BY or LIMIT,
+    /// returning it with the aliases its FROM clause makes visible.
+    fn core(&mut self) -> Result<(AST<PredNode>, Vec<String>), ParseError> {
+        self.expect_keyword("SELECT")?;
+        let distinct = self.keyword("DISTINCT");
+        if !distinct {
+            self.keyword("ALL");
+        }
+        // The items can only be resolved once the FROM clause is known, so it's parsed first.
+        let items_pos = self.pos;
+        let items_offset = self.offset();
+        self.skip_to_from()?;
+        self.advance();
+        let (sources, join) = self.from()?;
+        let after_from = self.pos;
+        self.pos = items_pos;
+        let items = self.items(&sources)?;
+        self.pos = after_from;
+
+        let scope = Scope {
+            sources: &sources,
+            aggregates: Aggregates::Forbidden,
+        };
+        let filter = if self.is_keyword("WHERE") {
+            let start = self.offset();
+            self.advance();
+            Some((self.pred(scope)?, start))
+        } else {
+            None
+        };
+        let group_by = if self.is_keyword("GROUP") {
+            let start = self.offset();
+            self.advance();
+            self.expect_keyword("BY")?;
+            let mut fields = vec![self.field(&sources)?];
+            while self.symbol(",") {
+                fields.push(self.field(&sources)?);
+            }
+            Some((fields, start))
+        } else {
+            None
+        };
+        let selected: Vec<_> = items
+            .iter()
+            .flatten()
+            .filter_map(|(item, _)| match &item.expr {
+                ExprNode::Aggregate(a) => Some(a.clone()),
+                _ => None,
+            })
+            .collect();
+        let having = if self.keyword("HAVING") {
+            let scope = Scope {
+                sources: &sources,
+                aggregates: Aggregates::Selected(&selected),
+            };
+            Some(self.pred(scope)?)
+        } else {
+            None
+        };
+        let visible = sources.iter().map(|s| s.alias().to_string()).collect();
+        let core = Core {
+            distinct,
+            items,
+            items_offset,
+            sources,
+            join,
+            filter,
+            group_by,
+            having,
+        };
+        Ok((self.build(core)?, visible))
+    }
+
+    /// [p.skip_to_from()] moves to the FROM clause of the SELECT statement being parsed.
+    fn skip_to_from(&mut self) -> Result<(), ParseError> {
+        let mut depth = 0;
+        loop {
+            match self.peek() {
+                Token::Word(w) if depth == 0 && w.eq_ignore_ascii_case("FROM") => return Ok(()),
+                Token::Symbol("(") => depth += 1,
+                Token::Symbol(")") if depth > 0 => depth -= 1,
+                Token::Symbol(")" | ";") | Token::End => {
+                    return self.error(self.offset(), "expected FROM")
+                }
+                _ => (),
+            }
+            self.advance();
+        }
+    }
+
+    /// [p.items(sources)] parses the items selected from [sources], up to FROM.
+    fn items(
+        &mut self,
+        sources: &[AST<PredNode>],
+    ) -> Result<Option<Vec<(SelectItem, usize)>>, ParseError> {
+        let items = if self.symbol("*") {
+            None
+        } else {
+            let scope = Scope {
+                sources,
+                aggregates: Aggregates::Any,
+            };
+            let mut items = Vec::new();
+            loop {
+                let start = self.offset();
+                let expr = self.expr(scope)?;
+                let alias = self.alias()?;
+                items.push((SelectItem { expr, alias }, start));
+                if !self.symbol(",") {
+                    break;
+                }
+            }
+            Some(items)
+        };
+        if self.is_keyword("FROM") {
+            self.advance();
+            Ok(items)
+        } else {
+            self.error(self.offset(), "expected FROM")
+        }
+    }
+
+    /// [p.from()] parses a FROM clause of one source, two sources separated by a comma or
+    /// CROSS JOIN, or two sources joined ON a condition.
+    fn from(&mut self) -> Result<FromClause, ParseError> {
+        let mut sources = vec![self.source()?];
+        let mut join = None;
+        let start = self.offset();
+        if self.symbol(",") {
+            sources.push(self.source()?);
+        } else if self.keyword("CROSS") {
+            self.expect_keyword("JOIN")?;
+            sources.push(self.source()?);
+        } else if let Some(kind) = self.join_kind()? {
+            sources.push(self.source()?);
+            if self.is_keyword("USING") {
+                return self.error(self.offset(), "USING isn't supported; use ON");
+            }
+            self.expect_keyword("ON")?;
+            let scope = Scope {
+                sources: &sources,
+                aggregates: Aggregates::Forbidden,
+            };
+            join = Some((kind, self.pred(scope)?));
+        }
+        if sources.len() == 2 {
+            if sources[0].alias().eq_ignore_ascii_case(sources[1].alias()) {
+                return self.error(
+                    start,
+                    format!(
+                        "both sides of the join are named {:?}; give one an alias",
+                        sources[1].alias()
+                    ),
+                );
+            }
+            if self.symbol(",") || self.join_kind()?.is_some() || self.is_keyword("CROSS") {
+                return self.error(
+                    start,
+                    "joins of more than two tables aren't supported; join a derived table",
+                );
+            }
+        }
+        Ok((sources, join))
+    }
+
+    /// [p.join_kind()] parses the keywords of an explicit join other than a cross join, if
+    /// they're next.
+    fn join_kind(&mut self) -> Result<Option<JoinKind>, ParseError> {
+        if self.is_keyword("NATURAL") {
+            return self.error(self.offset(), "NATURAL joins aren't supported; use ON");
+        }
+        let kind = if self.keyword("LEFT") {
+            JoinKind::Left
+        } else if self.keyword("RIGHT") {
+            JoinKind::Right
+        } else if self.keyword("FULL") {
+            JoinKind::Full
+        } else if self.keyword("INNER") || self.is_keyword("JOIN") {
+            JoinKind::Inner
+        } else {
+            return Ok(None);
+        };
+        if kind != JoinKind::Inner {
+            self.keyword("OUTER");
+        }
+        self.expect_keyword("JOIN")?;
+        Ok(Some(kind))
+    }
+
+    /// source := table [[AS] alias] | (query) [AS] alias
+    fn source(&mut self) -> Result<AST<PredNode>, ParseError> {
+        let start = self.offset();
+        if self.symbol("(") {
+            let query = self.query()?;
+            self.expect_symbol(")")?;
+            match self.alias()? {
+                Some(alias) => Ok(query.with_alias(alias)),
+                None => self.error(self.offset(), "expected an alias for the derived table"),
+            }
+        } else {
+            let name = self.expect_name("a table")?;
+            let columns = self.columns(&name, start)?;
+            let alias = self.alias()?.unwrap_or_else(|| name.clone());
+            Ok(AST::Table {
+                name,
+                columns,
+                alias,
+            })
+        }
+    }
+
+    /// [p.columns(table, offset)] looks up the columns of [table], which is named at
+    /// [offset].
+    fn columns(&self, table: &str, offset: usize) -> Result<Vec<String>, ParseError> {
+        let sql = format!("SELECT * FROM \"{}\"", table.replace('"', "\"\""));
+        match self.conn.prepare(&sql) {
+            Ok(stmt) => Ok(stmt.column_names().into_iter().map(String::from).collect()),
+            Err(_) => self.error(offset, format!("unknown table {:?}", table)),
+        }
+    }
+
+    /// [p.build(core)] turns the clauses of a SELECT statement into a query, if it has one.
+    fn build(&self, core: Core) -> Result<AST<PredNode>, ParseError> {
+        let aggregated = core.group_by.is_some()
+            || core.having.is_some()
+            || core
+                .items
+                .iter()
+                .flatten()
+                .any(|(item, _)| contains_aggregate(&item.expr));
+        if aggregated {
+            return self.build_aggregate(core);
+        }
+        let mut sources = core.sources.into_iter();
+        let table1 = sources.next().expect("a FROM clause has a source");
+        let fields = core
+            .items
+            .map(|items| items.into_iter().map(|(item, _)| item).collect());
+        match (sources.next(), core.join) {
+            (None, _) => {
+                if fields.is_none() && !core.distinct && core.filter.is_none() {
+                    return Ok(table1);
+                }
+                let alias = AST::fresh_alias(&[&table1]);
+                Ok(AST::Select {
+                    fields,
+                    distinct: core.distinct,
+                    table: Box::new(table1),
+                    pred: core.filter.map_or(PredNode::True, |(pred, _)| pred),
+                    alias,
+                })
+            }
+            (Some(table2), None) if fields.is_none() && !core.distinct && core.filter.is_none() => {
+                let alias = AST::fresh_alias(&[&table1, &table2]);
+                Ok(AST::Concat {
+                    table1: Box::new(table1),
+                    table2: Box::new(table2),
+                    alias,
+                })
+            }
+            (Some(table2), join) => {
+                let (kind, on) = join.unwrap_or((JoinKind::Inner, PredNode::True));
+                // The rows an outer join pads with NULLs aren't filtered by its condition, so
+                // only an inner join can take the WHERE clause as part of it.
+                let pred = match (core.filter, on) {
+                    (None, on) => on,
+                    (Some((filter, _)), PredNode::True) if kind == JoinKind::Inner => filter,
+                    (Some((filter, _)), on) if kind == JoinKind::Inner => PredNode::And {
+                        left: Box::new(on),
+                        right: Box::new(filter),
+                    },
+                    (Some((_, offset)), _) => {
+                        return self.error(
+                            offset,
+                            "WHERE isn't supported on an outer join; filter a derived table",
+                        )
+                    }
+                };
+                let alias = AST::fresh_alias(&[&table1, &table2]);
+                Ok(AST::Join {
+                    fields,
+                    distinct: core.distinct,
+                    kind,
+                    table1: Box::new(table1),
+                    table2: Box::new(table2),
+                    pred,
+                    alias,
+                })
+            }
+        }
+    }
+
+    /// [p.build_aggregate(core)] turns a grouped SELECT statement into a query, which has to
+    /// select its grouping fields in order, followed by its aggregates.
+    fn build_aggregate(&self, core: Core) -> Result<AST<PredNode>, ParseError> {
+        let (group_by, group_offset) = core.group_by.unwrap_or((Vec::new(), core.items_offset));
+        if core.sources.len() > 1 {
+            return self.error(
+                core.items_offset,
+                "grouping a join isn't supported; group a derived table",
+            );
+        }
+        if core.distinct {
+            return self.error(core.items_offset, "DISTINCT isn't supported with grouping");
+        }
+        if let Some((_, offset)) = core.filter {
+            return self.error(
+                offset,
+                "WHERE isn't supported with grouping; group a derived table",
+            );
+        }
+        let Some(items) = core.items else {
+            return self.error(
+                core.items_offset,
+                "a grouped query must select its grouping columns followed by its aggregates",
+            );
+        };
+        if items.len() < group_by.len() {
+            return self.error(
+                group_offset,
+                "a grouped query must select its grouping columns followed by its aggregates",
+            );
+        }
+        let mut aggregates = Vec::new();
+        for (i, (item, offset)) in items.into_iter().enumerate() {
+            match (item.expr, group_by.get(i)) {
+                (ExprNode::Field(field), Some(group)) if field == *group => {
+                    if item.alias.is_some_and(|a| a != field.name) {
+                        return self.error(offset, "grouping columns can't be renamed");
+                    }
+                }
+                (ExprNode::Aggregate(a), _) if i >= group_by.len() => {
+                    if item.alias.is_some_and(|alias| alias != a.column_name()) {
+                        return self.error(
+                            offset,
+                            format!("aggregates can only be named {:?}", a.column_name()),
+                        );
+                    }
+                    aggregates.push(a);
+                }
+                (_, Some(_)) => {
+                    return self.error(
+                        offset,
+                        format!(
+                            "expected grouping column {:?}, in the order of GROUP BY",
+                            group_by[i].name
+                        ),
+                    )
+                }
+                _ => return self.error(offset, "expected an aggregate"),
+            }
+        }
+        let table = core
+            .sources
+            .into_iter()
+            .next()
+            .expect("a FROM clause has a source");
+        let alias = AST::fresh_alias(&[&table]);
+        Ok(AST::Aggregate {
+            group_by: group_by.into(),
+            aggregates: aggregates.into(),
+            table: Box::new(table),
+            having: core.having.unwrap_or(PredNode::True),
+            alias,
+        })
+    }
+
+    /// [p.field(sources)] parses a column of one of [sources], which may be qualified by its
+    /// alias.
+    fn field(&mut self, sources: &[AST<PredNode>]) -> Result<Field, ParseError> {
+        let start = self.offset();
+        let first = self.expect_name("a column")?;
+        let (qualifier, name) = if self.symbol(".") {
+            (Some(first), self.expect_name("a column name")?)
+        } else {
+            (None, first)
+        };
+        let candidates: Vec<_> = sources
+            .iter()
+            .filter(|s| {
+                qualifier
+                    .as_ref()
+                    .is_none_or(|q| q.eq_ignore_ascii_case(s.alias()))
+            })
+            .collect();
+        if let (Some(qualifier), true) = (&qualifier, candidates.is_empty()) {
+            return self.error(start, format!("unknown table or alias {:?}", qualifier));
+        }
+        let mut matches = candidates
+            .into_iter()
+            .flat_map(output_fields)
+            .filter(|f| f.name.eq_ignore_ascii_case(&name));
+        match (matches.next(), matches.next()) {
+            (Some(field), None) => Ok(field),
+            (None, _) => self.error(start, format!("unknown column {:?}", name)),
+            (Some(_), Some(_)) => self.error(start, format!("ambiguous column {:?}", name)),
+        }
+    }
+
+    /// pred := conj {OR conj}
+    fn pred(&mut self, scope: Scope) -> Result<PredNode, ParseError> {
+        let mut pred = self.conjunction(scope)?;
+        while self.keyword("OR") {
+            pred = PredNode::Or {
+                left: Box::new(pred),
+                right: Box::new(self.conjunction(scope)?),
+            };
+        }
+        Ok(pred)
+    }
+
+    /// conj := neg {AND neg}
+    fn conjunction(&mut self, scope: Scope) -> Result<PredNode, ParseError> {
+        let mut pred = self.negation(scope)?;
+        while self.keyword("AND") {
+            pred = PredNode::And {
+                left: Box::new(pred),
+                right: Box::new(self.negation(scope)?),
+            };
+        }
+        Ok(pred)
+    }
+
+    /// neg := NOT neg | atom
+    fn negation(&mut self, scope: Scope) -> Result<PredNode, ParseError> {
+        if self.keyword("NOT") {
+            Ok(PredNode::Not {
+                pred: Box::new(self.negation(scope)?),
+            })
+        } else {
+            self.atom(scope)
+        }
+    }
+
+    /// [p.continues_expr()] is true if the next token continues an expression or compares it,
+    /// so the parenthesized predicate before it was really an expression.
+    fn continues_expr(&self) -> bool {
+        match self.peek() {
+            Token::Symbol(s) => !matches!(*s, ")" | "," | ";"),
+            Token::Word(w) => ["DIV", "IN", "NOT", "IS"]
+                .iter()
+                .any(|k| w.eq_ignore_ascii_case(k)),
+            _ => false,
+        }
+    }
+
+    /// atom := TRUE | FALSE | EXISTS (query) | (pred) | expr op expr | expr [NOT] IN (query)
+    fn atom(&mut self, scope: Scope) -> Result<PredNode, ParseError> {
+        if self.keyword("TRUE") {
+            return Ok(PredNode::True);
+        }
+        if self.keyword("FALSE") {
+            return Ok(PredNode::Not {
+                pred: Box::new(PredNode::True),
+            });
+        }
+        if self.keyword("EXISTS") {
+            let subquery = self.subquery()?;
+            return Ok(PredNode::Exists { subquery });
+        }
+        // A parenthesis can start either a predicate or an expression, so the predicate is
+        // tried first and the error that got further is reported if neither parses.
+        let start = self.pos;
+        let mut pred_error = None;
+        if self.symbol("(") {
+            match self
+                .pred(scope)
+                .and_then(|p| self.expect_symbol(")").map(|_| p))
+            {
+                Ok(pred) if !self.continues_expr() => return Ok(pred),
+                Ok(_) => (),
+                Err(e) => pred_error = Some((self.offset(), e)),
+            }
+            self.pos = start;
+        }
+        let result = self.comparison(scope);
+        match (result, pred_error) {
+            (Err(_), Some((pred_offset, e))) if pred_offset > self.offset() => Err(e),
+            (result, _) => result,
+        }
+    }
+
+    fn comparison(&mut self, scope: Scope) -> Result<PredNode, ParseError> {
+        let left = self.expr(scope)?;
+        if let Token::Symbol(op @ ("<" | "<=" | ">" | ">=" | "=" | "<>" | "!=")) = *self.peek() {
+            self.advance();
+            let right = self.expr(scope)?;
+            return Ok(comparison(op, left, right));
+        }
+        let negated = self.is_keyword("NOT");
+        if negated {
+            self.advance();
+            if !self.is_keyword("IN") {
+                return self.error(self.offset(), "expected IN");
+            }
+        }
+        if self.keyword("IN") {
+            let start = self.offset();
+            let subquery = self.subquery()?;
+            if subquery.query.arity() != 1 {
+                return self.error(start, "an IN subquery must select exactly one column");
+            }
+            let pred = PredNode::In {
+                expr: left,
+                subquery,
+            };
+            return Ok(if negated {
+                PredNode::Not {
+                    pred: Box::new(pred),
+                }
+            } else {
+                pred
+            });
+        }
+        match left {
+            // SQLite has no booleans, so its true is printed as 1, and its false as 0.
+            ExprNode::Int { value: 1 } => Ok(PredNode::True),
+            ExprNode::Int { value: 0 } => Ok(PredNode::Not {
+                pred: Box::new(PredNode::True),
+            }),
+            _ if self.is_keyword("IS") => self.error(self.offset(), "IS isn't supported"),
+            _ => self.error(self.offset(), "expected a comparison"),
+        }
+    }
+
+    /// [p.subquery()] parses a parenthesized query and evaluates it. Subqueries can't
+    /// reference the query they're nested in.
+    fn subquery(&mut self) -> Result<Arc<Subquery>, ParseError> {
+        self.expect_symbol("(")?;
+        let query = self.query()?;
+        self.expect_symbol(")")?;
+        let rows = crate::sql::eval(&query, self.conn)
+            .map_err(EvalError::from)?
+            .values;
+        Ok(Arc::new(Subquery {
+            query,
+            rows,
+            scope: None,
+        }))
+    }
+
+    /// expr := term {(+ | -) term}
+    fn expr(&mut self, scope: Scope) -> Result<ExprNode, ParseError> {
+        let mut expr = self.term(scope)?;
+        loop {
+            let left = Box::new(expr);
+            expr = if self.symbol("+") {
+                ExprNode::Add {
+                    left,
+                    right: Box::new(self.term(scope)?),
+                }
+            } else if self.symbol("-") {
+                ExprNode::Sub {
+                    left,
+                    right: Box::new(self.term(scope)?),
+                }
+            } else {
+                return Ok(*left);
+            };
+        }
+    }
+
+    /// term := factor {(* | / | DIV | %) factor}
+    fn term(&mut self, scope: Scope) -> Result<ExprNode, ParseError> {
+        let mut expr = self.factor(scope)?;
+        loop {
+            let left = Box::new(expr);
+            expr = if self.symbol("*") {
+                ExprNode::Mul {
+                    left,
+                    right: Box::new(self.factor(scope)?),
+                }
+            } else if self.symbol("/") || self.keyword("DIV") {
+                ExprNode::Div {
+                    left,
+                    right: Box::new(self.factor(scope)?),
+                }
+            } else if self.symbol("%") {
+                ExprNode::Mod {
+                    left,
+                    right: Box::new(self.factor(scope)?),
+                }
+            } else {
+                return Ok(*left);
+            };
+        }
+    }
+
+    /// factor := n | -n | (expr) | aggregate(field) | field
+    fn factor(&mut self, scope: Scope) -> Result<ExprNode, ParseError> {
+        let start = self.offset();
+        match self.peek().clone() {
+            Token::Int(value) => {
+                self.advance();
+                Ok(ExprNode::Int { value })
+            }
+            Token::Symbol("-") => {
+                self.advance();
+                match *self.peek() {
+                    Token::Int(value) => {
+                        self.advance();
+                        Ok(ExprNode::Int { value: -value })
+                    }
+                    _ => self.error(start, "only integer literals can be negated"),
+                }
+            }
+            Token::Symbol("(") => {
+                self.advance();
+                let expr = self.expr(scope)?;
+                self.expect_symbol(")")?;
+                Ok(expr)
+            }
+            Token::Word(w) if w.eq_ignore_ascii_case("NULL") => {
+                self.error(start, "NULL isn't supported")
+            }
+            Token::Word(w) if self.tokens[self.pos + 1].0 == Token::Symbol("(") => {
+                self.advance();
+                self.aggregate(&w, start, scope)
+            }
+            _ => Ok(ExprNode::Field(self.field(scope.sources)?)),
+        }
+    }
+
+    /// [p.aggregate(name, offset, scope)] parses the argument of aggregate function [name],
+    /// which is called at [offset].
+    fn aggregate(
+        &mut self,
+        name: &str,
+        offset: usize,
+        scope: Scope,
+    ) -> Result<ExprNode, ParseError> {
+        let Some(func) = AggFunc::ALL
+            .into_iter()
+            .find(|f| f.to_string().eq_ignore_ascii_case(name))
+        else {
+            return self.error(offset, format!("unsupported function {:?}", name));
+        };
+        self.expect_symbol("(")?;
+        if self.is_keyword("DISTINCT") || *self.peek() == Token::Symbol("*") {
+            return self.error(
+                self.offset(),
+                format!("{} is only supported on a column", func),
+            );
+        }
+        let field = self.field(scope.sources)?;
+        self.expect_symbol(")")?;
+        let a = AggExpr { func, field };
+        match scope.aggregates {
+            Aggregates::Any => Ok(ExprNode::Aggregate(a)),
+            Aggregates::Selected(selected) if selected.contains(&a) => Ok(ExprNode::Aggregate(a)),
+            Aggregates::Selected(_) => {
+                self.error(offset, "HAVING can only use aggregates that are selected")
+            }
+            Aggregates::Forbidden => self.error(
+                offset,
+                "aggregates are only supported in the SELECT and HAVING clauses",
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sql::{create_dialect_sql_query, create_sql_query, create_table, eval};
+
+    fn example_conn() -> Connection {
+        create_table(&[
+            ConcTable {
+                name: String::from("t1"),
+                columns: vec![String::from("a"), String::from("b")],
+                values: vec![
+                    vec![1.into(), 2.into()],
+                    vec![3.into(), 4.into()],
+                    vec![5.into(), 6.into()],
+                ],
+            },
+            ConcTable {
+                name: String::from("t2"),
+                columns: vec![String::from("a"), String::from("order")],
+                values: vec![vec![1.into(), 7.into()], vec![5.into(), 8.into()]],
+            },
+        ])
+        .unwrap()
+    }
+
+    fn field(table: &str, name: &str) -> Field {
+        Field {
+            table: String::from(table),
+            name: String::from(name),
+        }
+    }
+
+    #[test]
+    fn test_parse_sql_query() {
+        let conn = example_conn();
+        let query = parse_sql_query(
+            "select A, b + 1 as c from T1 where not (a > 2) or t1.b = -6",
+            &conn,
+        )
+        .unwrap();
+        // Keywords and names are case-insensitive, but columns keep the schema's spelling.
+        let expected = AST::Select {
+            fields: Some(
+                vec![
+                    SelectItem::from(field("T1", "a")),
+                    SelectItem {
+                        expr: ExprNode::Add {
+                            left: Box::new(ExprNode::Field(field("T1", "b"))),
+                            right: Box::new(ExprNode::Int { value: 1 }),
+                        },
+                        alias: Some(String::from("c")),
+                    },
+                ]
+                .into(),
+            ),
+            distinct: false,
+            table: Box::new(AST::Table {
+                name: String::from("T1"),
+                columns: vec![String::from("a"), String::from("b")],
+                alias: String::from("T1"),
+            }),
+            pred: PredNode::Or {
+                left: Box::new(PredNode::Not {
+                    pred: Box::new(PredNode::Gt {
+                        left: ExprNode::Field(field("T1", "a")),
+                        right: ExprNode::Int { value: 2 },
+                    }),
+                }),
+                right: Box::new(PredNode::Eq {
+                    left: ExprNode::Field(field("T1", "b")),
+                    right: ExprNode::Int { value: -6 },
+                }),
+            },
+            alias: String::from("q1"),
+        };
+        assert_eq!(query, expected);
+    }
+
+    #[test]
+    fn test_parse_printed_queries() {
+        let conn = example_conn();
+        let t1 = AST::Table {
+            name: String::from("t1"),
+            columns: vec![String::from("a"), String::from("b")],
+            alias: String::from("t1"),
+        };
+        let t2 = AST::Table {
+            name: String::from("t2"),
+            columns: vec![String::from("a"), String::from("order")],
+            alias: String::from("t2"),
+        };
+        let subquery = AST::Select {
+            fields: Some(vec![SelectItem::from(field("t1", "a"))].into()),
+            distinct: false,
+            table: Box::new(t1.clone()),
+            pred: PredNode::True,
+            alias: String::from("q1"),
+        };
+        let join = AST::Join {
+            fields: Some(
+                vec![
+                    SelectItem::from(field("t1", "b")),
+                    SelectItem {
+                        expr: ExprNode::Field(field("t2", "order")),
+                        alias: Some(String::from("o")),
+                    },
+                ]
+                .into(),
+            ),
+            distinct: true,
+            kind: JoinKind::Left,
+            table1: Box::new(t1.clone()),
+            table2: Box::new(t2.clone()),
+            pred: PredNode::And {
+                left: Box::new(PredNode::Eq {
+                    left: ExprNode::Field(field("t1", "a")),
+                    right: ExprNode::Field(field("t2", "a")),
+                }),
+                right: Box::new(PredNode::Not {
+                    pred: Box::new(PredNode::In {
+                        expr: ExprNode::Mod {
+                            left: Box::new(ExprNode::Field(field("t1", "b"))),
+                            right: Box::new(ExprNode::Int { value: 4 }),
+                        },
+                        subquery: Arc::new(Subquery {
+                            rows: eval(&subquery, &conn).unwrap().values,
+                            query: subquery,
+                            scope: None,
+                        }),
+                    }),
+                }),
+            },
+            alias: String::from("q1"),
+        };
+        let aggregate = AST::Aggregate {
+            group_by: vec![field("t1", "a")].into(),
+            aggregates: vec![AggExpr {
+                func: AggFunc::Sum,
+                field: field("t1", "b"),
+            }]
+            .into(),
+            table: Box::new(t1.clone()),
+            having: PredNode::Gt {
+                left: ExprNode::Aggregate(AggExpr {
+                    func: AggFunc::Sum,
+                    field: field("t1", "b"),
+                }),
+                right: ExprNode::Int { value: 2 },
+            },
+            alias: String::from("q1"),
+        };
+        let set_op = AST::SetOp {
+            op: SetOp::Except,
+            all: true,
+            table1: Box::new(aggregate.clone()),
+            table2: Box::new(join.clone().with_alias(String::from("q2"))),
+            alias: String::from("q3"),
+        };
+        let sorted = AST::OrderBy {
+            keys: vec![SortKey {
+                field: field("q3", "a"),
+                dir: SortDir::Desc,
+            }]
+            .into(),
+            table: Box::new(set_op),
+            alias: String::from("q1"),
+        };
+        let limited = AST::Limit {
+            count: 2,
+            table: Box::new(sorted.with_alias(String::from("q4"))),
+            alias: String::from("q5"),
+        };
+        let concat = AST::Concat {
+            table1: Box::new(t1),
+            table2: Box::new(t2),
+            alias: String::from("q1"),
+        };
+        // SQLite can't run EXCEPT ALL, so it's printed for SQLite as a rewrite that isn't read
+        // back as one.
+        for (query, sqlite) in [
+            (join, true),
+            (aggregate, true),
+            (limited, false),
+            (concat, true),
+        ] {
+            for dialect in [Dialect::Sqlite, Dialect::Postgres, Dialect::Ansi] {
+                if dialect == Dialect::Sqlite && !sqlite {
+                    continue;
+                }
+                let sql = create_dialect_sql_query(&query, dialect);
+                assert_eq!(parse_sql_query(&sql, &conn).unwrap(), query, "{}", sql);
+            }
+        }
+    }
+
+    #[test]
+    fn test_parse_sql_query_round_trip() {
+        let conn = example_conn();
+        let queries = [
+            "SELECT * FROM t1",
+            "SELECT DISTINCT b FROM t1 AS x WHERE x.a <> 3 AND (b - 1) * 2 >= a",
+            "SELECT t1.a, \"order\" FROM t1, t2 WHERE t1.a = t2.a",
+            "SELECT * FROM t1 CROSS JOIN t2 AS u",
+            "SELECT * FROM t1 JOIN t2 ON t1.a = t2.a WHERE b < 6",
+            "SELECT * FROM t1 FULL OUTER JOIN t2 ON ((t1.a + 0) = t2.a)",
+            "SELECT a, COUNT(b), MAX(b) AS \"MAX(b)\" FROM t1 GROUP BY a HAVING COUNT(b) > 0",
+            "SELECT MIN(a) FROM (SELECT * FROM t1 WHERE b > 2) AS big",
+            "SELECT * FROM t1 WHERE a IN (SELECT a FROM t2) OR EXISTS (SELECT * FROM t2 WHERE 0)",
+            "SELECT a FROM t1 UNION SELECT a FROM t2 INTERSECT SELECT a FROM t1 ORDER BY a",
+            "SELECT a, b FROM t1 ORDER BY t1.b DESC, a LIMIT 2;",
+            "-- the first row\nSELECT `b` FROM `t1` FETCH FIRST 1 ROWS ONLY",
+        ];
+        for sql in queries {
+            let query = parse_sql_query(sql, &conn).unwrap();
+            let printed = create_sql_query(&query);
+            let reparsed = parse_sql_query(&printed, &conn).unwrap();
+            assert_eq!(create_sql_query(&reparsed), printed, "{}", sql);
+            // FETCH FIRST isn't SQLite, so that query is compared in its printed form.
+            let expected = conn
+                .prepare(if sql.contains("FETCH") { &printed } else { sql })
+                .unwrap()
+                .query_map([], |row| {
+                    (0..query.arity())
+                        .map(|i| row.get::<_, Value>(i))
+                        .collect::<Result<Vec<_>, _>>()
+                })
+                .unwrap()
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap();
+            assert_eq!(eval(&query, &conn).unwrap().values, expected, "{}", sql);
+        }
+    }
+
+    #[test]
+    fn test_parse_sql_query_errors() {
+        let conn = example_conn();
+        let cases = [
+            ("SELECT a FROM t1 WHERE b = 'x'", "string literals aren't supported at line 1, column 28"),
+            ("SELECT a\nFROM t1\nWHERE b = 1.5", "only integer literals are supported at line 3, column 11"),
+            ("SELECT c FROM t1", "unknown column \"c\" at line 1, column 8"),
+            ("SELECT a FROM t1, t2", "ambiguous column \"a\" at line 1, column 8"),
+            ("SELECT u.a FROM t1", "unknown table or alias \"u\" at line 1, column 8"),
+            ("SELECT a FROM t3", "unknown table \"t3\" at line 1, column 15"),
+            ("SELECT 1", "expected FROM at line 1, column 9"),
+            ("SELECT a FROM t1 WHERE b IS NULL", "IS isn't supported at line 1, column 26"),
+            ("SELECT a FROM t1 WHERE abs(b) > 1", "unsupported function \"abs\" at line 1, column 24"),
+            ("SELECT COUNT(*) FROM t1", "COUNT is only supported on a column at line 1, column 14"),
+            ("SELECT a FROM t1 WHERE SUM(b) > 1", "aggregates are only supported in the SELECT and HAVING clauses at line 1, column 24"),
+            ("SELECT SUM(b), a FROM t1 GROUP BY a", "expected grouping column \"a\", in the order of GROUP BY at line 1, column 8"),
+            ("SELECT a FROM t1 GROUP BY a HAVING MIN(b) > 1", "HAVING can only use aggregates that are selected at line 1, column 36"),
+            ("SELECT * FROM t1 LEFT JOIN t2 ON t1.a = t2.a WHERE b > 1", "WHERE isn't supported on an outer join; filter a derived table at line 1, column 46"),
+            ("SELECT * FROM t1 JOIN t2 ON 1 JOIN t1 AS u ON 1", "joins of more than two tables aren't supported; join a derived table at line 1, column 18"),
+            ("SELECT * FROM t1, t1", "both sides of the join are named \"t1\"; give one an alias at line 1, column 17"),
+            ("SELECT * FROM (SELECT * FROM t1)", "expected an alias for the derived table at line 1, column 33"),
+            ("SELECT a FROM t1 UNION SELECT * FROM t1", "expected 1 columns, like the other side of the UNION at line 1, column 24"),
+            ("SELECT a FROM t1 WHERE a IN (SELECT * FROM t1)", "an IN subquery must select exactly one column at line 1, column 29"),
+            ("SELECT a FROM t1 WHERE (a + 1", "expected \")\" at line 1, column 30"),
+            ("SELECT a FROM t1 LIMIT 1 OFFSET 1", "OFFSET isn't supported at line 1, column 26"),
+            ("SELECT t1.a FROM t1 t", "unknown table or alias \"t1\" at line 1, column 8"),
+            ("SELECT a FROM t1 garbage here", "expected the end of the query at line 1, column 26"),
+        ];
+        for (sql, message) in cases {
+            let error = parse_sql_query(sql, &conn).unwrap_err();
+            assert_eq!(error.to_string(), message, "{}", sql);
+        }
+    }
+}
@@ -1,17 +1,126 @@
+use crate::eval::{empty_table, EvalError, Evaluator};
 use crate::types::*;
+use rusqlite::types::{FromSql, FromSqlResult, ToSql, ToSqlOutput, ValueRef};
 use rusqlite::{params, params_from_iter, Connection, Error, Result};
+use std::sync::Mutex;
 
-pub fn create_table(input: &[ConcTable]) -> Result<Connection, Error> {
+impl ToSql for Value {
+    fn to_sql(&self) -> Result<ToSqlOutput<'_>> {
+        Ok(match self {
+            Value::Null => ToSqlOutput::Borrowed(ValueRef::Null),
+            Value::Integer(n) => ToSqlOutput::Borrowed(ValueRef::Integer(*n as i64)),
+            Value::Real(x) => ToSqlOutput::Borrowed(ValueRef::Real(*x)),
+            Value::Text(s) => ToSqlOutput::Borrowed(ValueRef::Text(s.as_bytes())),
+        })
+    }
+}
+
+impl FromSql for Value {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        Ok(match value {
+            ValueRef::Null => Value::Null,
+            ValueRef::Integer(n) => Value::Integer(n as isize),
+            ValueRef::Real(x) => Value::Real(x),
+            // We never insert blobs, but read them back as text rather than failing.
+            ValueRef::Text(s) | ValueRef::Blob(s) => {
+                Value::Text(String::from_utf8_lossy(s).into_owned())
+            }
+        })
+    }
+}
+
+/// [column_type(table, i)] returns the declared type for column [i] of [table]: the
+/// storage class shared by every non-NULL value in it, or no type at all if the values
+/// are mixed, so that SQLite's type affinity doesn't convert them on insertion.
+fn column_type(table: &ConcTable, i: usize) -> &'static str {
+    let mut types = table.values.iter().filter_map(|row| match row.get(i)? {
+        Value::Null => None,
+        Value::Integer(_) => Some(" INTEGER"),
+        Value::Real(_) => Some(" REAL"),
+        Value::Text(_) => Some(" TEXT"),
+    });
+    match types.next() {
+        Some(t) if types.all(|u| u == t) => t,
+        Some(_) => "",
+        None => " INTEGER",
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum CreateTableError {
+    #[error("invalid name {name:?}: {reason}")]
+    InvalidName { name: String, reason: &'static str },
+    #[error("failed to create the example tables")]
+    Database(#[from] Error),
+}
+
+/// [check_name(name)] returns an error explaining why [name] can't name a table or column, if
+/// it can't. Anything else is quoted wherever it's used.
+fn check_name(name: &str) -> Result<(), CreateTableError> {
+    let reason = if name.is_empty() {
+        "names can't be empty"
+    } else if name.chars().any(char::is_control) {
+        "names can't contain control characters"
+    } else {
+        return Ok(());
+    };
+    Err(CreateTableError::InvalidName {
+        name: name.to_string(),
+        reason,
+    })
+}
+
+/// [check_names(input)] checks that every table of [input] and every column of each table can
+/// be told apart by name, which SQLite compares without case.
+fn check_names(input: &[ConcTable]) -> Result<(), CreateTableError> {
+    let duplicate = |names: &[&String], reason| {
+        let mut seen = std::collections::HashSet::new();
+        match names.iter().find(|name| !seen.insert(name.to_lowercase())) {
+            Some(name) => Err(CreateTableError::InvalidName {
+                name: name.to_string(),
+                reason,
+            }),
+            None => Ok(()),
+        }
+    };
+    let tables: Vec<_> = input.iter().map(|t| &t.name).collect();
+    duplicate(&tables, "tables must have different names")?;
+    for table in input.iter() {
+        check_name(&table.name)?;
+        if table.name.to_lowercase().starts_with("sqlite_") {
+            return Err(CreateTableError::InvalidName {
+                name: table.name.clone(),
+                reason: "names starting with sqlite_ are reserved",
+            });
+        }
+        for column in table.columns.iter() {
+            check_name(column)?;
+        }
+        let columns: Vec<_> = table.columns.iter().collect();
+        duplicate(&columns, "columns of a table must have different names")?;
+    }
+    Ok(())
+}
+
+pub fn create_table(input: &[ConcTable]) -> Result<Connection, CreateTableError> {
+    check_names(input)?;
+    Ok(load_tables(input)?)
+}
+
+/// [load_tables(input)] returns a new in-memory database holding the tables [input], whose
+/// names have already been checked.
+fn load_tables(input: &[ConcTable]) -> Result<Connection, Error> {
     let conn = Connection::open_in_memory()?;
+    let quote = |name: &str| Dialect::Sqlite.identifier(name);
 
     for table in input.iter() {
         // Create table
         let mut create_table = String::from("CREATE TABLE ");
-        create_table.push_str(&table.name);
+        create_table.push_str(&quote(&table.name));
         create_table.push_str(" (");
         for (i, field) in table.columns.iter().enumerate() {
-            create_table.push_str(field);
-            create_table.push_str(" INTEGER");
+            create_table.push_str(&quote(field));
+            create_table.push_str(column_type(table, i));
             if i != table.columns.len() - 1 {
                 create_table.push_str(", ");
             }
@@ -21,7 +130,7 @@ pub fn create_table(input: &[ConcTable]) -> Result<Connection, Error> {
 
         // Insert values
         let mut insert = String::from("INSERT INTO ");
-        insert.push_str(&table.name);
+        insert.push_str(&quote(&table.name));
         insert.push_str(" VALUES (");
         for i in 0..table.columns.len() {
             if i == table.columns.len() - 1 {
@@ -39,45 +148,84 @@ pub fn create_table(input: &[ConcTable]) -> Result<Connection, Error> {
     Ok(conn)
 }
 
-fn make_dummy_table(query: &AST<PredNode>) -> ConcTable {
-    match query {
-        AST::Select { table, .. } => make_dummy_table(&table),
-        AST::Join { table1, .. } => make_dummy_table(&table1),
-        AST::Table { name, columns } => ConcTable {
-            name: name.clone(),
-            columns: columns.clone(),
-            values: Vec::new(),
-        },
-        AST::Concat { table1, .. } => make_dummy_table(&table1),
-    }
-}
-
 pub fn eval_abstract(query: &AST<()>, conn: &Connection) -> Result<ConcTable, Error> {
     let query = query
         .with_predicates(&vec![PredNode::True; query.num_holes()])
         .expect("expected hole count to match");
 
-    let res = eval(&query, conn);
+    let res = run_query(&create_sql_query_aux(&query, Mode::Abstract), conn);
 
     match res {
         Ok(table) => Ok(table),
-        Err(_) => Ok(make_dummy_table(&query)),
+        Err(_) => Ok(empty_table(&query)),
     }
 }
 
 // NOTE: can we make query a reference? maybe there's a reason we can't?
 pub fn eval(query: &AST<PredNode>, conn: &Connection) -> Result<ConcTable, Error> {
+    run_query(&create_sql_query_aux(query, Mode::Concrete), conn)
+}
+
+/// Evaluates queries in SQLite, which is the reference for how queries are evaluated. A
+/// [Connection] can't be shared between threads, so each thread evaluating a query gets a
+/// connection to its own copy of the tables, which are reused once it's done.
+pub struct SqliteEvaluator {
+    input: Vec<ConcTable>,
+    idle: Mutex<Vec<Connection>>,
+}
+
+impl SqliteEvaluator {
+    /// [SqliteEvaluator::new(input)] evaluates queries over the tables [input], failing like
+    /// [create_table(input)] if they can't be loaded.
+    pub fn new(input: &[ConcTable]) -> Result<Self, CreateTableError> {
+        let conn = create_table(input)?;
+        Ok(SqliteEvaluator {
+            input: input.to_vec(),
+            idle: Mutex::new(vec![conn]),
+        })
+    }
+
+    /// [e.with_connection(f)] calls [f] with a connection that no other thread is using,
+    /// loading the tables into a new one if every connection is busy.
+    pub fn with_connection<T>(
+        &self,
+        f: impl FnOnce(&Connection) -> Result<T, Error>,
+    ) -> Result<T, Error> {
+        let idle = self
+            .idle
+            .lock()
+            .expect("connections are never used under the lock")
+            .pop();
+        let conn = match idle {
+            Some(conn) => conn,
+            None => load_tables(&self.input)?,
+        };
+        let result = f(&conn);
+        self.idle
+            .lock()
+            .expect("connections are never used under the lock")
+            .push(conn);
+        result
+    }
+}
+
+impl Evaluator for SqliteEvaluator {
+    fn eval(&self, query: &AST<PredNode>) -> Result<ConcTable, EvalError> {
+        Ok(self.with_connection(|conn| eval(query, conn))?)
+    }
+
+    fn eval_abstract(&self, query: &AST<()>) -> Result<ConcTable, EvalError> {
+        Ok(self.with_connection(|conn| eval_abstract(query, conn))?)
+    }
+}
+
+fn run_query(query_str: &str, conn: &Connection) -> Result<ConcTable, Error> {
     let mut table = ConcTable {
         name: String::from(""),
         columns: Vec::new(),
         values: Vec::new(),
     };
 
-    let query_str = create_sql_query(query);
-
-    // TODO: there should be a better way of doing this, but remove the paren
-    // at the beginning and end of the query string
-    let query_str = &query_str[1..query_str.len() - 1];
     let mut stmt = conn.prepare(query_str)?;
 
     for column in stmt.column_names().iter() {
@@ -88,110 +236,498 @@ pub fn eval(query: &AST<PredNode>, conn: &Connection) -> Result<ConcTable, Error
     while let Some(row) = rows.next()? {
         let mut row_vec = Vec::new();
         for i in 0..table.columns.len() {
-            row_vec.push(row.get::<_, isize>(i)?);
+            row_vec.push(row.get::<_, Value>(i)?);
         }
         table.values.push(row_vec);
     }
     Ok(table)
 }
 
-fn create_fields_str(fields: Option<&[Field]>) -> String {
-    match fields {
-        Some(fields) => {
-            let mut sql = String::from("");
-            for (i, field) in fields.iter().enumerate() {
-                sql.push_str(&field.name);
-                if i != fields.len() - 1 {
-                    sql.push_str(", ");
-                }
-            }
-            sql
+impl Dialect {
+    /// [d.identifier(name)] quotes [name] so that [d] reads it as an identifier, even if it's
+    /// a keyword or has characters that would otherwise end it.
+    fn identifier(self, name: &str) -> String {
+        let quote = match self {
+            Dialect::MySql => '`',
+            Dialect::Sqlite | Dialect::Postgres | Dialect::Ansi => '"',
+        };
+        let escaped = name.replace(quote, &format!("{}{}", quote, quote));
+        format!("{}{}{}", quote, escaped, quote)
+    }
+
+    /// [d.boolean(b)] prints the literal [b]. SQLite has no boolean type, so it's an integer.
+    fn boolean(self, b: bool) -> &'static str {
+        match (self, b) {
+            (Dialect::Sqlite, true) => "1",
+            (Dialect::Sqlite, false) => "0",
+            (_, true) => "TRUE",
+            (_, false) => "FALSE",
         }
+    }
+
+    /// [d.null_safe_eq()] is the operator comparing values for equality that also holds for
+    /// two NULLs, the way set operations compare rows.
+    fn null_safe_eq(self) -> &'static str {
+        match self {
+            Dialect::Sqlite => "IS",
+            Dialect::MySql => "<=>",
+            Dialect::Postgres | Dialect::Ansi => "IS NOT DISTINCT FROM",
+        }
+    }
+
+    /// [d.field(f)] prints a reference to [f].
+    fn field(self, f: &Field) -> String {
+        format!("{}.{}", self.identifier(&f.table), self.identifier(&f.name))
+    }
+}
+
+fn create_fields_str(fields: &[Field], dialect: Dialect) -> String {
+    fields
+        .iter()
+        .map(|field| dialect.field(field))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn create_items_str(items: Option<&[SelectItem]>, dialect: Dialect) -> String {
+    match items {
+        Some(items) => items
+            .iter()
+            .map(|item| match (&item.expr, &item.alias) {
+                (ExprNode::Field(field), None) => dialect.field(field),
+                (expr, None) => create_sql_expr(expr, dialect),
+                (expr, Some(alias)) => format!(
+                    "{} AS {}",
+                    create_sql_expr(expr, dialect),
+                    dialect.identifier(alias)
+                ),
+            })
+            .collect::<Vec<_>>()
+            .join(", "),
         None => String::from("*"),
     }
 }
 
+/// What a query is being printed for.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Mode {
+    /// SQL in the given dialect, to show to users.
+    Display(Dialect),
+    /// SQL that SQLite can run, which doesn't support INTERSECT ALL or EXCEPT ALL. This is
+    /// also what's displayed for SQLite.
+    Concrete,
+    /// SQL that SQLite can run producing every row the abstract query could produce, laid out
+    /// the way [crate::bvdfs::bvdfs] expects. Outer joins produce their whole cross product
+    /// followed by every NULL-padded row, and set operations produce the rows of both tables.
+    Abstract,
+}
+
+impl Mode {
+    fn dialect(self) -> Dialect {
+        match self {
+            Mode::Display(dialect) => dialect,
+            Mode::Concrete | Mode::Abstract => Dialect::Sqlite,
+        }
+    }
+}
+
+/// [create_sql_query(query)] prints [query] as a standalone statement, in which every nested
+/// query is a derived table named by its alias.
 pub fn create_sql_query(query: &AST<PredNode>) -> String {
+    create_dialect_sql_query(query, Dialect::default())
+}
+
+/// [create_dialect_sql_query(query, dialect)] prints [query] like [create_sql_query], for
+/// [dialect].
+pub fn create_dialect_sql_query(query: &AST<PredNode>, dialect: Dialect) -> String {
+    create_sql_query_aux(query, Mode::Display(dialect))
+}
+
+/// [select_keyword(distinct, mode)] begins a projection, removing duplicates if [distinct]
+/// is set. Abstract queries keep them so that their rows still line up with their tables'.
+fn select_keyword(distinct: bool, mode: Mode) -> &'static str {
+    if distinct && mode != Mode::Abstract {
+        "SELECT DISTINCT"
+    } else {
+        "SELECT"
+    }
+}
+
+/// [create_sql_source(query, mode)] prints [query] as an item of a FROM clause, named by its
+/// alias.
+fn create_sql_source(query: &AST<PredNode>, mode: Mode) -> String {
+    let dialect = mode.dialect();
+    match query {
+        AST::Table { name, alias, .. } if name == alias => dialect.identifier(name),
+        AST::Table { name, alias, .. } => format!(
+            "{} AS {}",
+            dialect.identifier(name),
+            dialect.identifier(alias)
+        ),
+        _ => format!(
+            "({}) AS {}",
+            create_sql_query_aux(query, mode),
+            dialect.identifier(query.alias())
+        ),
+    }
+}
+
+/// [create_where_clause(pred, mode)] filters the rows of a query by [pred], which is left out
+/// if it's trivially true.
+fn create_where_clause(pred: &PredNode, mode: Mode) -> String {
+    match pred {
+        PredNode::True => String::new(),
+        _ => format!(" WHERE {}", create_sql_pred(pred, mode)),
+    }
+}
+
+/// [create_order_by_clause(keys, dialect)] sorts the rows of a query by [keys].
+fn create_order_by_clause(keys: &[SortKey], dialect: Dialect) -> String {
+    let keys: Vec<_> = keys
+        .iter()
+        .map(|k| format!("{} {}", dialect.field(&k.field), k.dir))
+        .collect();
+    format!(" ORDER BY {}", keys.join(", "))
+}
+
+fn create_sql_query_aux(query: &AST<PredNode>, mode: Mode) -> String {
+    let dialect = mode.dialect();
     match query {
         AST::Select {
             fields,
+            distinct,
             table,
             pred,
+            ..
         } => {
             format!(
-                "(SELECT {} FROM {} WHERE {})",
-                create_fields_str(fields.as_ref().map(|t| &t[..])),
-                create_sql_query(table),
-                create_sql_pred(pred)
+                "{} {} FROM {}{}",
+                select_keyword(*distinct, mode),
+                create_items_str(fields.as_ref().map(|t| &t[..]), dialect),
+                create_sql_source(table, mode),
+                create_where_clause(pred, mode)
             )
         }
         AST::Join {
             fields,
+            distinct,
+            kind,
             table1,
             table2,
             pred,
+            ..
         } => {
+            let fields = create_items_str(fields.as_ref().map(|t| &t[..]), dialect);
+            let table1 = create_sql_source(table1, mode);
+            let table2 = create_sql_source(table2, mode);
+            let pred = create_sql_pred(pred, mode);
+            if mode == Mode::Abstract && *kind != JoinKind::Inner {
+                format!(
+                    "SELECT {} FROM {} JOIN {} ON {} UNION ALL SELECT {} FROM {} {} {} ON 0",
+                    fields, table1, table2, pred, fields, table1, kind, table2
+                )
+            } else if dialect == Dialect::MySql && *kind == JoinKind::Full {
+                // MySQL has no FULL JOIN, so the rows of [table2] that don't match any of
+                // [table1] are added to the left join instead.
+                format!(
+                    "{} {} FROM {} LEFT JOIN {} ON {} {} {} {} FROM {} RIGHT JOIN {} ON {} WHERE NOT EXISTS (SELECT 1 FROM {} WHERE {})",
+                    select_keyword(*distinct, mode),
+                    fields,
+                    table1,
+                    table2,
+                    pred,
+                    if *distinct { "UNION" } else { "UNION ALL" },
+                    select_keyword(*distinct, mode),
+                    fields,
+                    table1,
+                    table2,
+                    pred,
+                    table1,
+                    pred
+                )
+            } else {
+                format!(
+                    "{} {} FROM {} {} {} ON {}",
+                    select_keyword(*distinct, mode),
+                    fields,
+                    table1,
+                    kind,
+                    table2,
+                    pred
+                )
+            }
+        }
+        // A table isn't a query on its own, so it's printed as one selecting all of it.
+        AST::Table { .. } => format!("SELECT * FROM {}", create_sql_source(query, mode)),
+        AST::Concat { table1, table2, .. } => {
+            let separator = match dialect {
+                Dialect::Sqlite => ",",
+                Dialect::Postgres | Dialect::MySql | Dialect::Ansi => " CROSS JOIN",
+            };
             format!(
-                "(SELECT {} FROM {} JOIN {} ON {})",
-                create_fields_str(fields.as_ref().map(|t| &t[..])),
-                create_sql_query(table1),
-                create_sql_query(table2),
-                create_sql_pred(pred)
+                "SELECT * FROM {}{} {}",
+                create_sql_source(table1, mode),
+                separator,
+                create_sql_source(table2, mode)
             )
         }
-        AST::Table { name, columns: _ } => format!("({})", name),
-        AST::Concat { table1, table2 } => {
+        // Aggregates are named explicitly, after the columns they're computed from.
+        AST::Aggregate {
+            group_by,
+            aggregates,
+            table,
+            having,
+            ..
+        } => {
+            let mut items = vec![create_fields_str(group_by, dialect)];
+            items.extend(aggregates.iter().map(|a| {
+                format!(
+                    "{} AS {}",
+                    create_sql_agg(a, dialect),
+                    dialect.identifier(&a.column_name())
+                )
+            }));
+            items.retain(|item| !item.is_empty());
+            let mut sql = format!(
+                "SELECT {} FROM {}",
+                items.join(", "),
+                create_sql_source(table, mode)
+            );
+            if !group_by.is_empty() {
+                sql.push_str(" GROUP BY ");
+                sql.push_str(&create_fields_str(group_by, dialect));
+            }
+            if *having != PredNode::True {
+                sql.push_str(" HAVING ");
+                sql.push_str(&create_sql_pred(having, mode));
+            }
+            sql
+        }
+        AST::OrderBy { keys, table, .. } => format!(
+            "SELECT * FROM {}{}",
+            create_sql_source(table, mode),
+            create_order_by_clause(keys, dialect)
+        ),
+        // A derived table doesn't have to keep its order, so a LIMIT of an ORDER BY has to be
+        // part of the same statement.
+        AST::Limit { count, table, .. } => {
+            let (source, order_by) = match table.as_ref() {
+                AST::OrderBy { keys, table, .. } => (table, create_order_by_clause(keys, dialect)),
+                _ => (table, String::new()),
+            };
+            let limit = match dialect {
+                Dialect::Ansi => format!("FETCH FIRST {} ROWS ONLY", count),
+                Dialect::Sqlite | Dialect::Postgres | Dialect::MySql => format!("LIMIT {}", count),
+            };
             format!(
-                "({}, {})",
-                create_sql_query(table1),
-                create_sql_query(table2)
+                "SELECT * FROM {}{} {}",
+                create_sql_source(source, mode),
+                order_by,
+                limit
             )
         }
+        AST::SetOp {
+            op,
+            all,
+            table1,
+            table2,
+            ..
+        } => {
+            let sql1 = create_sql_source(table1, mode);
+            let sql2 = create_sql_source(table2, mode);
+            match mode {
+                Mode::Abstract => {
+                    format!("SELECT * FROM {} UNION ALL SELECT * FROM {}", sql1, sql2)
+                }
+                Mode::Concrete | Mode::Display(Dialect::Sqlite) if *all && *op != SetOp::Union => {
+                    // Number the copies of each row so that the plain set operation matches
+                    // them up one-to-one. The columns have to be renamed to be partitioned
+                    // by, so an empty selection from [table1] comes first to name them.
+                    let columns = (0..table1.arity())
+                        .map(|i| format!("c{}", i))
+                        .collect::<Vec<_>>()
+                        .join(", ");
+                    let numbered = |name| {
+                        format!(
+                            "SELECT *, ROW_NUMBER() OVER (PARTITION BY {}) FROM {}",
+                            columns, name
+                        )
+                    };
+                    format!(
+                        "SELECT * FROM {} WHERE 0 UNION ALL SELECT * FROM (WITH l({}) AS (SELECT * FROM {}), r({}) AS (SELECT * FROM {}) SELECT {} FROM ({} {} {}) AS numbered) AS {}",
+                        sql1,
+                        columns,
+                        sql1,
+                        columns,
+                        sql2,
+                        columns,
+                        numbered("l"),
+                        op,
+                        numbered("r"),
+                        dialect.identifier(query.alias())
+                    )
+                }
+                Mode::Display(Dialect::MySql) if *op != SetOp::Union => create_emulated_set_op(
+                    *op,
+                    *all,
+                    &sql1,
+                    &sql2,
+                    table1.arity(),
+                    query.alias(),
+                    dialect,
+                ),
+                _ => format!(
+                    "SELECT * FROM {} {}{} SELECT * FROM {}",
+                    sql1,
+                    op,
+                    if *all { " ALL" } else { "" },
+                    sql2
+                ),
+            }
+        }
+    }
+}
+
+/// [create_emulated_set_op(op, all, sql1, sql2, arity, alias, dialect)] prints [op], an
+/// INTERSECT or EXCEPT of the FROM items [sql1] and [sql2] with [arity] columns, without
+/// using either, for MySQL before 8.0.31. Rows of [sql1] are kept if a matching row of [sql2]
+/// exists (or doesn't, for EXCEPT), where NULLs match like they do in set operations. If
+/// [all] is set, the copies of each row are numbered so that they're matched one-to-one, and
+/// otherwise duplicates are removed. Like the ROW_NUMBER rewrite for SQLite, an empty
+/// selection from [sql1] comes first to name the columns.
+fn create_emulated_set_op(
+    op: SetOp,
+    all: bool,
+    sql1: &str,
+    sql2: &str,
+    arity: usize,
+    alias: &str,
+    dialect: Dialect,
+) -> String {
+    let columns: Vec<_> = (0..arity).map(|i| format!("c{}", i)).collect();
+    let numbered = |name| {
+        format!(
+            "SELECT *, ROW_NUMBER() OVER (PARTITION BY {}) AS n FROM {}",
+            columns.join(", "),
+            name
+        )
+    };
+    let mut matched: Vec<_> = columns
+        .iter()
+        .map(|c| format!("ln.{} {} rn.{}", c, dialect.null_safe_eq(), c))
+        .collect();
+    if all {
+        matched.push(String::from("ln.n = rn.n"));
     }
+    format!(
+        "SELECT * FROM {} WHERE {} UNION ALL SELECT * FROM (WITH l({}) AS (SELECT * FROM {}), r({}) AS (SELECT * FROM {}) SELECT {}{} FROM ({}) AS ln WHERE {}EXISTS (SELECT 1 FROM ({}) AS rn WHERE {})) AS {}",
+        sql1,
+        dialect.boolean(false),
+        columns.join(", "),
+        sql1,
+        columns.join(", "),
+        sql2,
+        if all { "" } else { "DISTINCT " },
+        columns
+            .iter()
+            .map(|c| format!("ln.{}", c))
+            .collect::<Vec<_>>()
+            .join(", "),
+        numbered("l"),
+        if op == SetOp::Except { "NOT " } else { "" },
+        numbered("r"),
+        matched.join(" AND "),
+        dialect.identifier(alias)
+    )
 }
 
-fn create_sql_pred(pred: &PredNode) -> String {
+fn create_sql_pred(pred: &PredNode, mode: Mode) -> String {
+    let dialect = mode.dialect();
     match pred {
-        PredNode::True => String::from("1"),
-        PredNode::Lt { left, right } => {
+        PredNode::True => String::from(dialect.boolean(true)),
+        PredNode::Lt { left, right } => create_sql_comparison(left, "<", right, dialect),
+        PredNode::Eq { left, right } => create_sql_comparison(left, "=", right, dialect),
+        PredNode::Le { left, right } => create_sql_comparison(left, "<=", right, dialect),
+        PredNode::Gt { left, right } => create_sql_comparison(left, ">", right, dialect),
+        PredNode::Ge { left, right } => create_sql_comparison(left, ">=", right, dialect),
+        PredNode::Ne { left, right } => create_sql_comparison(left, "<>", right, dialect),
+        PredNode::And { left, right } => {
             let mut sql = String::from("(");
-            sql.push_str(&create_sql_expr(left));
-            sql.push_str(" < ");
-            sql.push_str(&create_sql_expr(right));
+            sql.push_str(&create_sql_pred(left, mode));
+            sql.push_str(" AND ");
+            sql.push_str(&create_sql_pred(right, mode));
             sql.push(')');
             sql
         }
-        PredNode::Eq { left, right } => {
+        PredNode::Or { left, right } => {
             let mut sql = String::from("(");
-            sql.push_str(&create_sql_expr(left));
-            sql.push_str(" = ");
-            sql.push_str(&create_sql_expr(right));
+            sql.push_str(&create_sql_pred(left, mode));
+            sql.push_str(" OR ");
+            sql.push_str(&create_sql_pred(right, mode));
             sql.push(')');
             sql
         }
-        PredNode::And { left, right } => {
-            let mut sql = String::from("(");
-            sql.push_str(&create_sql_pred(left));
-            sql.push_str(" AND ");
-            sql.push_str(&create_sql_pred(right));
+        PredNode::Not { pred } => {
+            let mut sql = String::from("(NOT ");
+            sql.push_str(&create_sql_pred(pred, mode));
             sql.push(')');
             sql
         }
+        PredNode::In { expr, subquery } => format!(
+            "({} IN ({}))",
+            create_sql_expr(expr, dialect),
+            create_sql_query_aux(&subquery.query, mode)
+        ),
+        PredNode::Exists { subquery } => {
+            format!("(EXISTS ({}))", create_sql_query_aux(&subquery.query, mode))
+        }
     }
 }
 
-fn create_sql_expr(expr: &ExprNode) -> String {
+fn create_sql_comparison(left: &ExprNode, op: &str, right: &ExprNode, dialect: Dialect) -> String {
+    format!(
+        "({} {} {})",
+        create_sql_expr(left, dialect),
+        op,
+        create_sql_expr(right, dialect)
+    )
+}
+
+pub(crate) fn create_sql_expr(expr: &ExprNode, dialect: Dialect) -> String {
     match expr {
-        ExprNode::Field(field) => format!("({}.{})", field.table, field.name),
+        ExprNode::Field(field) => format!("({})", dialect.field(field)),
         ExprNode::Int { value } => format!("({})", value),
+        ExprNode::Aggregate(a) => format!("({})", create_sql_agg(a, dialect)),
+        ExprNode::Add { left, right } => create_sql_arith(left, "+", right, dialect),
+        ExprNode::Sub { left, right } => create_sql_arith(left, "-", right, dialect),
+        ExprNode::Mul { left, right } => create_sql_arith(left, "*", right, dialect),
+        // Dividing integers truncates in SQLite, which MySQL only does with DIV.
+        ExprNode::Div { left, right } if dialect == Dialect::MySql => {
+            create_sql_arith(left, "DIV", right, dialect)
+        }
+        ExprNode::Div { left, right } => create_sql_arith(left, "/", right, dialect),
+        ExprNode::Mod { left, right } => create_sql_arith(left, "%", right, dialect),
     }
 }
 
+fn create_sql_agg(a: &AggExpr, dialect: Dialect) -> String {
+    format!("{}({})", a.func, dialect.field(&a.field))
+}
+
+fn create_sql_arith(left: &ExprNode, op: &str, right: &ExprNode, dialect: Dialect) -> String {
+    format!(
+        "({} {} {})",
+        create_sql_expr(left, dialect),
+        op,
+        create_sql_expr(right, dialect)
+    )
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::types::Field;
-    use std::rc::Rc;
+    use std::sync::Arc;
 
     #[test]
     fn test_create_table() {
@@ -199,19 +735,23 @@ mod tests {
             ConcTable {
                 name: String::from("t1"),
                 columns: vec![String::from("a"), String::from("b")],
-                values: vec![vec![1, 2], vec![3, 4], vec![5, 6]],
+                values: vec![
+                    vec![1.into(), 2.into()],
+                    vec![3.into(), 4.into()],
+                    vec![5.into(), 6.into()],
+                ],
             },
             ConcTable {
                 name: String::from("t2"),
                 columns: vec![String::from("a"), String::from("b")],
-                values: vec![vec![1, 2], vec![3, 4]],
+                values: vec![vec![1.into(), 2.into()], vec![3.into(), 4.into()]],
             },
         ];
 
         let expected_output = ConcTable {
             name: String::from("t1"),
             columns: vec![String::from("a"), String::from("b")],
-            values: vec![vec![1, 2], vec![3, 4]],
+            values: vec![vec![1.into(), 2.into()], vec![3.into(), 4.into()]],
         };
 
         let conn = create_table(&example_input).unwrap();
@@ -234,38 +774,126 @@ mod tests {
         assert_eq!(row.get::<_, isize>(1), Ok(4));
     }
 
+    #[test]
+    fn test_sqlite_evaluator_threads() {
+        use rayon::prelude::*;
+
+        let input = vec![ConcTable {
+            name: String::from("t"),
+            columns: vec![String::from("a")],
+            values: (0..50).map(|n| vec![n.into()]).collect(),
+        }];
+        let evaluator = SqliteEvaluator::new(&input).unwrap();
+        let query = |n: isize| AST::Select {
+            fields: None,
+            distinct: false,
+            table: Box::new(AST::Table {
+                name: String::from("t"),
+                columns: vec![String::from("a")],
+                alias: String::from("t"),
+            }),
+            pred: PredNode::Lt {
+                left: ExprNode::Field(Field {
+                    table: String::from("t"),
+                    name: String::from("a"),
+                }),
+                right: ExprNode::Int { value: n },
+            },
+            alias: String::from("q1"),
+        };
+        // Threads evaluating queries at the same time each get a connection of their own, and
+        // all of them see the same tables.
+        let counts: Vec<_> = (0..200)
+            .into_par_iter()
+            .map(|n| evaluator.eval(&query(n % 50)).unwrap().values.len())
+            .collect();
+        assert_eq!(counts, (0..200).map(|n| n % 50).collect::<Vec<_>>());
+        assert!(matches!(
+            SqliteEvaluator::new(&[ConcTable {
+                name: String::from("sqlite_t"),
+                columns: vec![String::from("a")],
+                values: Vec::new(),
+            }]),
+            Err(CreateTableError::InvalidName { .. })
+        ));
+    }
+
+    #[test]
+    fn test_create_table_names() {
+        let table = |name: &str, columns: &[&str]| ConcTable {
+            name: String::from(name),
+            columns: columns.iter().map(|c| c.to_string()).collect(),
+            values: vec![vec![1.into(); columns.len()]],
+        };
+        // Keywords and punctuation are quoted rather than read as SQL.
+        let input = vec![table("t; DROP TABLE t", &["order", "a \"b\""])];
+        let conn = create_table(&input).unwrap();
+        let query = AST::Table {
+            name: input[0].name.clone(),
+            columns: input[0].columns.clone(),
+            alias: input[0].name.clone(),
+        };
+        let output = eval(&query, &conn).unwrap();
+        assert_eq!(output.columns, input[0].columns);
+        assert_eq!(output.values, input[0].values);
+
+        for input in [
+            vec![table("", &["a"])],
+            vec![table("t", &["a\0"])],
+            vec![table("t", &["a", "A"])],
+            vec![table("t", &["a"]), table("T", &["a"])],
+            vec![table("sqlite_master", &["a"])],
+        ] {
+            assert!(matches!(
+                create_table(&input),
+                Err(CreateTableError::InvalidName { .. })
+            ));
+        }
+    }
+
     #[test]
     fn test_create_basic_sql_query() {
         let query = AST::Select {
             fields: None,
+            distinct: false,
             table: Box::new(AST::Table {
                 name: String::from("t1"),
                 columns: vec![String::from("a"), String::from("b")],
+                alias: String::from("t1"),
             }),
             pred: PredNode::True,
+            alias: String::from("q1"),
         };
 
-        let expected = String::from("SELECT * FROM t1 WHERE true");
+        let expected = String::from("SELECT * FROM \"t1\"");
+        assert_eq!(create_sql_query(&query), expected);
     }
 
     #[test]
     fn test_create_large_sql_query() {
         let query = AST::Join {
             fields: None,
+            distinct: false,
+            kind: JoinKind::Inner,
             table1: Box::new(AST::Select {
-                fields: Some(Rc::from(vec![
-                    Field {
-                        name: String::from("id"),
-                        table: String::from("users"),
-                    },
-                    Field {
-                        name: String::from("role_id"),
-                        table: String::from("users"),
-                    },
-                ])),
+                fields: Some(Arc::from(
+                    [
+                        Field {
+                            name: String::from("id"),
+                            table: String::from("users"),
+                        },
+                        Field {
+                            name: String::from("role_id"),
+                            table: String::from("users"),
+                        },
+                    ]
+                    .map(SelectItem::from),
+                )),
+                distinct: false,
                 table: Box::new(AST::Table {
                     name: String::from("users"),
                     columns: vec![String::from("id"), String::from("role_id")],
+                    alias: String::from("users"),
                 }),
                 pred: PredNode::And {
                     left: Box::new(PredNode::Lt {
@@ -283,21 +911,27 @@ mod tests {
                         right: ExprNode::Int { value: 1 },
                     }),
                 },
+                alias: String::from("employees"),
             }),
             table2: Box::new(AST::Select {
-                fields: Some(Rc::from(vec![
-                    Field {
-                        name: String::from("id"),
-                        table: String::from("users"),
-                    },
-                    Field {
-                        name: String::from("role_id"),
-                        table: String::from("users"),
-                    },
-                ])),
+                fields: Some(Arc::from(
+                    [
+                        Field {
+                            name: String::from("id"),
+                            table: String::from("users"),
+                        },
+                        Field {
+                            name: String::from("role_id"),
+                            table: String::from("users"),
+                        },
+                    ]
+                    .map(SelectItem::from),
+                )),
+                distinct: false,
                 table: Box::new(AST::Table {
                     name: String::from("users"),
                     columns: vec![String::from("id"), String::from("role_id")],
+                    alias: String::from("users"),
                 }),
                 pred: PredNode::And {
                     left: Box::new(PredNode::Lt {
@@ -315,20 +949,393 @@ mod tests {
                         right: ExprNode::Int { value: 2 },
                     }),
                 },
+                alias: String::from("managers"),
             }),
             pred: PredNode::Eq {
                 left: ExprNode::Field(Field {
-                    table: String::from("users"),
+                    table: String::from("employees"),
                     name: String::from("id"),
                 }),
                 right: ExprNode::Field(Field {
-                    table: String::from("users"),
+                    table: String::from("managers"),
                     name: String::from("id"),
                 }),
             },
+            alias: String::from("q1"),
+        };
+
+        insta::assert_debug_snapshot!(create_sql_query(&query), @r###""SELECT * FROM (SELECT \"users\".\"id\", \"users\".\"role_id\" FROM \"users\" WHERE (((\"users\".\"id\") < (10)) AND ((\"users\".\"role_id\") = (1)))) AS \"employees\" JOIN (SELECT \"users\".\"id\", \"users\".\"role_id\" FROM \"users\" WHERE (((\"users\".\"id\") < (10)) AND ((\"users\".\"role_id\") = (2)))) AS \"managers\" ON ((\"employees\".\"id\") = (\"managers\".\"id\"))""###);
+    }
+
+    #[test]
+    fn test_create_sql_pred_disjunction() {
+        let pred = PredNode::Or {
+            left: Box::new(PredNode::Not {
+                pred: Box::new(PredNode::Lt {
+                    left: ExprNode::Field(Field {
+                        table: String::from("t1"),
+                        name: String::from("a"),
+                    }),
+                    right: ExprNode::Int { value: 3 },
+                }),
+            }),
+            right: Box::new(PredNode::Eq {
+                left: ExprNode::Field(Field {
+                    table: String::from("t1"),
+                    name: String::from("b"),
+                }),
+                right: ExprNode::Int { value: 4 },
+            }),
+        };
+
+        insta::assert_debug_snapshot!(create_sql_pred(&pred, Mode::Display(Dialect::Sqlite)), @r###""((NOT ((\"t1\".\"a\") < (3))) OR ((\"t1\".\"b\") = (4)))""###);
+    }
+
+    #[test]
+    fn test_create_aggregate_sql_query() {
+        let table = AST::Table {
+            name: String::from("employees"),
+            columns: vec![String::from("id"), String::from("dept")],
+            alias: String::from("employees"),
+        };
+        let query = AST::Aggregate {
+            group_by: Arc::from(vec![Field {
+                name: String::from("dept"),
+                table: String::from("employees"),
+            }]),
+            aggregates: Arc::from(vec![AggExpr {
+                func: AggFunc::Count,
+                field: Field {
+                    name: String::from("id"),
+                    table: String::from("employees"),
+                },
+            }]),
+            table: Box::new(table),
+            having: PredNode::True,
+            alias: String::from("q1"),
+        };
+
+        insta::assert_debug_snapshot!(create_sql_query(&query), @r###""SELECT \"employees\".\"dept\", COUNT(\"employees\".\"id\") AS \"COUNT(id)\" FROM \"employees\" GROUP BY \"employees\".\"dept\"""###);
+    }
+
+    #[test]
+    fn test_eval_outer_join() {
+        let example_input = vec![
+            ConcTable {
+                name: String::from("t1"),
+                columns: vec![String::from("a")],
+                values: vec![vec![1.into()], vec![2.into()]],
+            },
+            ConcTable {
+                name: String::from("t2"),
+                columns: vec![String::from("b")],
+                values: vec![vec![1.into()], vec![3.into()]],
+            },
+        ];
+        let table = |name: &str, column: &str| AST::Table {
+            name: String::from(name),
+            columns: vec![String::from(column)],
+            alias: String::from(name),
+        };
+        let field = |name: &str, table: &str| {
+            ExprNode::Field(Field {
+                name: String::from(name),
+                table: String::from(table),
+            })
+        };
+        let query = AST::Join {
+            fields: None,
+            distinct: false,
+            kind: JoinKind::Full,
+            table1: Box::new(table("t1", "a")),
+            table2: Box::new(table("t2", "b")),
+            pred: PredNode::Eq {
+                left: field("a", "t1"),
+                right: field("b", "t2"),
+            },
+            alias: String::from("q1"),
+        };
+        insta::assert_debug_snapshot!(create_sql_query(&query), @r###""SELECT * FROM \"t1\" FULL JOIN \"t2\" ON ((\"t1\".\"a\") = (\"t2\".\"b\"))""###);
+
+        let conn = create_table(&example_input).unwrap();
+        assert_eq!(
+            eval(&query, &conn).unwrap().values,
+            vec![
+                vec![1.into(), 1.into()],
+                vec![2.into(), Value::Null],
+                vec![Value::Null, 3.into()],
+            ]
+        );
+        // MySQL's stand-in for a full join is plain enough for SQLite to check it.
+        let mysql = create_dialect_sql_query(&query, Dialect::MySql);
+        assert_eq!(
+            run_query(&mysql, &conn).unwrap().values,
+            eval(&query, &conn).unwrap().values
+        );
+        // Abstractly, the cross product comes first, followed by every row padded with NULLs.
+        let query = AST::Join {
+            fields: None,
+            distinct: false,
+            kind: JoinKind::Full,
+            table1: Box::new(AST::Table {
+                name: String::from("t1"),
+                columns: vec![String::from("a")],
+                alias: String::from("t1"),
+            }),
+            table2: Box::new(AST::Table {
+                name: String::from("t2"),
+                columns: vec![String::from("b")],
+                alias: String::from("t2"),
+            }),
+            pred: (),
+            alias: String::from("q1"),
+        };
+        assert_eq!(
+            eval_abstract(&query, &conn).unwrap().values[4..],
+            vec![
+                vec![1.into(), Value::Null],
+                vec![2.into(), Value::Null],
+                vec![Value::Null, 1.into()],
+                vec![Value::Null, 3.into()],
+            ]
+        );
+    }
+
+    #[test]
+    fn test_create_dialect_sql_query() {
+        let table = |name: &str, column: &str| AST::Table {
+            name: String::from(name),
+            columns: vec![String::from(column)],
+            alias: String::from(name),
+        };
+        let field = |name: &str, table: &str| {
+            ExprNode::Field(Field {
+                name: String::from(name),
+                table: String::from(table),
+            })
+        };
+        let concat = AST::Concat {
+            table1: Box::new(table("t1", "a")),
+            table2: Box::new(table("t2", "b")),
+            alias: String::from("q1"),
+        };
+        let limit = AST::Limit {
+            count: 3,
+            table: Box::new(concat),
+            alias: String::from("q2"),
+        };
+        let top = AST::Limit {
+            count: 3,
+            table: Box::new(AST::OrderBy {
+                keys: Arc::from(vec![SortKey {
+                    field: Field {
+                        name: String::from("a"),
+                        table: String::from("t1"),
+                    },
+                    dir: SortDir::Desc,
+                }]),
+                table: Box::new(table("t1", "a")),
+                alias: String::from("q1"),
+            }),
+            alias: String::from("q2"),
+        };
+        let join = AST::Join {
+            fields: Some(Arc::from(vec![SelectItem {
+                expr: ExprNode::Div {
+                    left: Box::new(field("a", "t1")),
+                    right: Box::new(field("b", "t2")),
+                },
+                alias: Some(String::from("a over b")),
+            }])),
+            distinct: false,
+            kind: JoinKind::Full,
+            table1: Box::new(table("t1", "a")),
+            table2: Box::new(table("t2", "b")),
+            pred: PredNode::True,
+            alias: String::from("q1"),
+        };
+
+        insta::assert_debug_snapshot!(create_dialect_sql_query(&limit, Dialect::Sqlite), @r###""SELECT * FROM (SELECT * FROM \"t1\", \"t2\") AS \"q1\" LIMIT 3""###);
+        insta::assert_debug_snapshot!(create_dialect_sql_query(&limit, Dialect::Postgres), @r###""SELECT * FROM (SELECT * FROM \"t1\" CROSS JOIN \"t2\") AS \"q1\" LIMIT 3""###);
+        insta::assert_debug_snapshot!(create_dialect_sql_query(&limit, Dialect::Ansi), @r###""SELECT * FROM (SELECT * FROM \"t1\" CROSS JOIN \"t2\") AS \"q1\" FETCH FIRST 3 ROWS ONLY""###);
+        // Derived tables don't keep their order, so the top rows are picked by one statement.
+        insta::assert_debug_snapshot!(create_dialect_sql_query(&top, Dialect::Sqlite), @r###""SELECT * FROM \"t1\" ORDER BY \"t1\".\"a\" DESC LIMIT 3""###);
+        insta::assert_debug_snapshot!(create_dialect_sql_query(&top, Dialect::MySql), @r###""SELECT * FROM `t1` ORDER BY `t1`.`a` DESC LIMIT 3""###);
+        insta::assert_debug_snapshot!(create_dialect_sql_query(&top, Dialect::Ansi), @r###""SELECT * FROM \"t1\" ORDER BY \"t1\".\"a\" DESC FETCH FIRST 3 ROWS ONLY""###);
+        insta::assert_debug_snapshot!(create_dialect_sql_query(&join, Dialect::Sqlite), @r###""SELECT ((\"t1\".\"a\") / (\"t2\".\"b\")) AS \"a over b\" FROM \"t1\" FULL JOIN \"t2\" ON 1""###);
+        insta::assert_debug_snapshot!(create_dialect_sql_query(&join, Dialect::Postgres), @r###""SELECT ((\"t1\".\"a\") / (\"t2\".\"b\")) AS \"a over b\" FROM \"t1\" FULL JOIN \"t2\" ON TRUE""###);
+        insta::assert_debug_snapshot!(create_dialect_sql_query(&join, Dialect::MySql), @r###""SELECT ((`t1`.`a`) DIV (`t2`.`b`)) AS `a over b` FROM `t1` LEFT JOIN `t2` ON TRUE UNION ALL SELECT ((`t1`.`a`) DIV (`t2`.`b`)) AS `a over b` FROM `t1` RIGHT JOIN `t2` ON TRUE WHERE NOT EXISTS (SELECT 1 FROM `t1` WHERE TRUE)""###);
+    }
+
+    #[test]
+    fn test_create_distinct_sql_query() {
+        let query = AST::Select {
+            fields: Some(Arc::from(
+                [Field {
+                    name: String::from("dept"),
+                    table: String::from("employees"),
+                }]
+                .map(SelectItem::from),
+            )),
+            distinct: true,
+            table: Box::new(AST::Table {
+                name: String::from("employees"),
+                columns: vec![String::from("id"), String::from("dept")],
+                alias: String::from("employees"),
+            }),
+            pred: PredNode::True,
+            alias: String::from("q1"),
+        };
+        insta::assert_debug_snapshot!(create_sql_query(&query), @r###""SELECT DISTINCT \"employees\".\"dept\" FROM \"employees\"""###);
+    }
+
+    #[test]
+    fn test_create_computed_sql_query() {
+        let field = |name: &str| {
+            ExprNode::Field(Field {
+                name: String::from(name),
+                table: String::from("orders"),
+            })
+        };
+        let query = AST::Select {
+            fields: Some(Arc::from(vec![
+                SelectItem {
+                    expr: field("id"),
+                    alias: Some(String::from("order_id")),
+                },
+                SelectItem {
+                    expr: ExprNode::Mul {
+                        left: Box::new(field("price")),
+                        right: Box::new(field("qty")),
+                    },
+                    alias: Some(String::from("total")),
+                },
+            ])),
+            distinct: false,
+            table: Box::new(AST::Table {
+                name: String::from("orders"),
+                columns: vec![
+                    String::from("id"),
+                    String::from("price"),
+                    String::from("qty"),
+                ],
+                alias: String::from("orders"),
+            }),
+            pred: PredNode::True,
+            alias: String::from("q1"),
+        };
+        insta::assert_debug_snapshot!(create_sql_query(&query), @r###""SELECT (\"orders\".\"id\") AS \"order_id\", ((\"orders\".\"price\") * (\"orders\".\"qty\")) AS \"total\" FROM \"orders\"""###);
+    }
+
+    #[test]
+    fn test_create_set_op_sql_query() {
+        let input = vec![
+            ConcTable {
+                name: String::from("a"),
+                columns: vec![String::from("id")],
+                values: vec![
+                    vec![1.into()],
+                    vec![1.into()],
+                    vec![1.into()],
+                    vec![2.into()],
+                ],
+            },
+            ConcTable {
+                name: String::from("b"),
+                columns: vec![String::from("id")],
+                values: vec![vec![1.into()], vec![3.into()]],
+            },
+        ];
+        let table = |name: &str| AST::Table {
+            name: String::from(name),
+            columns: vec![String::from("id")],
+            alias: String::from(name),
+        };
+        let conn = create_table(&input).unwrap();
+        // SQLite doesn't support INTERSECT ALL or EXCEPT ALL, so what's printed for it has to
+        // be rewritten for it to run at all.
+        for (op, expected) in [
+            (SetOp::Intersect, vec![vec![1.into()]]),
+            (
+                SetOp::Except,
+                vec![vec![1.into()], vec![1.into()], vec![2.into()]],
+            ),
+        ] {
+            let query = AST::SetOp {
+                op,
+                all: true,
+                table1: Box::new(table("a")),
+                table2: Box::new(table("b")),
+                alias: String::from("q1"),
+            };
+            let sql = create_sql_query(&query);
+            let mut rows = run_query(&sql, &conn).unwrap().values;
+            rows.sort();
+            assert_eq!(rows, expected, "{}", sql);
+        }
+    }
+
+    #[test]
+    fn test_emulated_set_op() {
+        let input = vec![
+            ConcTable {
+                name: String::from("a"),
+                columns: vec![String::from("x"), String::from("y")],
+                values: vec![
+                    vec![1.into(), Value::Null],
+                    vec![1.into(), Value::Null],
+                    vec![1.into(), Value::Null],
+                    vec![2.into(), 3.into()],
+                    vec![4.into(), 5.into()],
+                ],
+            },
+            ConcTable {
+                name: String::from("b"),
+                columns: vec![String::from("x"), String::from("y")],
+                values: vec![
+                    vec![1.into(), Value::Null],
+                    vec![2.into(), 3.into()],
+                    vec![2.into(), 3.into()],
+                ],
+            },
+        ];
+        let table = |name: &str| AST::Table {
+            name: String::from(name),
+            columns: vec![String::from("x"), String::from("y")],
+            alias: String::from(name),
         };
+        let conn = create_table(&input).unwrap();
+        // The emulation for MySQL runs in SQLite too, where it has to agree with SQLite's own
+        // set operations.
+        for op in [SetOp::Intersect, SetOp::Except] {
+            for all in [false, true] {
+                let query = AST::SetOp {
+                    op,
+                    all,
+                    table1: Box::new(table("a")),
+                    table2: Box::new(table("b")),
+                    alias: String::from("q1"),
+                };
+                let sql =
+                    create_emulated_set_op(op, all, "\"a\"", "\"b\"", 2, "q1", Dialect::Sqlite);
+                let emulated = run_query(&sql, &conn).unwrap();
+                let mut expected = eval(&query, &conn).unwrap();
+                let mut rows = emulated.values;
+                rows.sort();
+                expected.values.sort();
+                assert_eq!(emulated.columns, expected.columns, "{}", sql);
+                assert_eq!(rows, expected.values, "{}", sql);
+            }
+        }
 
-        insta::assert_debug_snapshot!(create_sql_query(&query), @r###""(SELECT * FROM (SELECT id, role_id FROM (users) WHERE (((users.id) < (10)) AND ((users.role_id) = (1)))) JOIN (SELECT id, role_id FROM (users) WHERE (((users.id) < (10)) AND ((users.role_id) = (2)))) ON ((users.id) = (users.id)))""###);
+        let query = AST::SetOp {
+            op: SetOp::Intersect,
+            all: true,
+            table1: Box::new(table("a")),
+            table2: Box::new(table("b")),
+            alias: String::from("q1"),
+        };
+        insta::assert_debug_snapshot!(create_dialect_sql_query(&query, Dialect::MySql), @r###""SELECT * FROM `a` WHERE FALSE UNION ALL SELECT * FROM (WITH l(c0, c1) AS (SELECT * FROM `a`), r(c0, c1) AS (SELECT * FROM `b`) SELECT ln.c0, ln.c1 FROM (SELECT *, ROW_NUMBER() OVER (PARTITION BY c0, c1) AS n FROM l) AS ln WHERE EXISTS (SELECT 1 FROM (SELECT *, ROW_NUMBER() OVER (PARTITION BY c0, c1) AS n FROM r) AS rn WHERE ln.c0 <=> rn.c0 AND ln.c1 <=> rn.c1 AND ln.n = rn.n)) AS `q1`""###);
     }
 
     #[test]
@@ -337,32 +1344,160 @@ mod tests {
             ConcTable {
                 name: String::from("t1"),
                 columns: vec![String::from("a"), String::from("b")],
-                values: vec![vec![1, 2], vec![3, 4]],
+                values: vec![vec![1.into(), 2.into()], vec![3.into(), 4.into()]],
             },
             ConcTable {
                 name: String::from("t2"),
                 columns: vec![String::from("a"), String::from("b")],
-                values: vec![vec![1, 2], vec![5, 6]],
+                values: vec![vec![1.into(), 2.into()], vec![5.into(), 6.into()]],
             },
         ];
 
         let expected_output = ConcTable {
             name: String::from(""),
             columns: vec![String::from("a"), String::from("b")],
-            values: vec![vec![1, 2], vec![3, 4]],
+            values: vec![vec![1.into(), 2.into()], vec![3.into(), 4.into()]],
         };
 
         let query = AST::Select {
             fields: None,
+            distinct: false,
             table: Box::new(AST::Table {
                 name: String::from("t1"),
                 columns: vec![String::from("a"), String::from("b")],
+                alias: String::from("t1"),
             }),
             pred: PredNode::True,
+            alias: String::from("q1"),
         };
 
         let conn = create_table(&example_input).unwrap();
         let table = eval(&query, &conn).unwrap();
         assert_eq!(table, expected_output);
     }
+
+    #[test]
+    fn test_eval_typed_values() {
+        let example_input = vec![ConcTable {
+            name: String::from("customers"),
+            columns: vec![
+                String::from("name"),
+                String::from("balance"),
+                String::from("referrer"),
+            ],
+            values: vec![
+                vec!["alice".into(), 10.5.into(), Value::Null],
+                vec!["bob".into(), Value::Null, 1.into()],
+            ],
+        }];
+
+        let query = AST::Select {
+            fields: None,
+            distinct: false,
+            table: Box::new(AST::Table {
+                name: String::from("customers"),
+                columns: example_input[0].columns.clone(),
+                alias: String::from("customers"),
+            }),
+            pred: PredNode::True,
+            alias: String::from("q1"),
+        };
+
+        let conn = create_table(&example_input).unwrap();
+        let table = eval(&query, &conn).unwrap();
+        assert_eq!(table.values, example_input[0].values);
+    }
+
+    #[test]
+    fn test_arithmetic_matches_sqlite() {
+        let conn = Connection::open_in_memory().unwrap();
+        let values = [
+            Value::Null,
+            Value::Integer(7),
+            Value::Integer(-2),
+            Value::Integer(0),
+            Value::Integer(isize::MAX),
+            Value::Integer(isize::MIN),
+            Value::Integer(-1),
+            Value::Real(2.5),
+            Value::Real(0.0),
+            Value::from("12"),
+            Value::from("abc"),
+            Value::from("12abc"),
+            Value::from(" 2.5e1x"),
+            Value::from("-.5"),
+            Value::from("3e"),
+            Value::from("0x10"),
+            Value::from("inf"),
+            Value::from("nan"),
+            Value::from("99999999999999999999x"),
+        ];
+        type Op = fn(&Value, &Value) -> Value;
+        let ops: [(&str, Op); 5] = [
+            ("+", Value::sql_add),
+            ("-", Value::sql_sub),
+            ("*", Value::sql_mul),
+            ("/", Value::sql_div),
+            ("%", Value::sql_rem),
+        ];
+        for (op, f) in ops {
+            let sql = format!("SELECT ?1 {} ?2", op);
+            for a in values.iter() {
+                for b in values.iter() {
+                    let expected: Value = conn
+                        .query_row(&sql, params![a, b], |row| row.get(0))
+                        .unwrap();
+                    assert_eq!(f(a, b), expected, "{} {} {}", a, op, b);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_in_matches_sqlite() {
+        let values = [
+            Value::Null,
+            Value::Integer(1),
+            Value::Integer(2),
+            Value::Real(1.0),
+        ];
+        let subquery_rows: [&[Value]; 4] = [
+            &[],
+            &[Value::Integer(1)],
+            &[Value::Integer(1), Value::Null],
+            &[Value::Integer(2)],
+        ];
+        for rows in subquery_rows {
+            let input = vec![ConcTable {
+                name: String::from("s"),
+                columns: vec![String::from("b")],
+                values: rows.iter().map(|v| vec![v.clone()]).collect(),
+            }];
+            let conn = create_table(&input).unwrap();
+            let query = AST::Select {
+                fields: None,
+                distinct: false,
+                table: Box::new(AST::Table {
+                    name: String::from("s"),
+                    columns: vec![String::from("b")],
+                    alias: String::from("s"),
+                }),
+                pred: PredNode::True,
+                alias: String::from("q1"),
+            };
+            let subquery = Subquery {
+                rows: eval(&query, &conn).unwrap().values,
+                query,
+                scope: None,
+            };
+            for v in values.iter() {
+                let expected: Option<bool> = conn
+                    .query_row("SELECT ?1 IN (SELECT b FROM s)", params![v], |row| {
+                        row.get(0)
+                    })
+                    .unwrap();
+                assert_eq!(subquery.contains(v), expected, "{} IN {:?}", v, rows);
+            }
+        }
+    }
 }
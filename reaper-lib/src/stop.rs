@@ -0,0 +1,91 @@
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+/// Why synthesis stopped before it was done.
+#[derive(Copy, Clone, Error, Debug, PartialEq, Eq)]
+pub enum Stopped {
+    #[error("synthesis was cancelled")]
+    Cancelled,
+    #[error("synthesis ran past its deadline")]
+    TimedOut,
+}
+
+/// A [Stopper] tells synthesis when to give up, either because it's been cancelled or because
+/// its deadline has passed. Clones share the same cancellation, so any of them can cancel the
+/// synthesis the others are checking.
+///
+/// Stopping is cooperative: synthesis checks its [Stopper] between steps, so it can run a
+/// little past its deadline before it notices.
+#[derive(Clone, Debug, Default)]
+pub struct Stopper {
+    cancelled: Arc<AtomicBool>,
+    deadline: Option<Instant>,
+}
+
+impl Stopper {
+    /// [Stopper::new()] returns a [Stopper] that only stops once it's cancelled.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// [Stopper::with_timeout(timeout)] returns a [Stopper] that stops once [timeout] has
+    /// passed, or once it's cancelled. A [timeout] too long to represent as a deadline never
+    /// passes.
+    pub fn with_timeout(timeout: Duration) -> Self {
+        Stopper {
+            cancelled: Arc::default(),
+            deadline: Instant::now().checked_add(timeout),
+        }
+    }
+
+    /// [s.cancel()] stops [s] and all of its clones.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    /// [s.check()] returns why [s] has stopped, if it has. Cancellation takes precedence over
+    /// the deadline.
+    pub fn check(&self) -> Result<(), Stopped> {
+        if self.cancelled.load(Ordering::SeqCst) {
+            Err(Stopped::Cancelled)
+        } else if self
+            .deadline
+            .is_some_and(|deadline| Instant::now() >= deadline)
+        {
+            Err(Stopped::TimedOut)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// [s.is_stopped()] returns whether [s] has stopped for any reason.
+    pub fn is_stopped(&self) -> bool {
+        self.check().is_err()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stopper() {
+        let stopper = Stopper::new();
+        assert_eq!(stopper.check(), Ok(()));
+        let clone = stopper.clone();
+        clone.cancel();
+        assert_eq!(stopper.check(), Err(Stopped::Cancelled));
+
+        let stopper = Stopper::with_timeout(Duration::ZERO);
+        assert_eq!(stopper.check(), Err(Stopped::TimedOut));
+        stopper.cancel();
+        assert_eq!(stopper.check(), Err(Stopped::Cancelled));
+
+        assert!(!Stopper::with_timeout(Duration::from_secs(3600)).is_stopped());
+        assert!(!Stopper::with_timeout(Duration::from_secs(u64::MAX)).is_stopped());
+    }
+}
@@ -1,11 +1,9 @@
 use bitvec::prelude as bv;
-use std::{
-    collections::HashMap,
-    rc::Rc,
-    sync::atomic::{AtomicBool, Ordering},
-};
+use std::{collections::HashMap, sync::Arc};
 use thiserror::Error;
 
+use crate::bvdfs::TruthVector;
+use crate::eval::compile_pred;
 use crate::types::*;
 
 #[derive(Copy, Clone, Error, Debug)]
@@ -71,25 +69,17 @@ impl<'a> IntermediateTable<'a> {
 // NOTE: if &= ends up being useful, figure out how to implement it. The overloads for
 // BitVec don't seem that helpful.
 
-/// [run_unless_stopped] runs [f] unless [stopper] is set.
-///
-/// It's recommended that [f] itself uses [run_unless_stopped] during different phases of
-/// expensive computation to ensure that the request to stop is respected as best as possible.
-fn run_unless_stopped<T>(f: impl FnOnce() -> T, stopper: &AtomicBool) -> Option<T> {
-    match stopper.load(Ordering::SeqCst) {
-        true => None,
-        false => Some(f()),
-    }
-}
-
 impl AST<()> {
     /// [t.num_holes()] returns the number of holes in the AST.
     pub(crate) fn num_holes(&self) -> usize {
         match self {
             AST::Select { table, .. } => table.num_holes() + 1,
             AST::Join { table1, table2, .. } => table1.num_holes() + table2.num_holes() + 1,
-            AST::Concat { table1, table2 } => table1.num_holes() + table2.num_holes(),
+            AST::Concat { table1, table2, .. } => table1.num_holes() + table2.num_holes(),
             AST::Table { .. } => 0,
+            AST::Aggregate { table, .. } => table.num_holes() + 1,
+            AST::OrderBy { table, .. } | AST::Limit { table, .. } => table.num_holes(),
+            AST::SetOp { table1, table2, .. } => table1.num_holes() + table2.num_holes(),
         }
     }
 
@@ -100,53 +90,139 @@ impl AST<()> {
         match self {
             AST::Select {
                 fields,
+                distinct,
                 table,
                 pred: _,
+                alias,
             } => {
                 let (pred, predicates) = predicates.split_first().ok_or(())?;
                 let (table, predicates) = table.with_predicates_aux(predicates)?;
                 Ok((
                     AST::Select {
-                        fields: fields.as_ref().map(Rc::clone),
+                        fields: fields.as_ref().map(Arc::clone),
+                        distinct: *distinct,
                         table: Box::new(table),
                         pred: pred.clone(),
+                        alias: alias.clone(),
                     },
                     predicates,
                 ))
             }
             AST::Join {
                 fields,
+                distinct,
+                kind,
                 table1,
                 table2,
                 pred: _,
+                alias,
             } => {
                 let (pred, predicates) = predicates.split_first().ok_or(())?;
                 let (table1, predicates) = table1.with_predicates_aux(predicates)?;
                 let (table2, predicates) = table2.with_predicates_aux(predicates)?;
                 Ok((
                     AST::Join {
-                        fields: fields.as_ref().map(Rc::clone),
+                        fields: fields.as_ref().map(Arc::clone),
+                        distinct: *distinct,
+                        kind: *kind,
                         table1: Box::new(table1),
                         table2: Box::new(table2),
                         pred: pred.clone(),
+                        alias: alias.clone(),
                     },
                     predicates,
                 ))
             }
-            AST::Table { name, columns } => Ok((
+            AST::Table {
+                name,
+                columns,
+                alias,
+            } => Ok((
                 AST::Table {
                     name: name.clone(),
                     columns: columns.clone(),
+                    alias: alias.clone(),
                 },
                 predicates,
             )),
-            AST::Concat { table1, table2 } => {
+            AST::Concat {
+                table1,
+                table2,
+                alias,
+            } => {
                 let (table1, predicates) = table1.with_predicates_aux(predicates)?;
                 let (table2, predicates) = table2.with_predicates_aux(predicates)?;
                 Ok((
                     AST::Concat {
                         table1: Box::new(table1),
                         table2: Box::new(table2),
+                        alias: alias.clone(),
+                    },
+                    predicates,
+                ))
+            }
+            AST::Aggregate {
+                group_by,
+                aggregates,
+                table,
+                having: _,
+                alias,
+            } => {
+                let (having, predicates) = predicates.split_first().ok_or(())?;
+                let (table, predicates) = table.with_predicates_aux(predicates)?;
+                Ok((
+                    AST::Aggregate {
+                        group_by: Arc::clone(group_by),
+                        aggregates: Arc::clone(aggregates),
+                        table: Box::new(table),
+                        having: having.clone(),
+                        alias: alias.clone(),
+                    },
+                    predicates,
+                ))
+            }
+            AST::OrderBy { keys, table, alias } => {
+                let (table, predicates) = table.with_predicates_aux(predicates)?;
+                Ok((
+                    AST::OrderBy {
+                        keys: Arc::clone(keys),
+                        table: Box::new(table),
+                        alias: alias.clone(),
+                    },
+                    predicates,
+                ))
+            }
+            AST::Limit {
+                count,
+                table,
+                alias,
+            } => {
+                let (table, predicates) = table.with_predicates_aux(predicates)?;
+                Ok((
+                    AST::Limit {
+                        count: *count,
+                        table: Box::new(table),
+                        alias: alias.clone(),
+                    },
+                    predicates,
+                ))
+            }
+            AST::SetOp {
+                op,
+                all,
+                table1,
+                table2,
+                alias,
+            } => {
+                let (table1, predicates) = table1.with_predicates_aux(predicates)?;
+                let (table2, predicates) = table2.with_predicates_aux(predicates)?;
+                Ok((
+                    AST::SetOp {
+                        op: *op,
+                        all: *all,
+                        table1: Box::new(table1),
+                        table2: Box::new(table2),
+                        alias: alias.clone(),
                     },
                     predicates,
                 ))
@@ -170,45 +246,6 @@ impl ConcTable {
     }
 }
 
-// TODO: maybe not strings here?
-struct Environment(HashMap<String, isize>);
-
-impl Environment {
-    fn from_row(table: &ConcTable, i: usize) -> Self {
-        let names = &table.columns;
-        let v = &table.values[i];
-        let map: HashMap<_, _> = names
-            .iter()
-            .map(|name| format!("{}.{}", table.name, name))
-            .zip(v.iter().copied())
-            .collect();
-        Self(map)
-    }
-}
-
-impl ExprNode {
-    fn eval(&self, env: &Environment) -> isize {
-        match self {
-            ExprNode::Field(Field { table: _, name }) => *env
-                .0
-                .get(name)
-                .expect("well-formed predicate implies a value in env"),
-            ExprNode::Int { value } => *value,
-        }
-    }
-}
-
-impl PredNode {
-    fn eval(&self, env: &Environment) -> bool {
-        match self {
-            PredNode::True => true,
-            PredNode::Lt { left, right } => left.eval(env) < right.eval(env),
-            PredNode::Eq { left, right } => left.eval(env) == right.eval(env),
-            PredNode::And { left, right } => left.eval(env) && right.eval(env),
-        }
-    }
-}
-
 fn base_exprs(fields: &[Field], constants: &[isize]) -> Vec<ExprNode> {
     fields
         .iter()
@@ -219,18 +256,13 @@ fn base_exprs(fields: &[Field], constants: &[isize]) -> Vec<ExprNode> {
 
 fn base_preds(fields: &[Field], constants: &[isize]) -> Vec<PredNode> {
     let exprs = base_exprs(fields, constants);
-    let mut new = Vec::with_capacity(exprs.len() * 2);
+    let mut new = Vec::with_capacity(exprs.len() * 6);
     new.push(PredNode::True);
+    // Every comparison is symmetric to one with its operands swapped (a < b iff b > a),
+    // so we only need to compare each expression against itself and those after it.
     for (i, left) in exprs.iter().enumerate() {
         for right in exprs[i..].iter() {
-            new.push(PredNode::Eq {
-                left: left.clone(),
-                right: right.clone(),
-            });
-            new.push(PredNode::Lt {
-                left: left.clone(),
-                right: right.clone(),
-            });
+            new.extend(PredNode::comparisons(left, right));
         }
     }
     new
@@ -241,14 +273,37 @@ fn grow(with: &[PredNode], base_predicates: &[PredNode]) -> Vec<PredNode> {
     for p1 in with.iter().chain(base_predicates.iter()) {
         for p2 in with.iter().chain(base_predicates.iter()).cloned() {
             new.push(PredNode::And {
+                left: Box::new(p1.clone()),
+                right: Box::new(p2.clone()),
+            });
+            new.push(PredNode::Or {
                 left: Box::new(p1.clone()),
                 right: Box::new(p2),
-            })
+            });
         }
         new.push(PredNode::And {
             left: Box::new(p1.clone()),
             right: Box::new(PredNode::True),
         });
+        new.push(PredNode::Not {
+            pred: Box::new(p1.clone()),
+        });
+    }
+    new
+}
+
+/// [grow_vectors(with, base_vectors)] returns the [TruthVector]s of the predicates
+/// [grow(with, base_predicates)] builds, in the same order, given those of [with] and
+/// [base_predicates].
+fn grow_vectors(with: &[TruthVector], base_vectors: &[TruthVector]) -> Vec<TruthVector> {
+    let mut new = Vec::with_capacity(with.len());
+    for v1 in with.iter().chain(base_vectors.iter()) {
+        for v2 in with.iter().chain(base_vectors.iter()) {
+            new.push(v1.and(v2));
+            new.push(v1.or(v2));
+        }
+        new.push(v1.clone());
+        new.push(v1.not());
     }
     new
 }
@@ -263,13 +318,25 @@ fn synthesize(
 ) -> Result<Vec<PredNode>, PredicateSynthesisError> {
     // First, evaluate the abstract query.
     let rows = crate::sql::eval_abstract(query, conn)?;
+    let columns = crate::bottomup::output_fields(query);
     // Now, phrase the concrete table as a bitvector.
     let target_intermediate = target.to_intermediate(&rows);
 
     // TODO: how do we get the space of all fields? I'm assuming it can be passed in as a parameter here, but
     // I think it might depend on our abstract query?
     let base_predicates = base_preds(fields, constants);
+    // Only the base predicates are evaluated row by row. The vectors of those built from
+    // them are computed from their children's with bitwise operations.
+    let base_vectors: Vec<TruthVector> = base_predicates
+        .iter()
+        .map(|pred| {
+            let pred = compile_pred(pred, &columns, &[])
+                .expect("well-formed predicate implies its fields are in scope");
+            rows.values.iter().map(|row| pred.eval(row)).collect()
+        })
+        .collect();
     let mut prior_depth_predicates = vec![PredNode::True];
+    let mut prior_depth_vectors = vec![TruthVector::all(rows.values.len())];
     let mut vec_to_preds = HashMap::new();
     vec_to_preds.insert(bv::bitvec![1; rows.values.len()], vec![PredNode::True]);
     for _depth in 1..max_depth {
@@ -278,35 +345,28 @@ fn synthesize(
         // predicates that, when AND-ed, make the right thing (for example)?
 
         // We don't do elimination here because it'll happen when we construct the new predicates anyways.
-        let mut predicates: HashMap<_, _> = grow(&prior_depth_predicates, &base_predicates)
+        let grown = grow(&prior_depth_predicates, &base_predicates);
+        let grown_vectors = grow_vectors(&prior_depth_vectors, &base_vectors);
+        let mut predicates: HashMap<_, _> = grown
             .into_iter()
-            .map(|pred| {
-                let mut v = bv::bitvec![0; rows.values.len()];
-                // TODO: here, make it possible to compute the new bitvectors w/o doing this computation
-                // by doing bitwise ops. Right now we have to re-evaluate each time by testing the predicate,
-                // which is pretty slow.
-                v.iter_mut().enumerate().for_each(|(i, mut x)| {
-                    let env = Environment::from_row(&rows, i);
-                    *x = pred.eval(&env)
-                });
-                (pred, v)
-            })
-            // v must be a superset of the target rows since we don't have disjunction.
-            .filter(|(_pred, v)| target_intermediate.rows.clone() & v == target_intermediate.rows)
-            .group_by(|(_pred, v)| v.clone())
+            .zip(grown_vectors)
+            // NOTE: now that we have disjunction and negation, any v can contribute to the
+            // target, so we can't prune vectors that aren't supersets of the target rows.
+            .group_by(|(_pred, v)| v.holds().clone())
             .into_iter()
             .map(|(v, pairs)| {
-                let mut pairs: Vec<_> = pairs.map(|(pred, _v)| pred).collect();
+                let mut pairs: Vec<_> = pairs.collect();
                 // TODO: sort pairs by some metric for complexity before popping last.
                 let rep = pairs
                     .pop()
                     .expect("to have a pair, group must be non-empty");
+                let pairs = pairs.into_iter().map(|(pred, _v)| pred).collect::<Vec<_>>();
                 (v, (rep, pairs))
             })
             .collect();
 
         // When we find a predicate that has the right rows, stop and return it.
-        if let Some((rep, mut rest)) = predicates.remove(&target_intermediate.rows) {
+        if let Some(((rep, _rep_vector), mut rest)) = predicates.remove(&target_intermediate.rows) {
             rest.push(rep);
             return Ok(rest);
         }
@@ -314,8 +374,10 @@ fn synthesize(
         // Lastly, if we haven't found matches at this depth, go to the next depth,
         // which can build on these predicates.
         let mut new_prior_depth_predicates = Vec::with_capacity(predicates.iter().len());
-        for (v, (rep, rest)) in predicates.into_iter() {
+        let mut new_prior_depth_vectors = Vec::with_capacity(predicates.iter().len());
+        for (v, ((rep, rep_vector), rest)) in predicates.into_iter() {
             new_prior_depth_predicates.push(rep.clone());
+            new_prior_depth_vectors.push(rep_vector);
             let e = vec_to_preds
                 .entry(v)
                 .or_insert_with(|| Vec::with_capacity(rest.len() + 1));
@@ -324,6 +386,7 @@ fn synthesize(
         }
 
         prior_depth_predicates = new_prior_depth_predicates;
+        prior_depth_vectors = new_prior_depth_vectors;
     }
 
     // Finding nothing doesn't indicate an error, but it does indicate that there might be no
@@ -333,12 +396,11 @@ fn synthesize(
 
 #[cfg(test)]
 mod tests {
-    use std::collections::HashMap;
-
-    use crate::stun::Environment;
-    use crate::types::{ExprNode, Field, PredNode};
+    use crate::bvdfs::TruthVector;
+    use crate::eval::compile_pred;
+    use crate::types::{ConcTable, ExprNode, Field, PredNode, Value};
     use proptest::strategy::Strategy;
-    use std::rc::Rc;
+    use std::sync::Arc;
 
     use super::grow;
 
@@ -365,14 +427,25 @@ mod tests {
             Just(PredNode::True),
             (expr_node(), expr_node()).prop_map(|(left, right)| PredNode::Lt { left, right }),
             (expr_node(), expr_node()).prop_map(|(left, right)| PredNode::Eq { left, right }),
+            (expr_node(), expr_node()).prop_map(|(left, right)| PredNode::Le { left, right }),
+            (expr_node(), expr_node()).prop_map(|(left, right)| PredNode::Gt { left, right }),
+            (expr_node(), expr_node()).prop_map(|(left, right)| PredNode::Ge { left, right }),
+            (expr_node(), expr_node()).prop_map(|(left, right)| PredNode::Ne { left, right }),
         );
         leaf.prop_recursive(8, 256, 10, |inner| {
-            inner.clone().prop_flat_map(move |left| {
-                inner.clone().prop_map(move |right| PredNode::And {
-                    left: Box::new(left.clone()),
-                    right: Box::new(right.clone()),
-                })
-            })
+            prop_oneof!(
+                (inner.clone(), inner.clone()).prop_map(|(left, right)| PredNode::And {
+                    left: Box::new(left),
+                    right: Box::new(right),
+                }),
+                (inner.clone(), inner.clone()).prop_map(|(left, right)| PredNode::Or {
+                    left: Box::new(left),
+                    right: Box::new(right),
+                }),
+                inner.prop_map(|pred| PredNode::Not {
+                    pred: Box::new(pred)
+                }),
+            )
         })
     }
     proptest::proptest! {
@@ -384,8 +457,11 @@ mod tests {
         #[test]
         fn pred_heights_are_strictly_larger_than_children(pred in pred_node()) {
             match &pred {
-                PredNode::Lt { left, right  } | PredNode::Eq { left, right }  => proptest::prop_assert!(left.height() < pred.height() && right.height() < pred.height() ),
-                PredNode::And { left, right } => proptest::prop_assert!(left.height() < pred.height() && right.height() < pred.height() ),
+                PredNode::Lt { left, right  } | PredNode::Eq { left, right } | PredNode::Le { left, right } | PredNode::Gt { left, right } | PredNode::Ge { left, right } | PredNode::Ne { left, right }  => proptest::prop_assert!(left.height() < pred.height() && right.height() < pred.height() ),
+                PredNode::And { left, right } | PredNode::Or { left, right } => proptest::prop_assert!(left.height() < pred.height() && right.height() < pred.height() ),
+                PredNode::Not { pred: inner } => proptest::prop_assert!(inner.height() < pred.height()),
+                PredNode::In { expr, .. } => proptest::prop_assert!(expr.height() < pred.height()),
+                PredNode::Exists { .. } => proptest::prop_assert_eq!(pred.height(), 2),
                 PredNode::True => proptest::prop_assert_eq!(pred.height(), 1),
             }
         }
@@ -431,12 +507,20 @@ mod tests {
         insta::assert_debug_snapshot!(grown);
     }
 
+    /// [truth(p, row)] evaluates [p] on [row], whose columns are [t.a] and [t.b].
+    fn truth(p: &PredNode, row: &[Value]) -> Option<bool> {
+        let fields = ["a", "b"].map(|name| Field {
+            table: String::from("t"),
+            name: String::from(name),
+        });
+        compile_pred(p, &fields, &[])
+            .expect("predicates only reference t.a and t.b")
+            .eval(row)
+    }
+
     #[test]
     fn predicate_equality() {
-        let environment = Environment(HashMap::from([
-            (String::from("a"), 1),
-            (String::from("b"), 2),
-        ]));
+        let row = [Value::Integer(1), Value::Integer(2)];
         let node = PredNode::Eq {
             left: ExprNode::Field(Field {
                 table: String::from("t"),
@@ -444,15 +528,12 @@ mod tests {
             }),
             right: ExprNode::Int { value: 1 },
         };
-        assert!(node.eval(&environment))
+        assert_eq!(truth(&node, &row), Some(true))
     }
 
     #[test]
     fn predicate_inequality() {
-        let environment = Environment(HashMap::from([
-            (String::from("a"), 1),
-            (String::from("b"), 2),
-        ]));
+        let row = [Value::Integer(1), Value::Integer(2)];
         let node = PredNode::Eq {
             left: ExprNode::Field(Field {
                 table: String::from("t"),
@@ -463,15 +544,12 @@ mod tests {
                 name: String::from("b"),
             }),
         };
-        assert!(!node.eval(&environment))
+        assert_eq!(truth(&node, &row), Some(false))
     }
 
     #[test]
     fn predicate_comparison() {
-        let environment = Environment(HashMap::from([
-            (String::from("a"), 1),
-            (String::from("b"), 2),
-        ]));
+        let row = [Value::Integer(1), Value::Integer(2)];
         let node = PredNode::Lt {
             left: ExprNode::Field(Field {
                 table: String::from("t"),
@@ -482,7 +560,113 @@ mod tests {
                 name: String::from("b"),
             }),
         };
-        assert!(node.eval(&environment))
+        assert_eq!(truth(&node, &row), Some(true))
+    }
+
+    #[test]
+    fn predicate_flipped_comparisons() {
+        let row = [Value::Integer(1), Value::Integer(2)];
+        let a = ExprNode::Field(Field {
+            table: String::from("t"),
+            name: String::from("a"),
+        });
+        let b = ExprNode::Field(Field {
+            table: String::from("t"),
+            name: String::from("b"),
+        });
+        let holds: Vec<_> = PredNode::comparisons(&a, &b)
+            .iter()
+            .map(|p| truth(p, &row) == Some(true))
+            .collect();
+        // Eq, Lt, Le, Gt, Ge, Ne
+        assert_eq!(holds, vec![false, true, true, false, false, true]);
+    }
+
+    #[test]
+    fn predicate_comparison_with_null() {
+        let row = [Value::Null, Value::Integer(2)];
+        let lt = PredNode::Lt {
+            left: ExprNode::Field(Field {
+                table: String::from("t"),
+                name: String::from("a"),
+            }),
+            right: ExprNode::Field(Field {
+                table: String::from("t"),
+                name: String::from("b"),
+            }),
+        };
+        let eq = PredNode::Eq {
+            left: ExprNode::Field(Field {
+                table: String::from("t"),
+                name: String::from("a"),
+            }),
+            right: ExprNode::Field(Field {
+                table: String::from("t"),
+                name: String::from("a"),
+            }),
+        };
+        assert_eq!(truth(&lt, &row), None);
+        assert_eq!(truth(&eq, &row), None);
+
+        // NOT of an unknown comparison is still unknown, but OR-ing with a true one isn't.
+        let not_lt = PredNode::Not {
+            pred: Box::new(lt.clone()),
+        };
+        let lt_or_true = PredNode::Or {
+            left: Box::new(lt),
+            right: Box::new(PredNode::True),
+        };
+        assert_eq!(truth(&not_lt, &row), None);
+        assert_eq!(truth(&lt_or_true, &row), Some(true));
+    }
+
+    #[test]
+    fn grown_vectors_match_row_by_row_evaluation() {
+        let fields = [
+            Field {
+                table: String::from("t"),
+                name: String::from("a"),
+            },
+            Field {
+                table: String::from("t"),
+                name: String::from("b"),
+            },
+        ];
+        let rows = ConcTable {
+            name: String::from("t"),
+            columns: vec![String::from("a"), String::from("b")],
+            values: vec![
+                vec![Value::Integer(1), Value::Integer(2)],
+                vec![Value::Null, Value::Integer(1)],
+                vec![Value::Integer(2), Value::Null],
+                vec![Value::Integer(1), Value::Integer(1)],
+            ],
+        };
+        let vector = |pred: &PredNode| -> TruthVector {
+            rows.values.iter().map(|row| truth(pred, row)).collect()
+        };
+
+        let base_preds = super::base_preds(&fields, &[1]);
+        let base_vectors: Vec<_> = base_preds.iter().map(vector).collect();
+        let mut preds = base_preds.clone();
+        let mut vectors = base_vectors.clone();
+        // Growing twice composes vectors that were themselves composed, including negations
+        // of rows that are unknown because of NULLs, which must stay unknown.
+        for _ in 0..2 {
+            let grown = grow(&preds, &base_preds);
+            let grown_vectors = super::grow_vectors(&vectors, &base_vectors);
+            assert_eq!(grown.len(), grown_vectors.len());
+            for (pred, v) in grown.iter().zip(grown_vectors.iter()) {
+                assert_eq!(&vector(pred), v, "{}", pred);
+            }
+            // Keep the next depth small by only growing the negations.
+            (preds, vectors) = grown
+                .into_iter()
+                .zip(grown_vectors)
+                .filter(|(pred, _)| matches!(pred, PredNode::Not { .. }))
+                .take(8)
+                .unzip();
+        }
     }
 
     // TODO: add an insta test that we find the right predicates for a pretty simple example
@@ -1,55 +1,339 @@
 extern crate serde;
 
-use std::{fmt, rc::Rc};
+use std::{borrow::Borrow, cmp::Ordering, collections::HashSet, fmt, hash, sync::Arc};
 
+/// A [Field] is column [name] of the query aliased [table] in the enclosing FROM clause.
 #[derive(Debug, Eq, PartialEq, Clone, Hash)]
 pub struct Field {
     pub name: String,
     pub table: String,
 }
 
+/// An output column of a projection, which computes [expr] and is named [alias] if it's set.
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct SelectItem {
+    pub expr: ExprNode,
+    pub alias: Option<String>,
+}
+
+impl SelectItem {
+    /// [i.column_name()] is the name SQLite gives the output column for [i]. Unnamed fields
+    /// keep their own name, and other unnamed expressions are named after their SQL.
+    pub fn column_name(&self) -> String {
+        match (&self.expr, &self.alias) {
+            (_, Some(alias)) => alias.clone(),
+            (ExprNode::Field(field), None) => field.name.clone(),
+            (expr, None) => crate::sql::create_sql_expr(expr, Dialect::Sqlite),
+        }
+    }
+}
+
+impl From<Field> for SelectItem {
+    fn from(field: Field) -> Self {
+        SelectItem {
+            expr: ExprNode::Field(field),
+            alias: None,
+        }
+    }
+}
+
+/// Every node has an [alias], which is what the queries it's nested in call it in their FROM
+/// clauses, and which the fields they reference it by are qualified with. The children of a
+/// node always have different aliases, so that a table can be joined with itself.
 #[derive(Debug, Eq, PartialEq, Clone)]
 pub enum AST<T> {
+    /// Selects [fields] (or every field, if [None]) from the rows of [table] satisfying
+    /// [pred], removing duplicate rows if [distinct] is set.
     Select {
-        fields: Option<Rc<[Field]>>,
+        fields: Option<Arc<[SelectItem]>>,
+        distinct: bool,
         table: Box<AST<T>>,
         pred: T,
+        alias: String,
     },
     Join {
-        fields: Option<Rc<[Field]>>,
+        fields: Option<Arc<[SelectItem]>>,
+        distinct: bool,
+        kind: JoinKind,
         table1: Box<AST<T>>,
         table2: Box<AST<T>>,
         pred: T,
+        alias: String,
     }, // Note: Rel, Rel can be expressed as select _ from _, _ where True
     Table {
         name: String,
         columns: Vec<String>,
+        alias: String,
     },
     Concat {
         table1: Box<AST<T>>,
         table2: Box<AST<T>>,
+        alias: String,
+    },
+    /// Groups the rows of [table] by [group_by] (or treats them all as one group if it's
+    /// empty), producing the grouping fields followed by one column per aggregate, and
+    /// keeps the groups satisfying [having].
+    Aggregate {
+        group_by: Arc<[Field]>,
+        aggregates: Arc<[AggExpr]>,
+        table: Box<AST<T>>,
+        having: T,
+        alias: String,
+    },
+    OrderBy {
+        keys: Arc<[SortKey]>,
+        table: Box<AST<T>>,
+        alias: String,
+    },
+    Limit {
+        count: usize,
+        table: Box<AST<T>>,
+        alias: String,
+    },
+    /// Combines the rows of [table1] and [table2], which must have the same arity, keeping
+    /// duplicates if [all] is set. The columns are named after those of [table1].
+    SetOp {
+        op: SetOp,
+        all: bool,
+        table1: Box<AST<T>>,
+        table2: Box<AST<T>>,
+        alias: String,
     },
 }
 
-impl AST<PredNode> {
-    pub(crate) fn height(&self) -> usize {
+impl<T> AST<T> {
+    /// [q.alias()] returns the name queries over [q] refer to it by.
+    pub fn alias(&self) -> &str {
+        match self {
+            AST::Select { alias, .. }
+            | AST::Join { alias, .. }
+            | AST::Table { alias, .. }
+            | AST::Concat { alias, .. }
+            | AST::Aggregate { alias, .. }
+            | AST::OrderBy { alias, .. }
+            | AST::Limit { alias, .. }
+            | AST::SetOp { alias, .. } => alias,
+        }
+    }
+
+    /// [q.with_alias(alias)] returns [q], renamed to [alias].
+    pub fn with_alias(mut self, new_alias: String) -> Self {
+        match &mut self {
+            AST::Select { alias, .. }
+            | AST::Join { alias, .. }
+            | AST::Table { alias, .. }
+            | AST::Concat { alias, .. }
+            | AST::Aggregate { alias, .. }
+            | AST::OrderBy { alias, .. }
+            | AST::Limit { alias, .. }
+            | AST::SetOp { alias, .. } => *alias = new_alias,
+        }
+        self
+    }
+
+    /// [q.children()] returns the queries [q] reads its rows from.
+    pub fn children(&self) -> Vec<&AST<T>> {
+        match self {
+            AST::Table { .. } => vec![],
+            AST::Select { table, .. }
+            | AST::Aggregate { table, .. }
+            | AST::OrderBy { table, .. }
+            | AST::Limit { table, .. } => vec![table],
+            AST::Join { table1, table2, .. }
+            | AST::Concat { table1, table2, .. }
+            | AST::SetOp { table1, table2, .. } => vec![table1, table2],
+        }
+    }
+
+    /// [q.aliases()] returns the alias of every node in [q].
+    pub fn aliases(&self) -> HashSet<&str> {
+        let mut aliases: HashSet<_> = self.children().into_iter().flat_map(AST::aliases).collect();
+        aliases.insert(self.alias());
+        aliases
+    }
+
+    /// [AST::fresh_alias(children)] returns an alias for a query over [children] that's
+    /// different from every alias in them, so that the fields of the new query can't be
+    /// confused with any of theirs.
+    pub fn fresh_alias(children: &[&AST<T>]) -> String {
+        let taken: HashSet<_> = children.iter().flat_map(|q| q.aliases()).collect();
+        (1..)
+            .map(|i| format!("q{}", i))
+            .find(|alias| !taken.contains(alias.as_str()))
+            .expect("there are infinitely many aliases")
+    }
+
+    /// [q.arity()] returns the number of columns [q] produces.
+    pub fn arity(&self) -> usize {
         match self {
             AST::Select {
-                fields: _,
+                fields: Some(fields),
+                ..
+            }
+            | AST::Join {
+                fields: Some(fields),
+                ..
+            } => fields.len(),
+            AST::Select {
+                fields: None,
                 table,
-                pred,
-            } => table.height().max(pred.height()),
+                ..
+            } => table.arity(),
+            AST::Join {
+                fields: None,
+                table1,
+                table2,
+                ..
+            }
+            | AST::Concat { table1, table2, .. } => table1.arity() + table2.arity(),
+            AST::Table { columns, .. } => columns.len(),
+            AST::Aggregate {
+                group_by,
+                aggregates,
+                ..
+            } => group_by.len() + aggregates.len(),
+            AST::OrderBy { table, .. } | AST::Limit { table, .. } => table.arity(),
+            AST::SetOp { table1, .. } => table1.arity(),
+        }
+    }
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Copy, Hash)]
+pub enum SetOp {
+    Union,
+    Intersect,
+    Except,
+}
+
+impl SetOp {
+    pub const ALL: [SetOp; 3] = [SetOp::Union, SetOp::Intersect, SetOp::Except];
+}
+
+impl fmt::Display for SetOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SetOp::Union => write!(f, "UNION"),
+            SetOp::Intersect => write!(f, "INTERSECT"),
+            SetOp::Except => write!(f, "EXCEPT"),
+        }
+    }
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Copy, Hash)]
+pub enum SortDir {
+    Asc,
+    Desc,
+}
+
+impl fmt::Display for SortDir {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SortDir::Asc => write!(f, "ASC"),
+            SortDir::Desc => write!(f, "DESC"),
+        }
+    }
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Hash)]
+pub struct SortKey {
+    pub field: Field,
+    pub dir: SortDir,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Copy, Hash)]
+pub enum JoinKind {
+    Inner,
+    Left,
+    Right,
+    Full,
+}
+
+impl JoinKind {
+    pub const ALL: [JoinKind; 4] = [
+        JoinKind::Inner,
+        JoinKind::Left,
+        JoinKind::Right,
+        JoinKind::Full,
+    ];
+}
+
+impl fmt::Display for JoinKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JoinKind::Inner => write!(f, "JOIN"),
+            JoinKind::Left => write!(f, "LEFT JOIN"),
+            JoinKind::Right => write!(f, "RIGHT JOIN"),
+            JoinKind::Full => write!(f, "FULL JOIN"),
+        }
+    }
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Copy, Hash)]
+pub enum AggFunc {
+    Count,
+    Sum,
+    Min,
+    Max,
+    Avg,
+}
+
+impl AggFunc {
+    pub const ALL: [AggFunc; 5] = [
+        AggFunc::Count,
+        AggFunc::Sum,
+        AggFunc::Min,
+        AggFunc::Max,
+        AggFunc::Avg,
+    ];
+}
+
+impl fmt::Display for AggFunc {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AggFunc::Count => write!(f, "COUNT"),
+            AggFunc::Sum => write!(f, "SUM"),
+            AggFunc::Min => write!(f, "MIN"),
+            AggFunc::Max => write!(f, "MAX"),
+            AggFunc::Avg => write!(f, "AVG"),
+        }
+    }
+}
+
+/// An [AggExpr] is an aggregate function applied to a field, like `SUM(t.a)`.
+#[derive(Debug, Eq, PartialEq, Clone, Hash)]
+pub struct AggExpr {
+    pub func: AggFunc,
+    pub field: Field,
+}
+
+impl AggExpr {
+    /// [a.column_name()] is the name of the output column for [a].
+    pub fn column_name(&self) -> String {
+        format!("{}({})", self.func, self.field.name)
+    }
+}
+
+impl fmt::Display for AggExpr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}({}.{})", self.func, self.field.table, self.field.name)
+    }
+}
+
+impl AST<PredNode> {
+    pub(crate) fn height(&self) -> usize {
+        match self {
+            AST::Select { table, pred, .. } => table.height().max(pred.height()),
             AST::Join {
-                fields: _,
                 table1,
                 table2,
                 pred,
+                ..
             } => table1.height().max(table2.height()).max(pred.height()),
-            AST::Table {
-                name: _,
-                columns: _,
-            } => 1,
-            AST::Concat { table1, table2 } => table1.height().max(table2.height()),
+            AST::Table { .. } => 1,
+            AST::Concat { table1, table2, .. } => table1.height().max(table2.height()),
+            AST::Aggregate { table, having, .. } => table.height().max(having.height()),
+            AST::OrderBy { table, .. } | AST::Limit { table, .. } => table.height(),
+            AST::SetOp { table1, table2, .. } => table1.height().max(table2.height()),
         }
     }
 }
@@ -57,7 +341,32 @@ impl AST<PredNode> {
 #[derive(Debug, Eq, PartialEq, Clone)]
 pub enum ExprNode {
     Field(Field),
-    Int { value: isize },
+    /// An aggregate, which can only be referenced in the HAVING clause of an [AST::Aggregate]
+    /// that computes it.
+    Aggregate(AggExpr),
+    Int {
+        value: isize,
+    },
+    Add {
+        left: Box<ExprNode>,
+        right: Box<ExprNode>,
+    },
+    Sub {
+        left: Box<ExprNode>,
+        right: Box<ExprNode>,
+    },
+    Mul {
+        left: Box<ExprNode>,
+        right: Box<ExprNode>,
+    },
+    Div {
+        left: Box<ExprNode>,
+        right: Box<ExprNode>,
+    },
+    Mod {
+        left: Box<ExprNode>,
+        right: Box<ExprNode>,
+    },
 }
 
 impl fmt::Display for ExprNode {
@@ -65,6 +374,12 @@ impl fmt::Display for ExprNode {
         match self {
             ExprNode::Field(Field { table, name }) => write!(f, "{}.{}", table, name),
             ExprNode::Int { value } => write!(f, "{}", value),
+            ExprNode::Aggregate(a) => write!(f, "{}", a),
+            ExprNode::Add { left, right } => write!(f, "({} + {})", left, right),
+            ExprNode::Sub { left, right } => write!(f, "({} - {})", left, right),
+            ExprNode::Mul { left, right } => write!(f, "({} * {})", left, right),
+            ExprNode::Div { left, right } => write!(f, "({} / {})", left, right),
+            ExprNode::Mod { left, right } => write!(f, "({} % {})", left, right),
         }
     }
 }
@@ -80,19 +395,144 @@ pub enum PredNode {
         left: ExprNode,
         right: ExprNode,
     },
+    Le {
+        left: ExprNode,
+        right: ExprNode,
+    },
+    Gt {
+        left: ExprNode,
+        right: ExprNode,
+    },
+    Ge {
+        left: ExprNode,
+        right: ExprNode,
+    },
+    Ne {
+        left: ExprNode,
+        right: ExprNode,
+    },
     And {
         left: Box<PredNode>,
         right: Box<PredNode>,
     },
+    Or {
+        left: Box<PredNode>,
+        right: Box<PredNode>,
+    },
+    Not {
+        pred: Box<PredNode>,
+    },
+    /// Whether [expr] is one of the values in the single column of [subquery].
+    In {
+        expr: ExprNode,
+        subquery: Arc<Subquery>,
+    },
+    /// Whether [subquery] produces any rows.
+    Exists {
+        subquery: Arc<Subquery>,
+    },
+}
+
+/// A query nested in a predicate. Uncorrelated subqueries don't reference the rows of the
+/// query they're nested in, so their rows only need to be evaluated once, when they're built.
+///
+/// A correlated subquery selects from a single table, filtered by a predicate that can also
+/// reference the fields of the row of the query it's nested in. Its rows are those of the
+/// table instead, which are filtered again for each of those rows.
+#[derive(Debug, Clone)]
+pub struct Subquery {
+    pub query: AST<PredNode>,
+    pub rows: Vec<Vec<Value>>,
+    /// The fields of [rows] if [query] is correlated. Any other fields its predicate
+    /// references are those of the query it's nested in.
+    pub scope: Option<Arc<[Field]>>,
 }
 
+/// [sql_in(v, values)] is SQL's [v IN values], which is unknown if [v] is NULL or if [values]
+/// contains NULL but not [v], unless [values] is empty.
+pub(crate) fn sql_in<V: Borrow<Value>>(
+    v: &Value,
+    values: impl IntoIterator<Item = V>,
+) -> Option<bool> {
+    let mut unknown = false;
+    for value in values {
+        match v.sql_cmp(value.borrow()) {
+            Some(Ordering::Equal) => return Some(true),
+            Some(_) => (),
+            None => unknown = true,
+        }
+    }
+    if unknown {
+        None
+    } else {
+        Some(false)
+    }
+}
+
+impl Subquery {
+    /// [s.contains(v)] is SQL's [v IN s] for uncorrelated [s].
+    pub(crate) fn contains(&self, v: &Value) -> Option<bool> {
+        sql_in(v, self.rows.iter().map(|row| &row[0]))
+    }
+
+    /// [s.exists()] is SQL's [EXISTS s] for uncorrelated [s].
+    pub(crate) fn exists(&self) -> bool {
+        !self.rows.is_empty()
+    }
+
+    /// [s.filtered(pred)] returns [s], a correlated subquery, with [pred] as its predicate.
+    pub(crate) fn filtered(&self, pred: PredNode) -> Subquery {
+        let mut subquery = self.clone();
+        if let AST::Select { pred: p, .. } = &mut subquery.query {
+            *p = pred;
+        }
+        subquery
+    }
+
+    /// [s.projected(field)] returns [s], a correlated subquery, selecting only [field], one of
+    /// the fields of its rows.
+    pub(crate) fn projected(&self, field: Field) -> Subquery {
+        let mut subquery = self.clone();
+        if let AST::Select { fields, .. } = &mut subquery.query {
+            *fields = Some(Arc::from([SelectItem::from(field)]));
+        }
+        subquery
+    }
+}
+
+// Subqueries are identified by their query alone, since their rows follow from it.
+impl PartialEq for Subquery {
+    fn eq(&self, other: &Self) -> bool {
+        self.query == other.query
+    }
+}
+
+impl Eq for Subquery {}
+
 impl fmt::Display for PredNode {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             PredNode::True => write!(f, "True"),
             PredNode::Lt { left, right } => write!(f, "{} < {}", left, right),
             PredNode::Eq { left, right } => write!(f, "{} == {}", left, right),
+            PredNode::Le { left, right } => write!(f, "{} <= {}", left, right),
+            PredNode::Gt { left, right } => write!(f, "{} > {}", left, right),
+            PredNode::Ge { left, right } => write!(f, "{} >= {}", left, right),
+            PredNode::Ne { left, right } => write!(f, "{} != {}", left, right),
             PredNode::And { left, right } => write!(f, "({}) && ({})", left, right),
+            PredNode::Or { left, right } => write!(f, "({}) || ({})", left, right),
+            PredNode::Not { pred } => write!(f, "!({})", pred),
+            PredNode::In { expr, subquery } => write!(
+                f,
+                "{} in {}",
+                expr,
+                crate::sql::create_sql_query(&subquery.query)
+            ),
+            PredNode::Exists { subquery } => write!(
+                f,
+                "exists {}",
+                crate::sql::create_sql_query(&subquery.query)
+            ),
         }
     }
 }
@@ -102,27 +542,435 @@ impl ExprNode {
         match self {
             ExprNode::Field(_) => 1,
             ExprNode::Int { value: _ } => 1,
+            ExprNode::Aggregate(_) => 1,
+            ExprNode::Add { left, right }
+            | ExprNode::Sub { left, right }
+            | ExprNode::Mul { left, right }
+            | ExprNode::Div { left, right }
+            | ExprNode::Mod { left, right } => left.height().max(right.height()) + 1,
+        }
+    }
+
+    /// [e.has_field()] is true if [e] references at least one field, i.e. it isn't constant.
+    pub(crate) fn has_field(&self) -> bool {
+        match self {
+            ExprNode::Field(_) | ExprNode::Aggregate(_) => true,
+            ExprNode::Int { value: _ } => false,
+            ExprNode::Add { left, right }
+            | ExprNode::Sub { left, right }
+            | ExprNode::Mul { left, right }
+            | ExprNode::Div { left, right }
+            | ExprNode::Mod { left, right } => left.has_field() || right.has_field(),
         }
     }
 }
 
+/// The orderings of its operands that satisfy a comparison.
+pub(crate) type Comparison = fn(Ordering) -> bool;
+
 impl PredNode {
+    /// [PredNode::comparisons(left, right)] returns every comparison of [left] against [right].
+    pub(crate) fn comparisons(left: &ExprNode, right: &ExprNode) -> [PredNode; 6] {
+        let (l, r) = (left.clone(), right.clone());
+        [
+            PredNode::Eq {
+                left: l.clone(),
+                right: r.clone(),
+            },
+            PredNode::Lt {
+                left: l.clone(),
+                right: r.clone(),
+            },
+            PredNode::Le {
+                left: l.clone(),
+                right: r.clone(),
+            },
+            PredNode::Gt {
+                left: l.clone(),
+                right: r.clone(),
+            },
+            PredNode::Ge {
+                left: l.clone(),
+                right: r.clone(),
+            },
+            PredNode::Ne { left: l, right: r },
+        ]
+    }
+
+    /// [p.comparison()] returns the orderings of its operands that satisfy [p] along with
+    /// the operands, if [p] is a comparison. This is the one definition of the comparisons
+    /// that every evaluator decides them by.
+    pub(crate) fn comparison(&self) -> Option<(Comparison, &ExprNode, &ExprNode)> {
+        match self {
+            PredNode::Lt { left, right } => Some((Ordering::is_lt, left, right)),
+            PredNode::Eq { left, right } => Some((Ordering::is_eq, left, right)),
+            PredNode::Le { left, right } => Some((Ordering::is_le, left, right)),
+            PredNode::Gt { left, right } => Some((Ordering::is_gt, left, right)),
+            PredNode::Ge { left, right } => Some((Ordering::is_ge, left, right)),
+            PredNode::Ne { left, right } => Some((Ordering::is_ne, left, right)),
+            _ => None,
+        }
+    }
+
     pub(crate) fn height(&self) -> usize {
         match self {
             PredNode::True => 1,
-            PredNode::Lt { left, right } | PredNode::Eq { left, right } => {
+            PredNode::Lt { left, right }
+            | PredNode::Eq { left, right }
+            | PredNode::Le { left, right }
+            | PredNode::Gt { left, right }
+            | PredNode::Ge { left, right }
+            | PredNode::Ne { left, right } => left.height().max(right.height()) + 1,
+            PredNode::And { left, right } | PredNode::Or { left, right } => {
                 left.height().max(right.height()) + 1
             }
-            PredNode::And { left, right } => left.height().max(right.height()) + 1,
+            PredNode::Not { pred } => pred.height() + 1,
+            PredNode::In { expr, .. } => expr.height() + 1,
+            PredNode::Exists { .. } => 2,
+        }
+    }
+}
+
+// Predicates follow SQL's three-valued logic, where [None] is unknown: the result of
+// comparing against NULL. A WHERE clause only keeps rows whose predicate is [Some(true)].
+
+/// [and3(a, b)] is SQL's AND over possibly-unknown truth values.
+pub(crate) fn and3(a: Option<bool>, b: Option<bool>) -> Option<bool> {
+    match (a, b) {
+        (Some(false), _) | (_, Some(false)) => Some(false),
+        (Some(true), Some(true)) => Some(true),
+        _ => None,
+    }
+}
+
+/// [or3(a, b)] is SQL's OR over possibly-unknown truth values.
+pub(crate) fn or3(a: Option<bool>, b: Option<bool>) -> Option<bool> {
+    match (a, b) {
+        (Some(true), _) | (_, Some(true)) => Some(true),
+        (Some(false), Some(false)) => Some(false),
+        _ => None,
+    }
+}
+
+/// A [Value] is a single cell of a [ConcTable], mirroring SQLite's storage classes.
+///
+/// In JSON, integers, reals, strings and `null` map directly onto the variants.
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(untagged)]
+pub enum Value {
+    Null,
+    Integer(isize),
+    Real(f64),
+    Text(String),
+}
+
+impl Value {
+    /// [v.sql_cmp(other)] compares two values the way SQLite does: numbers compare
+    /// numerically regardless of storage class and sort before text, and any comparison
+    /// involving NULL is unknown, which is represented by [None].
+    pub fn sql_cmp(&self, other: &Value) -> Option<Ordering> {
+        match (self, other) {
+            (Value::Null, _) | (_, Value::Null) => None,
+            (Value::Integer(a), Value::Integer(b)) => Some(a.cmp(b)),
+            (Value::Integer(a), Value::Real(b)) => (*a as f64).partial_cmp(b),
+            (Value::Real(a), Value::Integer(b)) => a.partial_cmp(&(*b as f64)),
+            (Value::Real(a), Value::Real(b)) => a.partial_cmp(b),
+            (Value::Text(a), Value::Text(b)) => Some(a.cmp(b)),
+            (Value::Text(_), _) => Some(Ordering::Greater),
+            (_, Value::Text(_)) => Some(Ordering::Less),
+        }
+    }
+
+    // Arithmetic follows SQLite: NULL operands give NULL, integer overflow falls back to
+    // real arithmetic, and dividing by zero gives NULL rather than an error.
+
+    pub fn sql_add(&self, other: &Value) -> Value {
+        self.arith(other, isize::checked_add, |a, b| a + b)
+    }
+
+    pub fn sql_sub(&self, other: &Value) -> Value {
+        self.arith(other, isize::checked_sub, |a, b| a - b)
+    }
+
+    pub fn sql_mul(&self, other: &Value) -> Value {
+        self.arith(other, isize::checked_mul, |a, b| a * b)
+    }
+
+    pub fn sql_div(&self, other: &Value) -> Value {
+        if other.numeric().as_f64() == Some(0.0) {
+            return Value::Null;
+        }
+        self.arith(other, isize::checked_div, |a, b| a / b)
+    }
+
+    pub fn sql_rem(&self, other: &Value) -> Value {
+        // SQLite takes the remainder of the operands truncated to integers, but the result
+        // is still real if either operand was.
+        match (self.numeric(), other.numeric()) {
+            (Value::Null, _) | (_, Value::Null) => Value::Null,
+            (Value::Integer(_), Value::Integer(0)) => Value::Null,
+            (Value::Integer(a), Value::Integer(b)) => Value::Integer(a.wrapping_rem(b)),
+            _ => {
+                let (a, b) = (self.truncated(), other.truncated());
+                if b == 0 {
+                    Value::Null
+                } else {
+                    Value::Real(a.wrapping_rem(b) as f64)
+                }
+            }
+        }
+    }
+
+    fn arith(
+        &self,
+        other: &Value,
+        int_op: fn(isize, isize) -> Option<isize>,
+        real_op: fn(f64, f64) -> f64,
+    ) -> Value {
+        let (a, b) = (self.numeric(), other.numeric());
+        if let (Value::Integer(a), Value::Integer(b)) = (&a, &b) {
+            if let Some(n) = int_op(*a, *b) {
+                return Value::Integer(n);
+            }
+        }
+        match (a.as_f64(), b.as_f64()) {
+            (Some(a), Some(b)) => match real_op(a, b) {
+                // SQLite has no NaN; it stores NULL instead.
+                x if x.is_nan() => Value::Null,
+                x => Value::Real(x),
+            },
+            _ => Value::Null,
+        }
+    }
+
+    /// [v.numeric()] converts [v] to a number the way SQLite does before arithmetic: text
+    /// is read up to the end of its longest prefix that's a number, text without one is 0,
+    /// and NULL stays NULL.
+    fn numeric(&self) -> Value {
+        match self {
+            Value::Text(s) => numeric_prefix(s),
+            v => v.clone(),
+        }
+    }
+
+    /// [v.truncated()] converts non-NULL [v] to an integer the way SQLite does for the
+    /// remainder: numbers are truncated, and text is read up to the end of its longest prefix
+    /// that's an integer, so `'2.5e1'` is 2 rather than 25.
+    fn truncated(&self) -> isize {
+        match self {
+            Value::Text(s) => {
+                let s = s.trim_start();
+                let sign = usize::from(matches!(s.as_bytes().first(), Some(b'+' | b'-')));
+                let end = sign + s[sign..].bytes().take_while(u8::is_ascii_digit).count();
+                let prefix = &s[..end];
+                match prefix.parse() {
+                    Ok(n) => n,
+                    // Integers too large to fit saturate.
+                    Err(_) if end > sign => prefix.parse::<f64>().unwrap() as isize,
+                    Err(_) => 0,
+                }
+            }
+            v => v.as_f64().map_or(0, |x| x as isize),
+        }
+    }
+
+    fn as_f64(&self) -> Option<f64> {
+        match self {
+            Value::Integer(n) => Some(*n as f64),
+            Value::Real(x) => Some(*x),
+            Value::Null | Value::Text(_) => None,
+        }
+    }
+}
+
+/// [numeric_prefix(s)] reads the longest prefix of [s] that's a number in SQLite's grammar,
+/// after any leading whitespace: an optional sign, digits with an optional fraction, and an
+/// optional exponent. It's an integer if it has neither a fraction nor an exponent and fits,
+/// and real otherwise. Unlike Rust's parsers, there's no `inf` or `nan`.
+fn numeric_prefix(s: &str) -> Value {
+    let s = s.trim_start();
+    let bytes = s.as_bytes();
+    let digits_from = |i: usize| {
+        i + bytes[i.min(bytes.len())..]
+            .iter()
+            .take_while(|b| b.is_ascii_digit())
+            .count()
+    };
+    let start = usize::from(matches!(bytes.first(), Some(b'+' | b'-')));
+    let mut end = digits_from(start);
+    let mut has_digits = end > start;
+    let mut integral = true;
+    if bytes.get(end) == Some(&b'.') {
+        let fraction_end = digits_from(end + 1);
+        if has_digits || fraction_end > end + 1 {
+            has_digits = true;
+            integral = false;
+            end = fraction_end;
+        }
+    }
+    if !has_digits {
+        return Value::Integer(0);
+    }
+    if matches!(bytes.get(end), Some(b'e' | b'E')) {
+        let exponent_start = end + 1 + usize::from(matches!(bytes.get(end + 1), Some(b'+' | b'-')));
+        let exponent_end = digits_from(exponent_start);
+        if exponent_end > exponent_start {
+            integral = false;
+            end = exponent_end;
+        }
+    }
+    let prefix = &s[..end];
+    match prefix.parse() {
+        Ok(n) if integral => Value::Integer(n),
+        _ => Value::Real(prefix.parse().expect("a numeric prefix parses as a real")),
+    }
+}
+
+// NOTE: equality here is structural (so NULL == NULL and 1 != 1.0), which is what we want
+// when comparing tables. Use [Value::sql_cmp] for SQL semantics.
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Value::Null, Value::Null) => true,
+            (Value::Integer(a), Value::Integer(b)) => a == b,
+            (Value::Real(a), Value::Real(b)) => a.to_bits() == b.to_bits(),
+            (Value::Text(a), Value::Text(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+impl Eq for Value {}
+
+// NOTE: like equality, this order is structural rather than SQL's: it's only meant to
+// canonicalize tables, e.g. by sorting their rows.
+impl PartialOrd for Value {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Value {
+    fn cmp(&self, other: &Self) -> Ordering {
+        fn rank(v: &Value) -> u8 {
+            match v {
+                Value::Null => 0,
+                Value::Integer(_) => 1,
+                Value::Real(_) => 2,
+                Value::Text(_) => 3,
+            }
+        }
+        match (self, other) {
+            (Value::Integer(a), Value::Integer(b)) => a.cmp(b),
+            (Value::Real(a), Value::Real(b)) => a.total_cmp(b),
+            (Value::Text(a), Value::Text(b)) => a.cmp(b),
+            _ => rank(self).cmp(&rank(other)),
+        }
+    }
+}
+
+impl hash::Hash for Value {
+    fn hash<H: hash::Hasher>(&self, state: &mut H) {
+        std::mem::discriminant(self).hash(state);
+        match self {
+            Value::Null => {}
+            Value::Integer(n) => n.hash(state),
+            Value::Real(x) => x.to_bits().hash(state),
+            Value::Text(s) => s.hash(state),
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Null => write!(f, "NULL"),
+            Value::Integer(n) => write!(f, "{}", n),
+            Value::Real(x) => write!(f, "{:?}", x),
+            Value::Text(s) => write!(f, "'{}'", s.replace('\'', "''")),
         }
     }
 }
 
+impl From<isize> for Value {
+    fn from(n: isize) -> Self {
+        Value::Integer(n)
+    }
+}
+
+impl From<f64> for Value {
+    fn from(x: f64) -> Self {
+        Value::Real(x)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(s: &str) -> Self {
+        Value::Text(s.to_string())
+    }
+}
+
+impl From<String> for Value {
+    fn from(s: String) -> Self {
+        Value::Text(s)
+    }
+}
+
 #[derive(Debug, Eq, PartialEq, Hash, Clone, serde::Deserialize)]
 pub struct ConcTable {
     pub name: String,
     pub columns: Vec<String>,
-    pub values: Vec<Vec<isize>>,
+    pub values: Vec<Vec<Value>>,
+}
+
+/// How the output of a synthesized query is compared against the expected output.
+#[derive(Debug, Eq, PartialEq, Clone, Copy, Default, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MatchMode {
+    /// The output has exactly the expected rows, including how often each one appears.
+    #[default]
+    Bag,
+    /// The output has exactly the expected rows, ignoring duplicates.
+    Set,
+    /// The output has at least the expected rows.
+    Superset,
+}
+
+/// How the columns of a synthesized query's output are lined up with the expected columns.
+#[derive(Debug, Eq, PartialEq, Clone, Copy, Default, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ColumnMatch {
+    /// The columns are in the expected order and have the expected names, except where
+    /// those are blank.
+    #[default]
+    ByName,
+    /// The columns are in any order and have any names, and are told apart by their values.
+    ByContent,
+}
+
+/// The database that printed queries are written for.
+#[derive(Debug, Eq, PartialEq, Clone, Copy, Default, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Dialect {
+    #[default]
+    Sqlite,
+    Postgres,
+    /// MySQL 8.0, which quotes identifiers with backticks and has no FULL JOIN, nor INTERSECT
+    /// or EXCEPT before 8.0.31.
+    MySql,
+    /// Standard SQL, which limits rows with FETCH FIRST rather than LIMIT.
+    Ansi,
 }
 
-pub type Example = (Vec<ConcTable>, ConcTable);
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct Example {
+    pub input: Vec<ConcTable>,
+    pub output: ConcTable,
+    /// Whether the order of the rows in [output] matters.
+    #[serde(default)]
+    pub ordered: bool,
+    #[serde(default)]
+    pub match_mode: MatchMode,
+    #[serde(default)]
+    pub column_match: ColumnMatch,
+}
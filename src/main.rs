@@ -2,46 +2,123 @@
 extern crate rocket;
 extern crate serde;
 
-use reaper_lib::bottomup::{generate_abstract_queries, get_fields};
-use reaper_lib::sql::{create_table, create_sql_query};
+use rayon::prelude::*;
+use reaper_lib::bottomup::{
+    column_mapping, generate_abstract_queries, generate_subqueries, get_fields,
+};
+use reaper_lib::eval::Evaluator;
+use reaper_lib::sql::{create_dialect_sql_query, SqliteEvaluator};
+use reaper_lib::stop::Stopper;
 use reaper_lib::types::*;
+use reaper_lib::SynthesisError;
 use rocket::fs::{relative, FileServer};
+use rocket::response::status::BadRequest;
 use rocket::serde::json::Json;
 use rocket::serde::Deserialize;
+use std::time::Duration;
 
 #[derive(Deserialize)]
-struct Example {
-    input: Vec<ConcTable>,
-    output: ConcTable,
+struct Request {
+    #[serde(flatten)]
+    example: Example,
     constants: Vec<isize>,
+    /// The database the synthesized query is printed for.
+    #[serde(default)]
+    dialect: Dialect,
+    /// How many seconds to search for before settling for the best query found so far.
+    #[serde(default = "default_timeout")]
+    timeout: u64,
 }
 
-#[post("/synth", format = "json", data = "<example>")]
-fn synth(example: Json<Example>) -> String {
-    let conn = create_table(&example.input).unwrap();
+fn default_timeout() -> u64 {
+    60
+}
+
+#[post("/synth", format = "json", data = "<request>")]
+fn synth(request: Json<Request>) -> Result<String, BadRequest<String>> {
+    let Request {
+        example,
+        constants,
+        dialect,
+        timeout,
+    } = request.into_inner();
+    let stopper = Stopper::with_timeout(Duration::from_secs(timeout));
+    let evaluator = SqliteEvaluator::new(&example.input)
+        .map_err(|e| BadRequest(format!("Invalid example: {}", e)))?;
+    // If this stops, so does everything after it, which reports that it ran out of time.
+    let subqueries = generate_subqueries(&example, &evaluator, &stopper).unwrap_or_default();
     for depth in 1..=3 {
         println!("Depth: {}", depth);
-        let queries = generate_abstract_queries(
-            (example.input.clone(), example.output.clone()),
-            depth,
-            &conn,
-        );
+        let queries = match generate_abstract_queries(example.clone(), depth, &evaluator, &stopper)
+        {
+            Ok(queries) => queries,
+            Err(_) => break,
+        };
         println!("looking for predicate...");
-        for query in queries.iter() {
-            let predicate =
-                reaper_lib::synthesize(query, &example.output, &example.constants, 3, &conn)
-                    .map(|ps| ps.first().expect("vec must not be empty").clone());
+        // Queries are searched in parallel, but the first one in order with a predicate wins.
+        let found = queries.par_iter().find_map_first(|query| {
+            let predicate = match reaper_lib::synthesize(
+                query,
+                &example,
+                &constants,
+                &subqueries,
+                3,
+                &evaluator,
+                &stopper,
+            ) {
+                Ok(ps) => Ok(ps.first().expect("vec must not be empty").clone()),
+                // Stopping partway through still leaves the queries found before then.
+                Err(
+                    SynthesisError::TimedOut { partial } | SynthesisError::Cancelled { partial },
+                ) if !partial.is_empty() => Ok(partial[0].clone()),
+                Err(e) => Err(e),
+            };
             println!("Predicate: {:?}", predicate);
-            if let Ok(predicate) = predicate {
-                let sql = create_sql_query(&predicate);
-                // Remove beginning and ending parens
-                let sql = &sql[1..sql.len() - 1];
-                println!("SQL: {}", sql);
-                return sql.to_string()
+            predicate.ok()
+        });
+        if let Some(predicate) = found {
+            let sql = create_dialect_sql_query(&predicate, dialect);
+            println!("SQL: {}", sql);
+            if example.column_match == ColumnMatch::ByContent {
+                if let Ok(result) = evaluator.eval(&predicate) {
+                    return Ok(format!("{}\n{}", sql, describe_mapping(&result, &example)));
+                }
             }
+            return Ok(sql);
+        }
+        if stopper.is_stopped() {
+            break;
         }
     }
-    "Unable to synthesize".to_string()
+    if stopper.is_stopped() {
+        return Ok(format!("Unable to synthesize within {} seconds", timeout));
+    }
+    Ok("Unable to synthesize".to_string())
+}
+
+/// Describes which column of the query's output lines up with each expected column, as SQL
+/// comments to go after the query.
+fn describe_mapping(result: &ConcTable, example: &Example) -> String {
+    let mapping = match column_mapping(result, example) {
+        Some(mapping) => mapping,
+        None => return String::new(),
+    };
+    example
+        .output
+        .columns
+        .iter()
+        .zip(mapping)
+        .enumerate()
+        .map(|(i, (expected, j))| {
+            format!(
+                "-- output column {} ({:?}) is {}",
+                i + 1,
+                expected,
+                result.columns[j]
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
 }
 
 #[launch]